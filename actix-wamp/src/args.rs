@@ -1,9 +1,18 @@
 use crate::error::Error;
 use crate::messages::Dict;
+use crate::payload::{value, Payload};
+use crate::session_core::Notify;
 use actix::prelude::*;
+use futures::channel::{mpsc, oneshot};
+use futures::future::LocalBoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::task::{Context, Poll};
+use futures::{FutureExt, StreamExt, TryFutureExt};
 use serde::Serialize;
 use serde_json::Value;
 use std::borrow::Cow;
+use std::pin::Pin;
+use std::time::Duration;
 
 pub trait ToArgs {
     fn as_json(&self) -> Result<Option<Value>, Error>;
@@ -30,12 +39,26 @@ def_args!(T1, T2);
 def_args!(T1, T2, T3);
 def_args!(T1, T2, T3, T4);
 
-#[derive(Debug)]
+/// How pending call is canceled when caller gives up waiting for result.
+#[derive(serde_derive::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CancelMode {
+    /// Dealer stops waiting for result, callee is not notified.
+    Skip,
+    /// Dealer interrupts callee and waits for its response.
+    #[default]
+    Kill,
+    /// Dealer interrupts callee and replies immediately.
+    KillNoWait,
+}
+
+#[derive(Debug, Clone)]
 pub struct RpcCallRequest {
     pub(crate) uri: Cow<'static, str>,
     pub(crate) options: Option<Dict>,
-    pub(crate) args: Option<Value>,
-    pub(crate) kw_args: Option<Value>,
+    pub(crate) payload: Payload,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) cancel_mode: CancelMode,
 }
 
 impl RpcCallRequest {
@@ -46,8 +69,9 @@ impl RpcCallRequest {
         Ok(RpcCallRequest {
             uri: uri.into(),
             options: None,
-            args: Some(serde_json::to_value(va_args)?),
-            kw_args: None,
+            payload: Payload::with_args(va_args)?,
+            timeout: None,
+            cancel_mode: CancelMode::default(),
         })
     }
 
@@ -55,8 +79,9 @@ impl RpcCallRequest {
         RpcCallRequest {
             uri: Cow::Borrowed(uri),
             options: None,
-            args: None,
-            kw_args: None,
+            payload: Payload::default(),
+            timeout: None,
+            cancel_mode: CancelMode::default(),
         }
     }
 
@@ -64,11 +89,23 @@ impl RpcCallRequest {
         Ok(RpcCallRequest {
             uri: Cow::Borrowed(uri),
             options: None,
-            args: args.as_json()?,
-            kw_args: None,
+            payload: Payload::with_args(&args.as_json()?)?,
+            timeout: None,
+            cancel_mode: CancelMode::default(),
         })
     }
 
+    /// Creates request with arguments kept as msgpack values, e.g. binary data.
+    pub fn with_payload(uri: impl Into<Cow<'static, str>>, payload: Payload) -> Self {
+        RpcCallRequest {
+            uri: uri.into(),
+            options: None,
+            payload,
+            timeout: None,
+            cancel_mode: CancelMode::default(),
+        }
+    }
+
     /// Adds named arguments.
     ///
     /// Panics if kw_args is not valid json object.
@@ -76,7 +113,25 @@ impl RpcCallRequest {
     pub fn with_kwargs(mut self, kw_args: Value) -> Self {
         assert!(kw_args.is_object());
 
-        self.kw_args = Some(kw_args);
+        for (key, value) in kw_args.as_object().into_iter().flatten() {
+            let value = value::to_value(value).expect("json is valid msgpack value");
+            self.payload.kw_args.insert(key.clone(), value);
+        }
+        self
+    }
+
+    /// Limits time of waiting for call result.
+    ///
+    /// Timeout is passed to dealer as `timeout` call option and enforced locally. Call that
+    /// is not finished on time fails with [`Error::Timeout`] and is canceled.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets mode of CANCEL sent when call times out or response future is dropped.
+    pub fn with_cancel_mode(mut self, cancel_mode: CancelMode) -> Self {
+        self.cancel_mode = cancel_mode;
         self
     }
 }
@@ -91,8 +146,274 @@ impl Message for RpcCallRequest {
     type Result = Result<RpcCallResponse, Error>;
 }
 
+pub(crate) type CallReceiver = oneshot::Receiver<Result<Payload, Error>>;
+
+/// Sends CALL with given request id and returns receiver of call result.
+///
+/// Id is chosen by caller, so call can be cancelled before this message is handled.
+pub struct StartCall(pub u64, pub RpcCallRequest);
+
+impl Message for StartCall {
+    type Result = Result<CallReceiver, Error>;
+}
+
+pub(crate) type ProgressiveReceiver = mpsc::UnboundedReceiver<Result<Payload, Error>>;
+
+/// Sends CALL with `receive_progress` option set.
+pub struct StartProgressiveCall(pub u64, pub RpcCallRequest);
+
+impl Message for StartProgressiveCall {
+    type Result = Result<ProgressiveReceiver, Error>;
+}
+
+pub struct CancelCall {
+    pub request_id: u64,
+}
+
+impl Message for CancelCall {
+    type Result = ();
+}
+
+/// Cancels pending call when dropped before [`CancelOnDrop::disarm`].
+pub(crate) struct CancelOnDrop {
+    request_id: u64,
+    connection: Option<Notify<CancelCall>>,
+}
+
+impl CancelOnDrop {
+    pub(crate) fn new(request_id: u64, connection: Notify<CancelCall>) -> Self {
+        CancelOnDrop {
+            request_id,
+            connection: Some(connection),
+        }
+    }
+
+    pub(crate) fn disarm(mut self) {
+        self.connection = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let request_id = self.request_id;
+            connection(CancelCall { request_id });
+        }
+    }
+}
+
+/// Stream of progressive call results, ending after final result.
+///
+/// Dropping it before final result cancels call.
+pub(crate) struct ProgressiveResults {
+    rx: ProgressiveReceiver,
+    guard: Option<CancelOnDrop>,
+}
+
+impl ProgressiveResults {
+    pub(crate) fn new(rx: ProgressiveReceiver, guard: CancelOnDrop) -> Self {
+        ProgressiveResults {
+            rx,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl Stream for ProgressiveResults {
+    type Item = Result<Payload, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = futures::ready!(self.rx.poll_next_unpin(cx));
+        if item.is_none() {
+            if let Some(guard) = self.guard.take() {
+                guard.disarm();
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
 pub trait RpcEndpoint {
     type Response: Future<Output = Result<RpcCallResponse, Error>> + 'static;
 
     fn rpc_call(&self, request: RpcCallRequest) -> Self::Response;
+
+    /// Calls procedure and returns result without conversion to json.
+    ///
+    /// Endpoints without native msgpack support convert json result of
+    /// [`rpc_call`](#tymethod.rpc_call), so binary data is not preserved there.
+    fn rpc_call_payload(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<Payload, Error>> + 'static>> {
+        self.rpc_call(request).map_ok(Payload::from).boxed_local()
+    }
+
+    /// Calls procedure with progressive results.
+    ///
+    /// Yields every progressive result and ends after the final one. Endpoints without
+    /// progressive results support yield the final result only.
+    fn rpc_call_progressive(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Stream<Item = Result<RpcCallResponse, Error>> + 'static>> {
+        self.rpc_call(request).into_stream().boxed_local()
+    }
+
+    /// Calls procedures keeping at most [`BatchOptions::with_concurrency`] calls in flight.
+    ///
+    /// Results are in order of requests. With [`BatchOptions::stop_on_error`] no more
+    /// calls are sent after first failure, calls already sent are awaited and the rest
+    /// fail with [`Error::BatchStopped`].
+    fn rpc_call_batch<'a>(
+        &'a self,
+        requests: Vec<RpcCallRequest>,
+        options: BatchOptions,
+    ) -> LocalBoxFuture<'a, Vec<Result<Payload, Error>>> {
+        async move {
+            let mut results: Vec<Option<Result<Payload, Error>>> =
+                requests.iter().map(|_| None).collect();
+            let mut requests = requests.into_iter().enumerate();
+            let mut in_flight = FuturesUnordered::new();
+            let mut stopped = false;
+
+            loop {
+                while !stopped && in_flight.len() < options.concurrency {
+                    match requests.next() {
+                        Some((index, request)) => in_flight.push(
+                            self.rpc_call_payload(request)
+                                .map(move |result| (index, result)),
+                        ),
+                        None => break,
+                    }
+                }
+                match in_flight.next().await {
+                    Some((index, result)) => {
+                        stopped |= options.stop_on_error && result.is_err();
+                        results[index] = Some(result);
+                    }
+                    None => break,
+                }
+            }
+
+            results
+                .into_iter()
+                .map(|result| result.unwrap_or(Err(Error::BatchStopped)))
+                .collect()
+        }
+        .boxed_local()
+    }
+}
+
+/// Options of [`RpcEndpoint::rpc_call_batch`].
+#[derive(Debug, Clone, Copy)]
+pub struct BatchOptions {
+    concurrency: usize,
+    stop_on_error: bool,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            concurrency: 8,
+            stop_on_error: false,
+        }
+    }
+}
+
+impl BatchOptions {
+    /// Sets maximum number of calls in flight, 8 by default.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Stops sending calls after first failure.
+    pub fn stop_on_error(mut self) -> Self {
+        self.stop_on_error = true;
+        self
+    }
+
+    /// Whether calls stop after first failure, see [`stop_on_error`](#method.stop_on_error).
+    pub fn stops_on_error(&self) -> bool {
+        self.stop_on_error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Fails calls to `test.fail`, tracks calls in flight.
+    #[derive(Default)]
+    struct Mock {
+        in_flight: Rc<Cell<usize>>,
+        max_in_flight: Rc<Cell<usize>>,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl RpcEndpoint for Mock {
+        type Response = Pin<Box<dyn Future<Output = Result<RpcCallResponse, Error>>>>;
+
+        fn rpc_call(&self, request: RpcCallRequest) -> Self::Response {
+            let (in_flight, max_in_flight) = (self.in_flight.clone(), self.max_in_flight.clone());
+            in_flight.set(in_flight.get() + 1);
+            max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+            self.calls.set(self.calls.get() + 1);
+
+            async move {
+                let _ = tokio::task::yield_now().await;
+                in_flight.set(in_flight.get() - 1);
+                match request.uri.as_ref() {
+                    "test.fail" => Err(Error::Timeout),
+                    _ => Ok(RpcCallResponse {
+                        args: request.payload.json_args(),
+                        kw_args: None,
+                    }),
+                }
+            }
+            .boxed_local()
+        }
+    }
+
+    fn request(uri: &'static str, n: u32) -> RpcCallRequest {
+        RpcCallRequest::with_args(uri, &(n,)).unwrap()
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let mock = Mock::default();
+
+        let requests = (0..10).map(|n| request("test.echo", n)).collect();
+        let results = runtime
+            .block_on(mock.rpc_call_batch(requests, BatchOptions::default().with_concurrency(3)));
+        let results: Vec<u32> = results
+            .into_iter()
+            .map(|result| result.unwrap().parse_arg(0).unwrap())
+            .collect();
+        assert_eq!(results, (0..10).collect::<Vec<_>>());
+        assert_eq!(mock.max_in_flight.get(), 3);
+
+        mock.calls.set(0);
+        let requests = vec![
+            request("test.echo", 0),
+            request("test.fail", 1),
+            request("test.echo", 2),
+            request("test.echo", 3),
+        ];
+        let results = runtime.block_on(mock.rpc_call_batch(
+            requests,
+            BatchOptions::default().with_concurrency(2).stop_on_error(),
+        ));
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Error::Timeout)));
+        assert!(matches!(results[3], Err(Error::BatchStopped)));
+        assert_eq!(mock.calls.get(), 3);
+    }
 }
@@ -4,7 +4,20 @@ use crate::messages::Dict;
 pub trait AuthMethod {
     fn auth_method(&self) -> &str;
 
+    /// Data sent to router in HELLO `authextra`, e.g. public key or client nonce.
+    fn hello_extra(&mut self, _auth_id: &str) -> Result<Dict, Error> {
+        Ok(Dict::new())
+    }
+
     fn challenge(&mut self, auth_id: &str, extra: &Dict) -> Result<(String, Dict), Error>;
+
+    /// Verifies WELCOME `authextra` of router, e.g. signature proving router knows password.
+    fn welcome(&mut self, _extra: &Dict) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
+pub mod cryptosign;
+pub mod scram;
+pub mod ticket;
 pub mod wampcra;
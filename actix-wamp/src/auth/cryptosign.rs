@@ -0,0 +1,83 @@
+//! WAMP-cryptosign (Ed25519) authentication implementation
+
+use super::{AuthMethod, Dict};
+use crate::error::Error;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+
+const CRYPTOSIGN_AUTH_METHOD_ID: &str = "cryptosign";
+
+struct CryptoSign {
+    key: PKey<Private>,
+}
+
+impl AuthMethod for CryptoSign {
+    fn auth_method(&self) -> &str {
+        CRYPTOSIGN_AUTH_METHOD_ID
+    }
+
+    fn hello_extra(&mut self, _auth_id: &str) -> Result<Dict, Error> {
+        let mut extra = Dict::new();
+        extra.insert(
+            "pubkey".into(),
+            hex::encode(self.key.raw_public_key()?).into(),
+        );
+        Ok(extra)
+    }
+
+    fn challenge(&mut self, _auth_id: &str, extra: &Dict) -> Result<(String, Dict), Error> {
+        let challenge = match extra
+            .get("challenge")
+            .and_then(|challenge| challenge.as_str())
+        {
+            Some(challenge) => hex::decode(challenge)?,
+            None => return Err(Error::protocol_err("missing challenge field")),
+        };
+
+        let mut signer = Signer::new_without_digest(&self.key)?;
+        let mut signature = signer.sign_oneshot_to_vec(&challenge)?;
+        // Router expects signature followed by signed message.
+        signature.extend_from_slice(&challenge);
+        Ok((hex::encode(signature), Dict::default()))
+    }
+}
+
+/// Creates WAMP-cryptosign authentication provider from raw 32 byte Ed25519 private key.
+pub fn cryptosign_auth(
+    private_key: &[u8],
+) -> Result<impl AuthMethod + Sync + Send + 'static, Error> {
+    let key = PKey::private_key_from_raw_bytes(private_key, Id::ED25519)?;
+    Ok(CryptoSign { key })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn test_sign_challenge() {
+        let secret = [7u8; 32];
+        let mut auth = cryptosign_auth(&secret).unwrap();
+        let pubkey = hex::decode(
+            auth.hello_extra("alice").unwrap()["pubkey"]
+                .as_str()
+                .unwrap(),
+        )
+        .unwrap();
+
+        let challenge = [0x5au8; 32];
+        let mut extra = Dict::new();
+        extra.insert("challenge".into(), hex::encode(challenge).into());
+        let (signature, _) = auth.challenge("alice", &extra).unwrap();
+        let signature = hex::decode(signature).unwrap();
+        assert_eq!(signature.len(), 96);
+        assert_eq!(&signature[64..], &challenge[..]);
+
+        let pubkey = PKey::public_key_from_raw_bytes(&pubkey, Id::ED25519).unwrap();
+        let mut verifier = Verifier::new_without_digest(&pubkey).unwrap();
+        assert!(verifier
+            .verify_oneshot(&signature[..64], &challenge)
+            .unwrap());
+    }
+}
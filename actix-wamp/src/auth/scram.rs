@@ -0,0 +1,368 @@
+//! WAMP Salted Challenge Response ("WAMP-SCRAM") authentication implementation
+
+use super::{AuthMethod, Dict};
+use crate::error::Error;
+use hmac::Mac;
+use sha2::Digest;
+use std::marker::PhantomData;
+
+const SCRAM_AUTH_METHOD_ID: &str = "wamp-scram";
+
+const KDF_ARGON2ID13: &str = "argon2id-13";
+const KDF_PBKDF2: &str = "pbkdf2";
+
+const SALTED_PASSWORD_LEN: usize = 32;
+
+struct Scram<F, E>
+where
+    F: FnMut(&str) -> Result<Vec<u8>, E>,
+    E: std::error::Error + Sync + Send + 'static,
+{
+    password_provider: F,
+    client_nonce: Option<String>,
+    /// Signature router proves knowledge of password with in WELCOME.
+    server_signature: Option<Vec<u8>>,
+    _error: PhantomData<E>,
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut hmac = hmac::Hmac::<sha2::Sha256>::new_varkey(key)?;
+    hmac.input(data);
+    Ok(hmac.result().code().to_vec())
+}
+
+/// Keys derived from salted password, as in RFC 5802.
+struct ScramKeys {
+    client_key: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramKeys {
+    fn new(salted_password: &[u8]) -> Result<Self, Error> {
+        let client_key = hmac_sha256(salted_password, b"Client Key")?;
+        let stored_key = sha2::Sha256::digest(&client_key).to_vec();
+        let server_key = hmac_sha256(salted_password, b"Server Key")?;
+        Ok(ScramKeys {
+            client_key,
+            stored_key,
+            server_key,
+        })
+    }
+
+    fn client_proof(&self, auth_message: &str) -> Result<Vec<u8>, Error> {
+        let client_signature = hmac_sha256(&self.stored_key, auth_message.as_bytes())?;
+        Ok(self
+            .client_key
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(k, s)| k ^ s)
+            .collect())
+    }
+
+    fn server_signature(&self, auth_message: &str) -> Result<Vec<u8>, Error> {
+        hmac_sha256(&self.server_key, auth_message.as_bytes())
+    }
+}
+
+fn auth_message(
+    auth_id: &str,
+    client_nonce: &str,
+    nonce: &str,
+    salt: &str,
+    iterations: u64,
+    channel_binding: &str,
+) -> String {
+    format!(
+        "n={},r={},r={},s={},i={},c={},r={}",
+        auth_id, client_nonce, nonce, salt, iterations, channel_binding, nonce
+    )
+}
+
+fn salted_password(
+    password: &[u8],
+    kdf: &str,
+    salt: &[u8],
+    extra: &Dict,
+) -> Result<Vec<u8>, Error> {
+    let iterations = extra
+        .get("iterations")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| Error::protocol_err("missing iterations field"))?;
+
+    match kdf {
+        KDF_ARGON2ID13 => {
+            let memory = extra
+                .get("memory")
+                .and_then(|v| v.as_u64())
+                .ok_or_else(|| Error::protocol_err("missing memory field"))?;
+            let config = argon2::Config {
+                variant: argon2::Variant::Argon2id,
+                version: argon2::Version::Version13,
+                mem_cost: memory as u32,
+                time_cost: iterations as u32,
+                lanes: 1,
+                thread_mode: argon2::ThreadMode::Sequential,
+                hash_length: SALTED_PASSWORD_LEN as u32,
+                ..argon2::Config::default()
+            };
+            Ok(argon2::hash_raw(password, salt, &config)?)
+        }
+        KDF_PBKDF2 => {
+            let mut key = vec![0u8; SALTED_PASSWORD_LEN];
+            openssl::pkcs5::pbkdf2_hmac(
+                password,
+                salt,
+                iterations as usize,
+                openssl::hash::MessageDigest::sha256(),
+                &mut key,
+            )?;
+            Ok(key)
+        }
+        _ => Err(Error::protocol_err("unsupported kdf")),
+    }
+}
+
+impl<F, E> AuthMethod for Scram<F, E>
+where
+    F: FnMut(&str) -> Result<Vec<u8>, E>,
+    E: std::error::Error + Sync + Send + 'static,
+{
+    fn auth_method(&self) -> &str {
+        SCRAM_AUTH_METHOD_ID
+    }
+
+    fn hello_extra(&mut self, _auth_id: &str) -> Result<Dict, Error> {
+        let nonce: [u8; 16] = rand::random();
+        let nonce = base64::encode(&nonce);
+        self.client_nonce = Some(nonce.clone());
+
+        let mut extra = Dict::new();
+        extra.insert("nonce".into(), nonce.into());
+        extra.insert("channel_binding".into(), serde_json::Value::Null);
+        Ok(extra)
+    }
+
+    fn challenge(&mut self, auth_id: &str, extra: &Dict) -> Result<(String, Dict), Error> {
+        let client_nonce = self
+            .client_nonce
+            .take()
+            .ok_or_else(|| Error::protocol_err("challenge without client nonce"))?;
+        let field = |name: &'static str| {
+            extra
+                .get(name)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| Error::ProtocolError(format!("missing {} field", name).into()))
+        };
+        let nonce = field("nonce")?;
+        let salt = field("salt")?;
+        let kdf = field("kdf")?;
+        let iterations = extra
+            .get("iterations")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default();
+        let channel_binding = extra
+            .get("channel_binding")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+
+        if !nonce.starts_with(&client_nonce) {
+            return Err(Error::protocol_err(
+                "server nonce does not extend client nonce",
+            ));
+        }
+
+        let password = (self.password_provider)(auth_id)?;
+        let salted_password = salted_password(&password, kdf, &base64::decode(salt)?, extra)?;
+
+        let auth_message = auth_message(
+            auth_id,
+            &client_nonce,
+            nonce,
+            salt,
+            iterations,
+            channel_binding,
+        );
+        let keys = ScramKeys::new(&salted_password)?;
+        self.server_signature = Some(keys.server_signature(&auth_message)?);
+
+        Ok((
+            base64::encode(&keys.client_proof(&auth_message)?),
+            Dict::default(),
+        ))
+    }
+
+    fn welcome(&mut self, extra: &Dict) -> Result<(), Error> {
+        let expected = self
+            .server_signature
+            .take()
+            .ok_or_else(|| Error::protocol_err("welcome without challenge"))?;
+        let signature = extra
+            .get("scram_server_signature")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| Error::protocol_err("missing scram_server_signature field"))?;
+        if base64::decode(signature)? != expected {
+            return Err(Error::protocol_err("invalid server signature"));
+        }
+        Ok(())
+    }
+}
+
+/// Creates WAMP-SCRAM authentication provider from function providing password.
+///
+/// Both `argon2id-13` and `pbkdf2` key derivation functions are supported.
+pub fn scram_auth<PasswordProvider, Err>(
+    password_provider: PasswordProvider,
+) -> impl AuthMethod + Sync + Send + 'static
+where
+    PasswordProvider: FnMut(&str) -> Result<Vec<u8>, Err> + Sync + Send + 'static,
+    Err: std::error::Error + Sync + Send + 'static,
+{
+    Scram {
+        password_provider,
+        client_nonce: None,
+        server_signature: None,
+        _error: PhantomData,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const NONCE: &str = "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+
+    fn scram() -> impl AuthMethod {
+        Scram {
+            password_provider: |_: &str| Ok::<_, std::io::Error>(b"pencil".to_vec()),
+            client_nonce: Some(CLIENT_NONCE.into()),
+            server_signature: None,
+            _error: PhantomData,
+        }
+    }
+
+    fn extra(value: serde_json::Value) -> Dict {
+        value.as_object().cloned().unwrap()
+    }
+
+    /// SCRAM-SHA-256 example of RFC 7677, which has the same layout of auth message.
+    #[test]
+    fn test_pbkdf2() {
+        let salt = "W22ZaJ0SNY7soEsUEjb6gQ==";
+        assert_eq!(
+            auth_message("user", CLIENT_NONCE, NONCE, salt, 4096, "biws"),
+            format!(
+                "n=user,r={0},r={1},s={2},i=4096,c=biws,r={1}",
+                CLIENT_NONCE, NONCE, salt
+            )
+        );
+
+        let salted = salted_password(
+            b"pencil",
+            KDF_PBKDF2,
+            &base64::decode(salt).unwrap(),
+            &extra(json!({"iterations": 4096})),
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(&salted),
+            "c4a49510323ab4f952cac1fa99441939e78ea74d6be81ddf7096e87513dc615d"
+        );
+        let keys = ScramKeys::new(&salted).unwrap();
+        assert_eq!(
+            hex::encode(&keys.client_key),
+            "a60fc923d67e8644a92d16b96eda5ef4656b0c725c484374be25535576996e8b"
+        );
+        assert_eq!(
+            hex::encode(&keys.stored_key),
+            "586e5df283e6dceb5c3e791d8b8528ec191e664045ce971792e2e6b5bb13e2a6"
+        );
+
+        let mut auth = scram();
+        let (proof, _) = auth
+            .challenge(
+                "user",
+                &extra(json!({
+                    "nonce": NONCE,
+                    "salt": salt,
+                    "kdf": "pbkdf2",
+                    "iterations": 4096,
+                    "channel_binding": "biws",
+                })),
+            )
+            .unwrap();
+        assert_eq!(proof, "dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        auth.welcome(&extra(json!({
+            "scram_server_signature": "6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        })))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_argon2id() {
+        let salt = base64::encode(b"saltsaltsaltsalt");
+        let salted = salted_password(
+            b"pencil",
+            KDF_ARGON2ID13,
+            b"saltsaltsaltsalt",
+            &extra(json!({"iterations": 2, "memory": 64})),
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(&salted),
+            "9bdf86b04625c4bcb9cd5698a5d83e1381e50d4005fe6a465b151e5d88229500"
+        );
+        let keys = ScramKeys::new(&salted).unwrap();
+        assert_eq!(
+            hex::encode(&keys.client_key),
+            "87a8e97fb6c910427a4125ae6387a660b8b1649e98d2483859341f29c0c332d7"
+        );
+        assert_eq!(
+            hex::encode(&keys.stored_key),
+            "f6100ee7fd99047f566888a08ed45f61df54b93679cf8a09000179707126edae"
+        );
+
+        let mut auth = scram();
+        let (proof, _) = auth
+            .challenge(
+                "user",
+                &extra(json!({
+                    "nonce": NONCE,
+                    "salt": salt,
+                    "kdf": "argon2id-13",
+                    "iterations": 2,
+                    "memory": 64,
+                    "channel_binding": null,
+                })),
+            )
+            .unwrap();
+        assert_eq!(proof, "F7hQZ+kmQJK46S2AJtVkdeIfdkt5AVZINqKq/TlewB0=");
+        auth.welcome(&extra(json!({
+            "scram_server_signature": "2fzb4ts5xyZ1+qeVB73SI5Dn+fAQz5CmaTgiuKlFy+I="
+        })))
+        .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_server_signature() {
+        let challenge = extra(json!({
+            "nonce": NONCE,
+            "salt": "W22ZaJ0SNY7soEsUEjb6gQ==",
+            "kdf": "pbkdf2",
+            "iterations": 4096,
+        }));
+
+        let mut auth = scram();
+        auth.challenge("user", &challenge).unwrap();
+        let forged = base64::encode(&[0u8; 32]);
+        assert!(auth
+            .welcome(&extra(json!({ "scram_server_signature": forged })))
+            .is_err());
+
+        let mut auth = scram();
+        auth.challenge("user", &challenge).unwrap();
+        assert!(auth.welcome(&Dict::new()).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//! WAMP Ticket-based authentication implementation
+
+use super::{AuthMethod, Dict};
+use crate::error::Error;
+use std::marker::PhantomData;
+
+const TICKET_AUTH_METHOD_ID: &str = "ticket";
+
+struct Ticket<F, E>(F, PhantomData<E>)
+where
+    F: FnMut(&str) -> Result<String, E>,
+    E: std::error::Error + Sync + Send + 'static;
+
+impl<F, E> AuthMethod for Ticket<F, E>
+where
+    F: FnMut(&str) -> Result<String, E>,
+    E: std::error::Error + Sync + Send + 'static,
+{
+    fn auth_method(&self) -> &str {
+        TICKET_AUTH_METHOD_ID
+    }
+
+    fn challenge(&mut self, auth_id: &str, _extra: &Dict) -> Result<(String, Dict), Error> {
+        let ticket = self.0(auth_id)?;
+        Ok((ticket, Dict::default()))
+    }
+}
+
+/// Creates Ticket authentication provider from function providing ticket for auth id.
+pub fn ticket_auth<TicketProvider, Err>(
+    ticket_provider: TicketProvider,
+) -> impl AuthMethod + Sync + Send + 'static
+where
+    TicketProvider: FnMut(&str) -> Result<String, Err> + Sync + Send + 'static,
+    Err: std::error::Error + Sync + Send + 'static,
+{
+    Ticket(ticket_provider, PhantomData)
+}
@@ -6,6 +6,30 @@ use std::marker::PhantomData;
 
 const CRA_AUTH_METHOD_ID: &str = "wampcra";
 
+const DEFAULT_ITERATIONS: usize = 1000;
+const DEFAULT_KEYLEN: usize = 32;
+
+/// Derives key for salted WAMP-CRA. Router uses base64 encoded PBKDF2 result
+/// as HMAC key.
+fn derive_key(secret: &[u8], salt: &str, extra: &Dict) -> Result<Vec<u8>, Error> {
+    let param = |name, default| {
+        extra
+            .get(name)
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+            .unwrap_or(default)
+    };
+    let mut key = vec![0u8; param("keylen", DEFAULT_KEYLEN)];
+    openssl::pkcs5::pbkdf2_hmac(
+        secret,
+        salt.as_bytes(),
+        param("iterations", DEFAULT_ITERATIONS),
+        openssl::hash::MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(base64::encode(&key).into_bytes())
+}
+
 struct WAMPCra<F, E>(F, PhantomData<E>)
 where
     F: FnMut(&str) -> Result<Vec<u8>, E>,
@@ -32,6 +56,10 @@ where
         };
 
         let secret = self.0(auth_id)?;
+        let secret = match extra.get("salt").and_then(|salt| salt.as_str()) {
+            Some(salt) => derive_key(&secret, salt, extra)?,
+            None => secret,
+        };
         let mut hmac = hmac::Hmac::<sha2::Sha256>::new_varkey(secret.as_ref())?;
         hmac.input(challenge.as_bytes());
         let r = hmac.result().code();
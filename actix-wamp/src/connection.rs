@@ -1,343 +1,106 @@
-use super::messages::types::*;
+//! Actix actor driving [`SessionCore`].
+
 use crate::args::RpcEndpoint;
 use crate::args::*;
 use crate::error::Error;
 use crate::messages::{Dict, WampError};
-use crate::pubsub;
+use crate::observer::SessionObserver;
+use crate::payload::Payload;
 use crate::pubsub::{Subscription, WampMessage};
-use crate::{AuthMethod, ErrorKind};
-use actix::io::WriteHandler;
+use crate::registry::{self, Invocation, Registration};
+use crate::serializer::Serializer;
+use crate::session::{
+    Close, GetSessionInfo, SessionEndpoint, SessionEvent, SessionInfo, WatchSession,
+};
+pub use crate::session_core::OpenSession;
+use crate::session_core::{
+    gen_id, Effect, FrameWriter, Heartbeat, Notify, SessionCore, CLOSE_TIMEOUT,
+};
+use actix::dev::{MessageResponse, ResponseChannel};
+use actix::io::{SinkWrite, WriteHandler};
 use actix::prelude::*;
 use actix_http::ws;
+use futures::channel::oneshot;
 use futures::task::Poll;
-use futures::{
-    channel::mpsc, channel::oneshot, prelude::*, stream::SplitSink, FutureExt, StreamExt,
-    TryFutureExt,
-};
-use serde::Serialize;
+use futures::{prelude::*, stream::SplitSink, FutureExt, StreamExt, TryFutureExt};
+use std::any::Any;
 use std::borrow::Cow;
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::io::Cursor;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-fn gen_id() -> u64 {
-    use rand::Rng;
-
-    let mut rng = rand::thread_rng();
-
-    rng.gen::<u64>() & 0x1f_ff_ff__ff_ff_ff_ffu64
-}
+type Writer<W> = SinkWrite<ws::Message, futures::sink::Buffer<W, ws::Message>>;
 
-pub struct OpenSession {
-    realm_id: String,
-    auth_id: Option<String>,
-    auth_methods: Vec<Box<dyn AuthMethod + Send + 'static>>,
+impl<W> FrameWriter for Writer<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    fn write(&mut self, msg: ws::Message) -> Result<(), Error> {
+        SinkWrite::write(self, msg).map_err(Error::ActixProtocolErorr)
+    }
 }
 
-impl Message for OpenSession {
-    type Result = Result<u64, crate::error::Error>;
+/// Delivers messages from handles (e.g. dropped subscription) to connection actor.
+fn notify<M>(recipient: Recipient<M>) -> Notify<M>
+where
+    M: Message<Result = ()> + Send + 'static,
+{
+    Box::new(move |msg| {
+        let _ = recipient.do_send(msg);
+    })
 }
 
 pub struct Connection<W>
 where
-    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin,
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
 {
     // TODO: Add wait for ready before write
-    writer: actix::io::SinkWrite<ws::Message, futures::sink::Buffer<W, ws::Message>>,
-    state: ConnectionState,
-}
-
-type SubSender = mpsc::UnboundedSender<Result<pubsub::WampMessage, WampError>>;
-
-enum ConnectionState {
-    Closed,
-    Establishing {
-        auth: Vec<Box<dyn AuthMethod + Send + 'static>>,
-        auth_id: Option<String>,
-        tx: Option<oneshot::Sender<Result<u64, Error>>>,
-    },
-    Authenticating {
-        tx: oneshot::Sender<Result<u64, Error>>,
-    },
-    Established {
-        #[allow(dead_code)]
-        session_id: u64,
-        pending_calls: HashMap<u64, CallDesc>,
-        subscribers: HashMap<u64, SubSender>,
-        pending_subscriptions: HashMap<u64, oneshot::Sender<Result<u64, Error>>>,
-    },
-    Failed,
-}
-
-struct CallDesc {
-    tx: oneshot::Sender<Result<RpcCallResponse, Error>>,
-}
-
-impl OpenSession {
-    pub fn anonymous(realm_id: String) -> Self {
-        OpenSession {
-            realm_id,
-            auth_id: None,
-            auth_methods: Vec::new(),
-        }
-    }
-
-    pub fn with_auth<A: AuthMethod + 'static + Send>(
-        realm_id: String,
-        auth_id: String,
-        auth_method: A,
-    ) -> Self {
-        OpenSession {
-            realm_id,
-            auth_id: Some(auth_id),
-            auth_methods: vec![Box::new(auth_method)],
-        }
-    }
+    core: SessionCore<Writer<W>>,
+    heartbeat: Option<Heartbeat>,
+    /// When last frame was received from peer.
+    last_seen: Instant,
 }
 
 impl<W: 'static> Connection<W>
 where
     W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin,
 {
-    fn new(w: W, ctx: &mut <Self as Actor>::Context) -> Self {
+    fn new(
+        w: W,
+        serializer: Serializer,
+        heartbeat: Option<Heartbeat>,
+        observers: Vec<Arc<dyn SessionObserver>>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> Self {
         Connection {
-            writer: io::SinkWrite::new(w.buffer(128), ctx),
-            state: ConnectionState::Closed,
-        }
-    }
-
-    fn send_message<M: Serialize>(&mut self, msg: &M) -> Result<(), Error> {
-        let bytes = rmp_serde::to_vec(&serde_json::to_value(msg)?)?;
-        //let bytes = rmp_serde::to_vec(&serde_json::to_value(msg)?)?;
-
-        if log::log_enabled!(log::Level::Debug) {
-            let out_value = rmpv::decode::read_value(&mut Cursor::new(&bytes)).unwrap();
-            log::debug!("send message {}", out_value);
-        }
-
-        self.writer
-            .write(ws::Message::Binary(bytes.into()))
-            .map(|_| ())
-            .map_err(|e| Error::ActixProtocolErorr(e))
-    }
-
-    fn handle_challenge(&mut self, auth_method: &str, extra: &Dict) -> Result<(), Error> {
-        let (auth_methods, auth_id, tx) = match &mut self.state {
-            ConnectionState::Establishing {
-                auth, auth_id, tx, ..
-            } => match auth_id {
-                Some(auth_id) => (auth, auth_id.as_str(), tx),
-                None => {
-                    return Err(Error::protocol_err(
-                        "unexpected challenge on anonymous handshake",
-                    ))
-                }
-            },
-            _ => {
-                return Err(Error::wamp_error(
-                    ErrorKind::OptionNotAllowed,
-                    "invalid connection state".into(),
-                ))
-            }
-        };
-
-        for auth in auth_methods {
-            if auth.auth_method() == auth_method {
-                let (signature, extra) = auth.challenge(auth_id, extra)?;
-                let tx = tx.take().unwrap();
-                self.state = ConnectionState::Authenticating { tx };
-                self.send_message(&(AUTHENTICATE, signature, extra))?;
-                return Ok(());
-            }
-        }
-
-        self.state = ConnectionState::Failed;
-        Err(Error::protocol_err("unexpected auth method received"))
-    }
-
-    fn handle_welcome(&mut self, session_id: u64, extra: &serde_json::Value) -> Result<(), Error> {
-        log::debug!("got welcome: {}", extra);
-        let old_state = std::mem::replace(
-            &mut self.state,
-            ConnectionState::Established {
-                session_id,
-                pending_calls: HashMap::new(),
-                subscribers: HashMap::new(),
-                pending_subscriptions: HashMap::new(),
-            },
-        );
-        match old_state {
-            ConnectionState::Establishing { tx, .. } => {
-                let _ = tx.unwrap().send(Ok(session_id));
-            }
-            ConnectionState::Authenticating { tx, .. } => {
-                let _ = tx.send(Ok(session_id));
-            }
-            _ => (),
-        };
-
-        Ok(())
-    }
-
-    fn pending_calls(&mut self) -> Result<&mut HashMap<u64, CallDesc>, Error> {
-        match &mut self.state {
-            ConnectionState::Established { pending_calls, .. } => Ok(pending_calls),
-            _ => Err(Error::InvalidState("session is closed or pending")),
-        }
-    }
-
-    fn handle_subscribed(&mut self, request_id: u64, subscription_id: u64) -> Result<(), Error> {
-        self.pending_subscriptions()?
-            .remove(&request_id)
-            .and_then(|sender| sender.send(Ok(subscription_id)).ok());
-        Ok(())
-    }
-
-    fn handle_result(&mut self, call_id: u64, args: Option<rmpv::Value>) -> Result<(), Error> {
-        if let Some(CallDesc { tx }) = self.pending_calls()?.remove(&call_id) {
-            let args = args
-                .and_then(|args| serde_json::to_value(args).ok())
-                .and_then(|args| args.as_array().cloned())
-                .unwrap_or_default();
-
-            let _ = tx.send(Ok(RpcCallResponse {
-                args,
-                kw_args: None,
-            }));
+            core: SessionCore::new(SinkWrite::new(w.buffer(128), ctx), serializer)
+                .with_observers(observers),
+            heartbeat,
+            last_seen: Instant::now(),
         }
-        Ok(())
     }
 
-    #[inline]
-    fn subscribers(&mut self) -> Result<&mut HashMap<u64, SubSender>, Error> {
-        match &mut self.state {
-            ConnectionState::Established { subscribers, .. } => Ok(subscribers),
-            _ => Err(Error::InvalidState("session is closed or pending")),
+    /// Pings peer and drops connection if nothing is received within `timeout`.
+    fn ping(&mut self, timeout: Duration, ctx: &mut <Self as Actor>::Context) {
+        let sent = Instant::now();
+        if !self.core.ping() {
+            return;
         }
-    }
-
-    #[inline]
-    fn pending_subscriptions(
-        &mut self,
-    ) -> Result<&mut HashMap<u64, oneshot::Sender<Result<u64, Error>>>, Error> {
-        match &mut self.state {
-            ConnectionState::Established {
-                pending_subscriptions,
-                ..
-            } => Ok(pending_subscriptions),
-            _ => Err(Error::InvalidState("session is closed or pending")),
-        }
-    }
-
-    fn handle_event(
-        &mut self,
-        sub_id: u64,
-        _pub_id: u64,
-        args: Option<&rmpv::Value>,
-        _kwargs: Option<&rmpv::Value>,
-    ) -> Result<(), Error> {
-        if let Some(tx) = self.subscribers()?.get_mut(&sub_id) {
-            let args = args
-                .and_then(|args| serde_json::to_value(args).ok())
-                .and_then(|args| args.as_array().cloned())
-                .unwrap_or_default();
-
-            // TODO: catch kw_args
-
-            let _ = tx.unbounded_send(Ok(WampMessage {
-                args,
-                kw_args: None,
-            }));
-        } else {
-            log::warn!("unhandled event: subscription_id={}", sub_id);
-        }
-        Ok(())
-    }
-
-    fn handle_abort(
-        &mut self,
-        error_uri: &str,
-        extra: &Vec<(rmpv::Value, rmpv::Value)>,
-    ) -> Result<(), Error> {
-        match std::mem::replace(&mut self.state, ConnectionState::Failed) {
-            ConnectionState::Authenticating { tx } => {
-                // TODO: log error
-                let _ = tx.send(Err(Error::from_abort(error_uri, extra)));
+        ctx.run_later(timeout, move |act, ctx| {
+            if act.last_seen < sent {
+                act.core.heartbeat_timeout();
+                // `stopped` reports lost connection.
+                ctx.stop();
             }
-            ConnectionState::Established { pending_calls, .. } => {
-                for (_call_id, desc) in pending_calls {
-                    // TODO: log error
-                    let _ = desc.tx.send(Err(Error::from_abort(error_uri, extra)));
-                }
-            }
-            _ => (),
-        }
-
-        Ok(())
-    }
-    // [
-    //      ERROR,
-    //      REQUEST.Type|int,
-    //      REQUEST.Request|id,
-    //      Details|dict,
-    //      Error|uri,
-    //      Arguments|list,
-    // ArgumentsKw|dict]
-    fn handle_error(
-        &mut self,
-        request_type: u64,
-        request_id: u64,
-        details: &rmpv::Value,
-        error_uri: &str,
-        args: &rmpv::Value,
-        kwargs: &rmpv::Value,
-    ) -> Result<(), Error> {
-        match request_type.try_into()? {
-            CALL => self.handle_error_call(request_id, details, error_uri, args, kwargs),
-            SUBSCRIBE => self.handle_error_subscribe(request_id, details, error_uri, args, kwargs),
-            _ => Ok(()),
-        }
-    }
-
-    fn handle_error_call(
-        &mut self,
-        request_id: u64,
-        _details: &rmpv::Value,
-        error_uri: &str,
-        args: &rmpv::Value,
-        kwargs: &rmpv::Value,
-    ) -> Result<(), Error> {
-        log::info!("handle call: {}", request_id);
-        let calls = match &mut self.state {
-            ConnectionState::Established { pending_calls, .. } => pending_calls,
-            _ => return Ok(()),
-        };
-        if let Some(desc) = calls.remove(&request_id) {
-            let _ = desc
-                .tx
-                .send(Err(Error::from_wamp_error_message(error_uri, args, kwargs)));
-        } else {
-            log::error!("invalid id");
-        }
-        Ok(())
+        });
     }
 
-    fn handle_error_subscribe(
-        &mut self,
-        request_id: u64,
-        _details: &rmpv::Value,
-        error_uri: &str,
-        args: &rmpv::Value,
-        kwargs: &rmpv::Value,
-    ) -> Result<(), Error> {
-        log::info!("handle call: {}", request_id);
-        if let Some(tx) = self.subscribers()?.remove(&request_id) {
-            let _ = tx.unbounded_send(Err(WampError::new(error_uri, args, kwargs)));
-        } else {
-            log::error!("invalid id");
-        }
-        Ok(())
+    /// Closes transport after session has ended.
+    fn shutdown(&mut self, ctx: &mut <Self as Actor>::Context) {
+        self.core.close_transport();
+        self.core.writer_mut().close();
+        // stop even if peer does not close its side.
+        ctx.run_later(CLOSE_TIMEOUT, |_, ctx| ctx.stop());
     }
 }
 
@@ -347,12 +110,21 @@ where
 {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
-        let _ = self.writer.write(ws::Message::Ping("smok".into()));
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let _ = self
+            .core
+            .writer_mut()
+            .write(ws::Message::Ping("smok".into()));
+        if let Some(heartbeat) = self.heartbeat {
+            ctx.run_interval(heartbeat.interval, move |act, ctx| {
+                act.ping(heartbeat.timeout, ctx)
+            });
+        }
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
         log::debug!("connection stopped");
+        self.core.connection_lost();
     }
 }
 
@@ -360,82 +132,27 @@ impl<W: 'static> StreamHandler<Result<ws::Frame, ws::ProtocolError>> for Connect
 where
     W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin,
 {
-    fn handle(&mut self, item: Result<ws::Frame, ws::ProtocolError>, _ctx: &mut Self::Context) {
-        let item = item.unwrap();
-
-        match item {
-            ws::Frame::Binary(bytes) => {
-                let value = rmpv::decode::read_value(&mut Cursor::new(bytes.as_ref())).unwrap();
-                log::trace!("got message ={}", value);
-
-                match value[0].as_i64().unwrap() as u8 {
-                    WELCOME => {
-                        let _ = self.handle_welcome(
-                            value[1].as_u64().unwrap(),
-                            &serde_json::to_value(&value[2].as_map()).unwrap(),
-                        );
-                    }
-                    ABORT => {
-                        // [3, {"message": "WAMP-CRA signature is invalid"}, "wamp.error.not_authorized"]
-                        let _ = self
-                            .handle_abort(value[2].as_str().unwrap(), value[1].as_map().unwrap());
-                    }
-
-                    CHALLENGE => {
-                        self.handle_challenge(
-                            value[1].as_str().unwrap(),
-                            &serde_json::to_value(&value[2])
-                                .unwrap()
-                                .as_object()
-                                .unwrap(),
-                        )
-                        .unwrap_or_else(|e| {
-                            log::error!("auth mathod failed with: {}", e);
-                        });
-                    }
-                    RESULT => {
-                        let _ =
-                            self.handle_result(value[1].as_u64().unwrap(), Some(value[3].clone()));
-                    }
-                    SUBSCRIBED => {
-                        let request_id = value[1].as_u64().unwrap();
-                        let subscription_id = value[2].as_u64().unwrap();
-                        let _ = self.handle_subscribed(request_id, subscription_id);
-                    }
-
-                    EVENT => {
-                        //[EVENT, SUBSCRIBED.Subscription|id, PUBLISHED.Publication|id, Details|dict, PUBLISH.Arguments|list, PUBLISH.ArgumentKw|dict]
-                        let subscription_id = value[1].as_u64().unwrap();
-                        let publication_id = value[2].as_u64().unwrap();
-                        let args = value.as_array().and_then(|a| a.get(4));
-                        let kwargs = value.as_array().and_then(|a| a.get(5));
-                        self.handle_event(subscription_id, publication_id, args, kwargs)
-                            .unwrap();
-                    }
-                    ERROR => {
-                        // There are 2 formats
-                        // [
-                        //      ERROR,
-                        //      REQUEST.Type|int,
-                        //      REQUEST.Request|id,
-                        //      Details|dict,
-                        //      Error|uri,
-                        //      Arguments|list,
-                        // ArgumentsKw|dict]
-                        log::trace!("got error");
-                        let _ = self.handle_error(
-                            value[1].as_u64().unwrap(),
-                            value[2].as_u64().unwrap(),
-                            &value[3],
-                            value[4].as_str().unwrap(),
-                            &value[5],
-                            &value[6],
-                        );
+    fn handle(&mut self, item: Result<ws::Frame, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                self.core.transport_error(&e);
+                ctx.stop();
+                return;
+            }
+        };
+        self.last_seen = Instant::now();
+
+        match self.core.handle_frame(item) {
+            Effect::None => (),
+            Effect::Invocation(request_id, reply) => {
+                let _ = ctx.spawn(reply.into_actor(self).map(move |result, act, _ctx| {
+                    if let Err(e) = act.core.send_invocation_result(request_id, result) {
+                        log::error!("unable to send invocation result: {}", e);
                     }
-                    _ => {}
-                }
+                }));
             }
-            _ => log::debug!("h={:?}", item),
+            Effect::Shutdown => self.shutdown(ctx),
         }
     }
 
@@ -450,7 +167,7 @@ where
 {
     fn error(&mut self, err: ws::ProtocolError, _ctx: &mut Self::Context) -> Running {
         log::error!("protocol error: {}", err);
-        self.state = ConnectionState::Failed;
+        self.core.fail();
         Running::Stop
     }
 }
@@ -459,61 +176,74 @@ impl<W> Handler<OpenSession> for Connection<W>
 where
     W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
 {
-    type Result = ActorResponse<Self, u64, crate::error::Error>;
+    // Not bound to actor, so that reply is delivered even if router drops connection
+    // right after ABORT.
+    type Result = ResponseFuture<Result<u64, crate::error::Error>>;
 
-    fn handle(
+    fn handle(&mut self, msg: OpenSession, _ctx: &mut Self::Context) -> Self::Result {
+        self.core.open(msg)
+    }
+}
+
+impl<W> Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    /// Cancels call with [`Error::Timeout`] when it does not complete in time.
+    fn watch_timeout(
         &mut self,
-        OpenSession {
-            realm_id,
-            auth_id,
-            auth_methods,
-        }: OpenSession,
-        _ctx: &mut Self::Context,
-    ) -> Self::Result {
-        use crate::messages::{HelloSpec, Role, RoleDesc};
-
-        // check state
-        match self.state {
-            ConnectionState::Closed => (),
-            _ => {
-                return ActorResponse::reply(Err(Error::InvalidState(
-                    "session is already opened or operation pending",
-                )))
-            }
+        request_id: u64,
+        timeout: Option<Duration>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        if let Some(timeout) = timeout {
+            let _ = ctx.run_later(timeout, move |act, _ctx| {
+                if let Err(e) = act.core.cancel_call(request_id, Some(Error::Timeout)) {
+                    log::debug!("unable to cancel call {}: {}", request_id, e);
+                }
+            });
         }
+    }
+}
 
-        let (tx, rx) = futures::channel::oneshot::channel();
-        let auth_methods_id = auth_methods.iter().map(|method| method.auth_method());
-
-        let auth_id_ref = match &auth_id {
-            Some(v) => Some(v.as_str()),
-            None => None,
-        };
-
-        let _ = self.send_message(&(
-            HELLO,
-            realm_id,
-            HelloSpec {
-                roles: vec![(Role::Caller, RoleDesc::default())]
-                    .into_iter()
-                    .collect(),
-                auth_methods: auth_methods_id.collect(),
-                authid: auth_id_ref,
-            },
-        ));
-        self.state = ConnectionState::Establishing {
-            auth: auth_methods,
-            auth_id,
-            tx: Some(tx),
-        };
+/// Response to [`RpcCallRequest`] sent straight to connection actor.
+///
+/// Like [`RpcEndpoint::rpc_call`], call is cancelled when request is dropped before
+/// result arrives.
+pub struct CallResponse {
+    result: Pin<Box<dyn Future<Output = Result<RpcCallResponse, Error>>>>,
+    guard: CancelOnDrop,
+}
 
-        ActorResponse::r#async(
-            rx.then(|r| match r {
-                Err(_e) => future::err(Error::ConnectionClosed),
-                Ok(resp) => future::ready(resp),
-            })
-            .into_actor(self),
-        )
+impl<W> MessageResponse<Connection<W>, RpcCallRequest> for CallResponse
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    fn handle<R: ResponseChannel<RpcCallRequest>>(
+        self,
+        _ctx: &mut Context<Connection<W>>,
+        mut tx: Option<R>,
+    ) {
+        let CallResponse { result, guard } = self;
+        Arbiter::spawn(async move {
+            // `Addr::send` replies through oneshot channel, closed when request is dropped.
+            let closed = (&mut tx as &mut dyn Any)
+                .downcast_mut::<Option<oneshot::Sender<Result<RpcCallResponse, Error>>>>()
+                .and_then(Option::as_mut)
+                .map(|sender| future::poll_fn(move |cx| sender.poll_canceled(cx)));
+            let result = match closed {
+                Some(closed) => match future::select(result, closed).await {
+                    future::Either::Left((result, _)) => result,
+                    // Dropping guard cancels call.
+                    future::Either::Right(_) => return,
+                },
+                None => result.await,
+            };
+            guard.disarm();
+            if let Some(tx) = tx {
+                tx.send(result);
+            }
+        });
     }
 }
 
@@ -521,68 +251,81 @@ impl<W> Handler<RpcCallRequest> for Connection<W>
 where
     W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
 {
-    type Result = ActorResponse<Self, RpcCallResponse, crate::error::Error>;
+    type Result = CallResponse;
 
-    fn handle(
-        &mut self,
-        RpcCallRequest {
-            uri,
-            options,
-            args,
-            kw_args,
-        }: RpcCallRequest,
-        _ctx: &mut Self::Context,
-    ) -> Self::Result {
-        let pending_calls = match &mut self.state {
-            ConnectionState::Established { pending_calls, .. } => pending_calls,
-            _ => {
-                return ActorResponse::reply(Err(Error::InvalidState(
-                    "session is closed or pending",
-                )))
+    fn handle(&mut self, request: RpcCallRequest, ctx: &mut Self::Context) -> Self::Result {
+        let timeout = request.timeout;
+        let request_id = gen_id();
+        let guard = CancelOnDrop::new(request_id, notify(ctx.address().recipient()));
+        let result = match self.core.call(request_id, request) {
+            Ok(rx) => {
+                self.watch_timeout(request_id, timeout, ctx);
+                async move {
+                    let payload = rx.await.map_err(|_| Error::ConnectionClosed)??;
+                    Ok(payload.into())
+                }
+                .boxed_local()
             }
+            Err(e) => future::err(e).boxed_local(),
         };
+        CallResponse { result, guard }
+    }
+}
 
-        // generate rpc-call-id. spec says that is should be random.
-        let id = {
-            let mut id = gen_id();
+impl<W> Handler<StartCall> for Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    type Result = Result<CallReceiver, Error>;
 
-            while pending_calls.contains_key(&id) {
-                id = gen_id()
-            }
+    fn handle(
+        &mut self,
+        StartCall(request_id, request): StartCall,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let timeout = request.timeout;
+        let rx = self.core.call(request_id, request)?;
+        self.watch_timeout(request_id, timeout, ctx);
+        Ok(rx)
+    }
+}
 
-            id
-        };
-        let (tx, rx) = oneshot::channel();
+impl<W> Handler<StartProgressiveCall> for Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    type Result = Result<ProgressiveReceiver, Error>;
 
-        pending_calls.insert(id, CallDesc { tx });
+    fn handle(
+        &mut self,
+        StartProgressiveCall(request_id, request): StartProgressiveCall,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        let timeout = request.timeout;
+        let rx = self.core.call_progressive(request_id, request)?;
+        self.watch_timeout(request_id, timeout, ctx);
+        Ok(rx)
+    }
+}
 
-        let result = match (args, kw_args) {
-            (None, None) => self.send_message(&(CALL, id, options.unwrap_or_default(), uri)),
-            (Some(args), None) => {
-                self.send_message(&(CALL, id, options.unwrap_or_default(), uri, args))
-            }
-            (args, Some(kw_args)) => self.send_message(&(
-                CALL,
-                id,
-                options.unwrap_or_default(),
-                uri,
-                args.unwrap_or_else(|| serde_json::json!([])),
-                kw_args,
-            )),
-        };
+impl<W> Handler<CancelCall> for Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    type Result = ();
 
-        if let Err(e) = result {
-            return ActorResponse::reply(Err(e));
+    fn handle(&mut self, msg: CancelCall, _ctx: &mut Self::Context) -> Self::Result {
+        if let Err(e) = self.core.cancel_call(msg.request_id, None) {
+            log::debug!("unable to cancel call {}: {}", msg.request_id, e);
         }
-
-        ActorResponse::r#async(
-            async move { rx.await.map_err(|_| Error::ConnectionClosed)? }.into_actor(self),
-        )
     }
 }
 
 pub fn connect<Transport>(
     transport: Transport,
+    serializer: Serializer,
+    heartbeat: Option<Heartbeat>,
+    observers: Vec<Arc<dyn SessionObserver>>,
 ) -> Addr<Connection<SplitSink<Transport, ws::Message>>>
 where
     Transport: Sink<ws::Message, Error = ws::ProtocolError>
@@ -592,7 +335,7 @@ where
     let (split_sink, split_stream) = transport.split();
     Connection::create(move |ctx| {
         Connection::add_stream(split_stream, ctx);
-        Connection::new(split_sink, ctx)
+        Connection::new(split_sink, serializer, heartbeat, observers, ctx)
     })
 }
 
@@ -606,11 +349,54 @@ where
     type Response = Pin<Box<dyn Future<Output = Result<RpcCallResponse, Error>> + 'static>>;
 
     fn rpc_call(&self, request: RpcCallRequest) -> Self::Response {
-        self.send(request)
+        self.rpc_call_payload(request)
+            .map_ok(RpcCallResponse::from)
+            .boxed_local()
+    }
+
+    fn rpc_call_payload(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<Payload, Error>> + 'static>> {
+        let request_id = gen_id();
+        // dropping response future before result arrives cancels call. Mailbox is FIFO,
+        // so cancel sent before CALL is started comes after it.
+        let guard = CancelOnDrop::new(request_id, notify(self.clone().recipient()));
+        let started = self.send(StartCall(request_id, request));
+
+        async move {
+            let result = match started.await {
+                Ok(Ok(rx)) => rx.await.map_err(|_| Error::ConnectionClosed),
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(Error::MailboxError(e)),
+            };
+            guard.disarm();
+            result?
+        }
+        .boxed_local()
+    }
+
+    fn rpc_call_progressive(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Stream<Item = Result<RpcCallResponse, Error>> + 'static>> {
+        let request_id = gen_id();
+        let guard = CancelOnDrop::new(request_id, notify(self.clone().recipient()));
+
+        self.send(StartProgressiveCall(request_id, request))
             .then(|resp| match resp {
                 Err(e) => future::err(Error::MailboxError(e)),
                 Ok(v) => future::ready(v),
             })
+            .map(move |started| match started {
+                Ok(rx) => Ok(ProgressiveResults::new(rx, guard)),
+                Err(e) => {
+                    guard.disarm();
+                    Err(e)
+                }
+            })
+            .try_flatten_stream()
+            .map_ok(RpcCallResponse::from)
             .boxed_local()
     }
 }
@@ -627,6 +413,22 @@ where
     Closed,
 }
 
+impl<Transport> FromRequest<Transport>
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + Unpin
+        + 'static,
+{
+    /// Number of events dropped because buffer was full, 0 until subscribed.
+    pub fn dropped(&self) -> u64 {
+        match self {
+            FromRequest::Subscription(sub) => sub.dropped(),
+            _ => 0,
+        }
+    }
+}
+
 impl<Transport> Stream for FromRequest<Transport>
 where
     Transport: Sink<ws::Message, Error = ws::ProtocolError>
@@ -650,12 +452,7 @@ where
                 },
             },
             FromRequest::Closed => return Poll::Ready(None),
-            FromRequest::Subscription(sub) => {
-                return sub
-                    .stream
-                    .poll_next_unpin(cx)
-                    .map(|item_or_end| item_or_end.map(|r| r.map_err(|e| Error::WampError(e))))
-            }
+            FromRequest::Subscription(sub) => return sub.stream.poll_next_unpin(cx),
         };
         self.set(next_state);
         if ret.is_ready() {
@@ -675,52 +472,59 @@ where
 {
     type Events = FromRequest<Transport>;
     //FlattenStream<Flatten<Request<Connection<SplitSink<Transport, ws::Message>>, crate::pubsub::Subscribe>, Error>>;
+    type Published = Pin<Box<dyn Future<Output = Result<Option<u64>, Error>> + 'static>>;
 
-    fn subscribe(&self, uri: &str) -> Self::Events {
+    fn subscribe_with(&self, uri: &str, options: crate::pubsub::SubscribeOptions) -> Self::Events {
         FromRequest::Request(self.send(crate::pubsub::Subscribe {
             topic: Cow::Owned(uri.into()),
+            options,
         }))
     }
+
+    fn publish(
+        &self,
+        topic: &str,
+        args: Vec<serde_json::Value>,
+        kw_args: Option<Dict>,
+        options: crate::pubsub::PublishOptions,
+    ) -> Self::Published {
+        self.send(crate::pubsub::Publish {
+            topic: Cow::Owned(topic.into()),
+            args,
+            kw_args,
+            options,
+        })
+        .then(|resp| match resp {
+            Err(e) => future::err(Error::MailboxError(e)),
+            Ok(v) => future::ready(v),
+        })
+        .boxed_local()
+    }
 }
 
-impl<Transport> Handler<crate::pubsub::Subscribe> for Connection<SplitSink<Transport, ws::Message>>
+impl<Transport> Handler<crate::pubsub::Publish> for Connection<SplitSink<Transport, ws::Message>>
 where
     Transport: Sink<ws::Message, Error = ws::ProtocolError>
         + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
         + 'static,
 {
-    type Result = ActorResponse<Self, crate::pubsub::Subscription, Error>;
-
-    fn handle(&mut self, msg: crate::pubsub::Subscribe, _ctx: &mut Self::Context) -> Self::Result {
-        let (tx, rx) = oneshot::channel();
+    type Result = ResponseFuture<Result<Option<u64>, Error>>;
 
-        let request_id = gen_id();
+    fn handle(&mut self, msg: crate::pubsub::Publish, _ctx: &mut Self::Context) -> Self::Result {
+        self.core.publish(msg)
+    }
+}
 
-        match self.pending_subscriptions() {
-            Ok(pending_subscriptions) => pending_subscriptions.insert(request_id, tx),
-            Err(e) => return ActorResponse::reply(Err(e)),
-        };
+impl<Transport> Handler<crate::pubsub::Subscribe> for Connection<SplitSink<Transport, ws::Message>>
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + 'static,
+{
+    type Result = ResponseFuture<Result<crate::pubsub::Subscription, Error>>;
 
-        self.send_message(&(SUBSCRIBE, request_id, Dict::default(), msg.topic.as_ref()))
-            .unwrap();
-
-        ActorResponse::r#async(
-            rx.map_err(From::from)
-                .and_then(|response| future::ready(response))
-                .into_actor(self)
-                .then(|subscription_id, act: &mut Self, ctx: &mut Self::Context| {
-                    let (tx, rx) = mpsc::unbounded();
-                    actix::fut::result((|| {
-                        let subscription_id = subscription_id?;
-                        act.subscribers()?.insert(subscription_id, tx);
-                        Ok(crate::pubsub::Subscription {
-                            subscription_id,
-                            stream: rx,
-                            connection: ctx.address().recipient(),
-                        })
-                    })())
-                }),
-        )
+    fn handle(&mut self, msg: crate::pubsub::Subscribe, ctx: &mut Self::Context) -> Self::Result {
+        self.core.subscribe(msg, notify(ctx.address().recipient()))
     }
 }
 
@@ -738,9 +542,567 @@ where
         msg: crate::pubsub::Unsubscribe,
         _ctx: &mut Self::Context,
     ) -> Self::Result {
-        let _ = self.subscribers().and_then(|s| {
-            let _ = s.remove(&msg.subscription_id);
+        self.core.unsubscribe(msg)
+    }
+}
+
+impl<Transport> registry::RpcRegistry for Addr<Connection<SplitSink<Transport, ws::Message>>>
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + Unpin
+        + 'static,
+{
+    type Registration = Pin<Box<dyn Future<Output = Result<Registration, Error>> + 'static>>;
+
+    fn register<Handler, Reply, Response>(&self, uri: &str, handler: Handler) -> Self::Registration
+    where
+        Handler: Fn(Invocation) -> Reply + Send + 'static,
+        Reply: Future<Output = Result<Response, WampError>> + 'static,
+        Response: Into<Payload> + 'static,
+    {
+        self.send(registry::Register {
+            uri: Cow::Owned(uri.into()),
+            handler: Box::new(move |invocation| {
+                handler(invocation).map_ok(Into::into).boxed_local()
+            }),
+        })
+        .then(|resp| match resp {
+            Err(e) => future::err(Error::MailboxError(e)),
+            Ok(v) => future::ready(v),
+        })
+        .boxed_local()
+    }
+}
+
+impl<Transport> Handler<registry::Register> for Connection<SplitSink<Transport, ws::Message>>
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + 'static,
+{
+    type Result = ResponseFuture<Result<Registration, Error>>;
+
+    fn handle(&mut self, msg: registry::Register, ctx: &mut Self::Context) -> Self::Result {
+        self.core.register(msg, notify(ctx.address().recipient()))
+    }
+}
+
+impl<Transport> Handler<registry::Unregister> for Connection<SplitSink<Transport, ws::Message>>
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: registry::Unregister, _ctx: &mut Self::Context) -> Self::Result {
+        self.core.unregister(msg)
+    }
+}
+
+impl<W> Handler<Close> for Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    type Result = ResponseFuture<Result<(), Error>>;
+
+    fn handle(&mut self, msg: Close, ctx: &mut Self::Context) -> Self::Result {
+        match self.core.close(&msg.reason) {
+            Ok(closing) => {
+                ctx.run_later(CLOSE_TIMEOUT, |act, ctx| {
+                    if act.core.close_timeout() {
+                        act.shutdown(ctx);
+                    }
+                });
+                closing
+            }
+            Err(e) => {
+                ctx.stop();
+                Box::pin(future::err(e))
+            }
+        }
+    }
+}
+
+impl<W> Handler<GetSessionInfo> for Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    type Result = Result<SessionInfo, Error>;
+
+    fn handle(&mut self, _msg: GetSessionInfo, _ctx: &mut Self::Context) -> Self::Result {
+        self.core.session_info()
+    }
+}
+
+impl<W> Handler<WatchSession> for Connection<W>
+where
+    W: Sink<ws::Message, Error = ws::ProtocolError> + Unpin + 'static,
+{
+    type Result = MessageResult<WatchSession>;
+
+    fn handle(&mut self, _msg: WatchSession, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.core.watch())
+    }
+}
+
+impl<Transport> SessionEndpoint for Addr<Connection<SplitSink<Transport, ws::Message>>>
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + Unpin
+        + 'static,
+{
+    type Closing = Pin<Box<dyn Future<Output = Result<(), Error>> + 'static>>;
+    type Closed = Pin<Box<dyn Future<Output = ()> + 'static>>;
+    type SessionEvents = Pin<Box<dyn Stream<Item = SessionEvent> + 'static>>;
+    type SessionInfo = Pin<Box<dyn Future<Output = Result<SessionInfo, Error>> + 'static>>;
+
+    fn close(&self, reason: &str) -> Self::Closing {
+        self.send(Close {
+            reason: Cow::Owned(reason.into()),
+        })
+        .then(|resp| match resp {
+            Err(e) => future::err(Error::MailboxError(e)),
+            Ok(v) => future::ready(v),
+        })
+        .boxed_local()
+    }
+
+    fn closed(&self) -> Self::Closed {
+        self.session_events()
+            .filter(|event| future::ready(matches!(event, SessionEvent::Closed { .. })))
+            .into_future()
+            .map(|_| ())
+            .boxed_local()
+    }
+
+    fn session_events(&self) -> Self::SessionEvents {
+        // stream is empty when connection is already stopped.
+        self.send(WatchSession)
+            .map(Result::ok)
+            .into_stream()
+            .filter_map(future::ready)
+            .flatten()
+            .boxed_local()
+    }
+
+    fn session_info(&self) -> Self::SessionInfo {
+        self.send(GetSessionInfo)
+            .then(|resp| match resp {
+                Err(e) => future::err(Error::MailboxError(e)),
+                Ok(v) => future::ready(v),
+            })
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::messages::types::*;
+    use crate::pubsub::{Subscribe, SubscribeOptions};
+    use futures::channel::mpsc;
+    use serde_json::json;
+
+    pub(crate) struct TestTransport {
+        tx: mpsc::UnboundedSender<ws::Message>,
+        rx: mpsc::UnboundedReceiver<Result<ws::Frame, ws::ProtocolError>>,
+    }
+
+    impl Sink<ws::Message> for TestTransport {
+        type Error = ws::ProtocolError;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut futures::task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: ws::Message) -> Result<(), Self::Error> {
+            let _ = self.tx.unbounded_send(item);
             Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut futures::task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut futures::task::Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Stream for TestTransport {
+        type Item = Result<ws::Frame, ws::ProtocolError>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            cx: &mut futures::task::Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx)
+        }
+    }
+
+    /// Router side of [`TestTransport`].
+    pub(crate) struct Peer {
+        rx: mpsc::UnboundedReceiver<ws::Message>,
+        tx: mpsc::UnboundedSender<Result<ws::Frame, ws::ProtocolError>>,
+    }
+
+    impl Peer {
+        pub(crate) fn pair() -> (TestTransport, Peer) {
+            let (out_tx, out_rx) = mpsc::unbounded();
+            let (in_tx, in_rx) = mpsc::unbounded();
+            (
+                TestTransport {
+                    tx: out_tx,
+                    rx: in_rx,
+                },
+                Peer {
+                    rx: out_rx,
+                    tx: in_tx,
+                },
+            )
+        }
+
+        pub(crate) async fn recv(&mut self) -> serde_json::Value {
+            loop {
+                if let ws::Message::Text(text) = self.rx.next().await.unwrap() {
+                    return serde_json::from_str(&text).unwrap();
+                }
+            }
+        }
+
+        pub(crate) fn send(&self, msg: serde_json::Value) {
+            let frame = ws::Frame::Text(msg.to_string().into());
+            self.tx.unbounded_send(Ok(frame)).unwrap();
+        }
+    }
+
+    type TestConnection = Addr<Connection<SplitSink<TestTransport, ws::Message>>>;
+
+    /// Connects to [`Peer`] and opens anonymous session.
+    async fn open_session(heartbeat: Option<Heartbeat>) -> (TestConnection, Peer) {
+        let (transport, mut peer) = Peer::pair();
+        let connection = connect(transport, Serializer::Json, heartbeat, Vec::new());
+
+        let session = connection.send(OpenSession::anonymous("realm".into()));
+        assert_eq!(peer.recv().await[0], json!(HELLO));
+        peer.send(json!([WELCOME, 1, {}]));
+        session.await.unwrap().unwrap();
+        (connection, peer)
+    }
+
+    fn subscribe(topic: &'static str) -> Subscribe {
+        Subscribe {
+            topic: topic.into(),
+            options: SubscribeOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_shared_subscription() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+
+            let first = connection.send(subscribe("evt.a"));
+            let second = connection.send(subscribe("evt.a"));
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(SUBSCRIBE));
+            assert_eq!(request[3], json!("evt.a"));
+            peer.send(json!([SUBSCRIBED, request[1], 7]));
+            let mut first = first.await.unwrap().unwrap();
+            let mut second = second.await.unwrap().unwrap();
+            assert_eq!(first.subscription_id, 7);
+            assert_eq!(second.subscription_id, 7);
+
+            peer.send(json!([EVENT, 7, 100, {"topic": "evt.a"}, ["x"]]));
+            for events in [&mut first, &mut second] {
+                let event = events.next().await.unwrap().unwrap();
+                assert_eq!(event.args, vec![json!("x")]);
+                assert_eq!(event.details.publication_id, 100);
+            }
+
+            // Remaining consumers keep broker subscription alive.
+            drop(first);
+            let third = connection.send(subscribe("evt.a")).await.unwrap().unwrap();
+            assert_eq!(third.subscription_id, 7);
+            drop(second);
+            drop(third);
+
+            let _other = connection.send(subscribe("evt.b"));
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(UNSUBSCRIBE));
+            assert_eq!(request[2], json!(7));
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(SUBSCRIBE));
+            assert_eq!(request[3], json!("evt.b"));
+        });
+    }
+
+    #[test]
+    fn test_event_while_closing() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+
+            let subscription = connection.send(subscribe("evt.a"));
+            let request = peer.recv().await;
+            peer.send(json!([SUBSCRIBED, request[1], 7]));
+            let _events = subscription.await.unwrap().unwrap();
+
+            let closing = connection.close("wamp.close.normal");
+            assert_eq!(peer.recv().await[0], json!(GOODBYE));
+            // Published before router got GOODBYE.
+            peer.send(json!([EVENT, 7, 100, {}, ["x"]]));
+            peer.send(json!([GOODBYE, {}, "wamp.close.goodbye_and_out"]));
+
+            closing.await.unwrap();
+            connection.closed().await;
+        });
+    }
+
+    #[test]
+    fn test_forged_server_signature() {
+        System::new("test").block_on(async {
+            let (transport, mut peer) = Peer::pair();
+            let connection = connect(transport, Serializer::Json, None, Vec::new());
+            let auth = crate::auth::scram::scram_auth(|_: &str| {
+                Ok::<_, std::io::Error>(b"pencil".to_vec())
+            });
+            let session =
+                connection.send(OpenSession::with_auth("realm".into(), "user".into(), auth));
+
+            let hello = peer.recv().await;
+            let nonce = hello[2]["authextra"]["nonce"].as_str().unwrap().to_string();
+            peer.send(json!([CHALLENGE, "wamp-scram", {
+                "nonce": nonce + "srv",
+                "salt": "W22ZaJ0SNY7soEsUEjb6gQ==",
+                "kdf": "pbkdf2",
+                "iterations": 16,
+            }]));
+            assert_eq!(peer.recv().await[0], json!(AUTHENTICATE));
+            peer.send(json!([WELCOME, 1, {
+                "authextra": {"scram_server_signature": base64::encode(&[0u8; 32])},
+            }]));
+
+            let abort = peer.recv().await;
+            assert_eq!(abort[0], json!(ABORT));
+            assert_eq!(abort[2], json!("wamp.error.cannot_authenticate"));
+            assert!(session.await.unwrap().is_err());
+            connection.closed().await;
+        });
+    }
+
+    #[test]
+    fn test_rejected_challenge() {
+        System::new("test").block_on(async {
+            let (transport, mut peer) = Peer::pair();
+            let connection = connect(transport, Serializer::Json, None, Vec::new());
+            let auth = crate::auth::scram::scram_auth(|_: &str| {
+                Ok::<_, std::io::Error>(b"pencil".to_vec())
+            });
+            let session =
+                connection.send(OpenSession::with_auth("realm".into(), "user".into(), auth));
+
+            let hello = peer.recv().await;
+            let nonce = hello[2]["authextra"]["nonce"].as_str().unwrap().to_string();
+            peer.send(json!([CHALLENGE, "wamp-scram", {
+                "nonce": nonce + "srv",
+                "salt": "not base64!",
+                "kdf": "pbkdf2",
+                "iterations": 16,
+            }]));
+
+            let abort = peer.recv().await;
+            assert_eq!(abort[0], json!(ABORT));
+            assert_eq!(abort[2], json!("wamp.error.cannot_authenticate"));
+            assert!(session.await.unwrap().is_err());
+            connection.closed().await;
+        });
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+
+            let result = connection.rpc_call(RpcCallRequest::with_no_args("test.big"));
+            assert_eq!(peer.recv().await[0], json!(CALL));
+            // codec rejects frame over limit.
+            peer.tx
+                .unbounded_send(Err(ws::ProtocolError::Overflow))
+                .unwrap();
+
+            assert!(matches!(result.await, Err(Error::MessageTooLarge)));
+            connection.closed().await;
+        });
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+
+            let result = connection.rpc_call(RpcCallRequest::with_no_args("test.echo"));
+            assert_eq!(peer.recv().await[0], json!(CALL));
+            peer.tx
+                .unbounded_send(Ok(ws::Frame::Text("[50, ".into())))
+                .unwrap();
+
+            assert!(matches!(result.await, Err(Error::ProtocolError(_))));
+            connection.closed().await;
+
+            let (connection, mut peer) = open_session(None).await;
+            let result = connection.rpc_call(RpcCallRequest::with_no_args("test.echo"));
+            assert_eq!(peer.recv().await[0], json!(CALL));
+            // RESULT without request id.
+            peer.send(json!([RESULT, "x", {}]));
+            assert!(matches!(result.await, Err(Error::ProtocolError(_))));
+        });
+    }
+
+    #[test]
+    fn test_abort_without_details() {
+        System::new("test").block_on(async {
+            let (transport, mut peer) = Peer::pair();
+            let connection = connect(transport, Serializer::Json, None, Vec::new());
+
+            let session = connection.send(OpenSession::anonymous("realm".into()));
+            assert_eq!(peer.recv().await[0], json!(HELLO));
+            peer.send(json!([ABORT, null, "wamp.error.no_such_realm"]));
+
+            match session.await.unwrap() {
+                Err(Error::WampError(e)) => assert_eq!(e.code, crate::ErrorKind::NoSuchRealm),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("session opened after abort"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_binary_result() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+
+            let payload = Payload::with_args(&(u64::MAX,)).unwrap();
+            let result =
+                connection.rpc_call_payload(RpcCallRequest::with_payload("test.data", payload));
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(CALL));
+            assert_eq!(request[4], json!([u64::MAX]));
+            // Binary data is sent as base64 string prefixed with NUL in json.
+            peer.send(json!([RESULT, request[1], {}, ["\u{0}AAH/", u64::MAX]]));
+
+            let result = result.await.unwrap();
+            assert_eq!(result.args[0], rmpv::Value::Binary(vec![0, 1, 255]));
+            assert_eq!(result.parse_arg::<Vec<u8>>(0).unwrap(), vec![0, 1, 255]);
+            assert_eq!(result.parse_arg::<u64>(1).unwrap(), u64::MAX);
+        });
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(Some(Heartbeat {
+                interval: Duration::from_millis(50),
+                timeout: Duration::from_millis(50),
+            }))
+            .await;
+
+            peer.tx
+                .unbounded_send(Ok(ws::Frame::Ping("x".into())))
+                .unwrap();
+            loop {
+                match peer.rx.next().await.unwrap() {
+                    ws::Message::Pong(payload) => break assert_eq!(payload, "x"),
+                    _ => continue,
+                }
+            }
+
+            // Peer goes silent, pending subscription fails.
+            let subscription = connection.send(subscribe("evt.a"));
+            assert_eq!(peer.recv().await[0], json!(SUBSCRIBE));
+            match subscription.await.unwrap() {
+                Err(Error::HeartbeatTimeout) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("subscribed without reply"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_call_timeout() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+
+            let result = connection.rpc_call(
+                RpcCallRequest::with_no_args("test.slow").with_timeout(Duration::from_millis(50)),
+            );
+            let call = peer.recv().await;
+            assert_eq!(call[0], json!(CALL));
+            assert_eq!(call[2]["timeout"], json!(50));
+
+            match result.await {
+                Err(Error::Timeout) => (),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("call finished without reply"),
+            }
+            assert_eq!(
+                peer.recv().await,
+                json!([CANCEL, call[1], {"mode": "kill"}])
+            );
+        });
+    }
+
+    #[test]
+    fn test_cancel_on_drop() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+            let request =
+                RpcCallRequest::with_no_args("test.slow").with_cancel_mode(CancelMode::Skip);
+
+            let result = connection.rpc_call(request.clone());
+            let call = peer.recv().await;
+            assert_eq!(call[0], json!(CALL));
+            drop(result);
+            assert_eq!(
+                peer.recv().await,
+                json!([CANCEL, call[1], {"mode": "skip"}])
+            );
+
+            // Dropped before driver got to it, call is not even started.
+            drop(connection.rpc_call(request));
+            let _result = connection.rpc_call(RpcCallRequest::with_no_args("test.next"));
+            let call = peer.recv().await;
+            assert_eq!(call[0], json!(CALL));
+            assert_eq!(call[3], json!("test.next"));
+        });
+    }
+
+    #[test]
+    fn test_cancel_on_dropped_request() {
+        System::new("test").block_on(async {
+            let (connection, mut peer) = open_session(None).await;
+            let request =
+                RpcCallRequest::with_no_args("test.slow").with_cancel_mode(CancelMode::Skip);
+
+            let result = connection.send(request);
+            let call = peer.recv().await;
+            assert_eq!(call[0], json!(CALL));
+            drop(result);
+            assert_eq!(
+                peer.recv().await,
+                json!([CANCEL, call[1], {"mode": "skip"}])
+            );
         });
     }
 }
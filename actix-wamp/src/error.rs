@@ -1,4 +1,3 @@
-use crate::messages::Dict;
 use crate::messages::WampError;
 use crate::ErrorKind;
 use actix::MailboxError;
@@ -9,7 +8,7 @@ use std::error;
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "{}", _0)]
-    WampError(WampError),
+    WampError(Box<WampError>),
 
     #[fail(display = "protocol error: {}", _0)]
     ProtocolError(Cow<'static, str>),
@@ -20,6 +19,29 @@ pub enum Error {
     #[fail(display = "connection closed")]
     ConnectionClosed,
 
+    #[fail(display = "call timeout")]
+    Timeout,
+
+    /// Peer did not respond to keepalive ping in time
+    /// (see [`WsOptions::with_heartbeat`](crate::WsOptions::with_heartbeat)).
+    #[fail(display = "heartbeat timeout")]
+    HeartbeatTimeout,
+
+    /// Call was not sent, because earlier one in batch failed
+    /// (see [`BatchOptions::stop_on_error`](crate::BatchOptions::stop_on_error)).
+    #[fail(display = "batch stopped after earlier failure")]
+    BatchStopped,
+
+    /// Peer sent message over size limit
+    /// (see [`WsOptions::with_max_message_size`](crate::WsOptions::with_max_message_size)).
+    #[fail(display = "message too large")]
+    MessageTooLarge,
+
+    /// Subscription consumer fell behind and its buffer overflowed
+    /// (see [`OverflowPolicy::Close`](crate::OverflowPolicy::Close)).
+    #[fail(display = "subscription buffer overflow")]
+    BufferOverflow,
+
     /// Throwed by connection actor in cases when you request action in wrong momment.
     ///
     /// For example:
@@ -50,44 +72,15 @@ impl Error {
     }
 
     pub fn wamp_error(code: ErrorKind, message: String) -> Self {
-        let extra = Dict::new();
-
-        Error::WampError(WampError {
-            code,
-            message,
-            extra,
-        })
+        Error::WampError(Box::new(WampError::with_message(code, message)))
     }
 
-    pub fn from_abort(uri: &str, extra: &Vec<(rmpv::Value, rmpv::Value)>) -> Self {
-        let code = ErrorKind::from_uri(uri);
-        let extra: Dict = extra
-            .into_iter()
-            .filter_map(|(k, v)| {
-                let key = match k {
-                    rmpv::Value::String(key) => key.clone().into_str()?,
-                    _ => return None,
-                };
-                let value = serde_json::to_value(v).ok()?;
-
-                Some((key, value))
-            })
-            .collect();
-        let message = extra
-            .get("message")
-            .and_then(|v| v.as_str())
-            .unwrap_or_else(|| code.uri())
-            .to_string();
-
-        Error::WampError(WampError {
-            code,
-            message,
-            extra,
-        })
+    pub fn from_abort(uri: &str, extra: &[(rmpv::Value, rmpv::Value)]) -> Self {
+        Error::WampError(Box::new(WampError::from_details(uri, extra)))
     }
 
     pub fn from_wamp_error_message(uri: &str, args: &rmpv::Value, kwargs: &rmpv::Value) -> Self {
-        Error::WampError(WampError::new(uri, args, kwargs))
+        Error::WampError(Box::new(WampError::new(uri, args, kwargs)))
     }
 }
 
@@ -3,30 +3,69 @@ mod auth;
 pub(crate) mod connection;
 mod error;
 mod messages;
+mod observer;
+mod payload;
 pub(crate) mod pubsub;
+mod reconnect;
+pub(crate) mod registry;
+#[cfg(feature = "test-router")]
+pub mod router;
+mod serializer;
+pub(crate) mod session;
+pub(crate) mod session_core;
+mod tokio_session;
 mod transport;
 
-pub use messages::ErrorKind;
+pub use messages::{ErrorKind, Role, RoleDesc, RoleMap};
 
+pub use auth::cryptosign::cryptosign_auth;
+pub use auth::scram::scram_auth;
+pub use auth::ticket::ticket_auth;
 pub use auth::wampcra::challenge_response_auth;
 pub use auth::AuthMethod;
 pub use error::Error;
 pub use messages::WampError;
-pub use pubsub::PubSubEndpoint;
-pub use transport::{wss, ClientError};
+#[cfg(feature = "tracing")]
+pub use observer::CallTracing;
+pub use observer::{
+    CallFinished, CallOutcome, CallStarted, CallStats, EventDelivered, LatencyHistogram,
+    ProcedureStats, SessionObserver, SubscriptionChange,
+};
+pub use payload::Payload;
+pub use pubsub::{
+    BufferOptions, DroppedEvents, EventDetails, MatchPolicy, OverflowPolicy, PubSubEndpoint,
+    PublishOptions, SubscribeOptions, WampMessage,
+};
+pub use reconnect::{
+    Backoff, CallPolicy, ConnectionStatus, ReconnectOptions, ReconnectingSession, TopicEvents,
+};
+pub use registry::{Invocation, Registration, RpcRegistry};
+pub use serializer::Serializer;
+pub use session::{CloseReason, SessionEndpoint, SessionEvent, SessionInfo, CLOSE_NORMAL};
+pub use tokio_session::{SessionDriver, TokioSession};
+pub use transport::{
+    rawsocket, record, ws, ws_with_options, wss, wss_with_options, wss_with_serializers,
+    ClientError, Proxy, TlsPolicy, WsOptions, DEFAULT_MAX_MESSAGE_SIZE,
+};
 
-pub use args::{RpcCallRequest, RpcCallResponse, RpcEndpoint, ToArgs};
+pub use args::{BatchOptions, CancelMode, RpcCallRequest, RpcCallResponse, RpcEndpoint, ToArgs};
 use futures::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct SessionBuilder {
-    msg: connection::OpenSession,
+    msg: session_core::OpenSession,
+    options: WsOptions,
+    observers: Vec<Arc<dyn SessionObserver>>,
 }
 
 impl SessionBuilder {
     #[inline]
     pub fn anonymous(realm_id: String) -> Self {
         SessionBuilder {
-            msg: connection::OpenSession::anonymous(realm_id),
+            msg: session_core::OpenSession::anonymous(realm_id),
+            options: WsOptions::default(),
+            observers: Vec::new(),
         }
     }
 
@@ -37,21 +76,114 @@ impl SessionBuilder {
         auth_method: A,
     ) -> Self {
         SessionBuilder {
-            msg: connection::OpenSession::with_auth(realm_id.into(), auth_id.into(), auth_method),
+            msg: session_core::OpenSession::with_auth(realm_id.into(), auth_id.into(), auth_method),
+            options: WsOptions::default(),
+            observers: Vec::new(),
         }
     }
 
+    /// Offers additional auth method to router, e.g. to fall back from cryptosign to ticket.
+    ///
+    /// Router picks the method, so order does not matter.
+    pub fn add_auth<A: AuthMethod + 'static + Send>(mut self, auth_method: A) -> Self {
+        self.msg.add_auth(auth_method);
+        self
+    }
+
+    /// Sets serializers offered to router, in order of preference.
+    ///
+    /// Transports without protocol negotiation use the first one.
+    pub fn with_serializers(mut self, serializers: &[Serializer]) -> Self {
+        self.options = self.options.with_serializers(serializers);
+        self
+    }
+
+    /// Sets server certificate verification policy used by [`create_wss`](#method.create_wss).
+    ///
+    /// Default policy does not verify certificate.
+    pub fn with_tls_policy(mut self, tls_policy: TlsPolicy) -> Self {
+        self.options = self.options.with_tls_policy(tls_policy);
+        self
+    }
+
+    /// Enables keepalive pings, see [`WsOptions::with_heartbeat`].
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.options = self.options.with_heartbeat(interval, timeout);
+        self
+    }
+
+    /// Connects through given proxy, see [`WsOptions::with_proxy`].
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.options = self.options.with_proxy(proxy);
+        self
+    }
+
+    /// Takes proxy from environment, see [`WsOptions::with_env_proxy`].
+    pub fn with_env_proxy(mut self) -> Self {
+        self.options = self.options.with_env_proxy();
+        self
+    }
+
+    /// Sets largest message accepted from router, see [`WsOptions::with_max_message_size`].
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.options = self.options.with_max_message_size(max_message_size);
+        self
+    }
+
+    /// Offers permessage-deflate compression, see [`WsOptions::with_compression`].
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.options = self.options.with_compression(compression);
+        self
+    }
+
+    /// Adds observer notified about calls, events and state changes of session,
+    /// e.g. [`CallStats`].
+    pub fn with_observer(mut self, observer: impl SessionObserver + 'static) -> Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
     pub fn create<Transport>(
         self,
         transport: Transport,
-    ) -> impl Future<Output = Result<impl RpcEndpoint + PubSubEndpoint + Clone, Error>>
+    ) -> impl Future<
+        Output = Result<
+            impl RpcEndpoint + PubSubEndpoint + RpcRegistry + SessionEndpoint + Clone,
+            Error,
+        >,
+    >
+    where
+        Transport: Sink<actix_http::ws::Message, Error = actix_http::ws::ProtocolError>
+            + Stream<Item = Result<actix_http::ws::Frame, actix_http::ws::ProtocolError>>
+            + Unpin
+            + 'static,
+    {
+        let serializer = self.options.serializers[0];
+        self.create_with_serializer(transport, serializer)
+    }
+
+    fn create_with_serializer<Transport>(
+        self,
+        transport: Transport,
+        serializer: Serializer,
+    ) -> impl Future<
+        Output = Result<
+            impl RpcEndpoint + PubSubEndpoint + RpcRegistry + SessionEndpoint + Clone,
+            Error,
+        >,
+    >
     where
         Transport: Sink<actix_http::ws::Message, Error = actix_http::ws::ProtocolError>
             + Stream<Item = Result<actix_http::ws::Frame, actix_http::ws::ProtocolError>>
             + Unpin
             + 'static,
     {
-        let connection = connection::connect(transport);
+        let connection = connection::connect(
+            transport,
+            serializer,
+            self.options.heartbeat,
+            self.observers,
+        );
 
         connection
             .send(self.msg)
@@ -62,13 +194,122 @@ impl SessionBuilder {
             .and_then(|_| future::ok(connection))
     }
 
+    /// Opens session without actix `System`, see [`TokioSession`].
+    ///
+    /// Returned driver has to be polled (e.g. spawned with `tokio::task::spawn_local`)
+    /// for session to open.
+    ///
+    /// Only [`rawsocket`] transports work on plain tokio runtime. [`ws`] and [`wss`]
+    /// connect with `awc`, which still has to run inside actix `System`.
+    ///
+    /// ```no_run
+    /// # use actix_wamp::{rawsocket, Error, Serializer, SessionBuilder};
+    /// # async fn run() -> Result<(), Error> {
+    /// let transport = rawsocket::tcp("127.0.0.1", 8080, Serializer::MsgPack).await?;
+    /// let (driver, session) = SessionBuilder::anonymous("realm1".into()).create_driven(transport);
+    /// tokio::task::spawn_local(driver);
+    /// let session = session.await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn create_driven<Transport>(
+        self,
+        transport: Transport,
+    ) -> (
+        SessionDriver,
+        impl Future<Output = Result<TokioSession, Error>>,
+    )
+    where
+        Transport: Sink<actix_http::ws::Message, Error = actix_http::ws::ProtocolError>
+            + Stream<Item = Result<actix_http::ws::Frame, actix_http::ws::ProtocolError>>
+            + 'static,
+    {
+        let serializer = self.options.serializers[0];
+        let (session, driver) = tokio_session::connect(
+            transport,
+            serializer,
+            self.options.heartbeat,
+            self.observers,
+        );
+
+        (driver, session.open(self.msg))
+    }
+
+    pub fn create_ws(
+        self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<
+        Output = Result<
+            impl RpcEndpoint + PubSubEndpoint + RpcRegistry + SessionEndpoint + Clone,
+            Error,
+        >,
+    > {
+        ws_with_options(host, port, &self.options)
+            .map_err(|e| Error::WsClientError(format!("{}", e)))
+            .and_then(move |(transport, serializer)| {
+                self.create_with_serializer(transport, serializer)
+            })
+    }
+
     pub fn create_wss(
         self,
         host: &str,
         port: u16,
-    ) -> impl Future<Output = Result<impl RpcEndpoint + PubSubEndpoint + Clone, Error>> {
-        wss(host, port)
+    ) -> impl Future<
+        Output = Result<
+            impl RpcEndpoint + PubSubEndpoint + RpcRegistry + SessionEndpoint + Clone,
+            Error,
+        >,
+    > {
+        wss_with_options(host, port, &self.options)
             .map_err(|e| Error::WsClientError(format!("{}", e)))
-            .and_then(move |(transport, _hash)| self.create(transport))
+            .and_then(move |(transport, serializer, _hash)| {
+                self.create_with_serializer(transport, serializer)
+            })
+    }
+
+    /// Connects with WAMP RawSocket over TCP.
+    ///
+    /// RawSocket has no serializer negotiation, the first preferred one is used.
+    pub fn create_tcp(
+        self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<
+        Output = Result<
+            impl RpcEndpoint + PubSubEndpoint + RpcRegistry + SessionEndpoint + Clone,
+            Error,
+        >,
+    > {
+        let serializer = self.options.serializers[0];
+
+        let max_len_exp = rawsocket::max_len_exp(self.options.max_message_size);
+
+        rawsocket::tcp_with_max_len(host, port, serializer, max_len_exp)
+            .map_err(Error::from)
+            .and_then(move |transport| self.create_with_serializer(transport, serializer))
+    }
+
+    /// Connects with WAMP RawSocket over Unix domain socket.
+    ///
+    /// RawSocket has no serializer negotiation, the first preferred one is used.
+    #[cfg(unix)]
+    pub fn create_unix(
+        self,
+        path: impl AsRef<std::path::Path>,
+    ) -> impl Future<
+        Output = Result<
+            impl RpcEndpoint + PubSubEndpoint + RpcRegistry + SessionEndpoint + Clone,
+            Error,
+        >,
+    > {
+        let serializer = self.options.serializers[0];
+
+        let max_len_exp = rawsocket::max_len_exp(self.options.max_message_size);
+
+        rawsocket::unix_with_max_len(path, serializer, max_len_exp)
+            .map_err(Error::from)
+            .and_then(move |transport| self.create_with_serializer(transport, serializer))
     }
 }
@@ -1,3 +1,6 @@
+use crate::error::Error;
+use crate::payload::Payload;
+use serde::de::DeserializeOwned;
 use serde_derive::*;
 use serde_json::{Map, Value};
 use std::collections::btree_map::BTreeMap;
@@ -26,23 +29,46 @@ pub mod types {
     pub const CALL: u8 = 48;
     pub const CANCEL: u8 = 49;
     pub const RESULT: u8 = 50;
+    pub const REGISTER: u8 = 64;
+    pub const REGISTERED: u8 = 65;
+    pub const UNREGISTER: u8 = 66;
+    pub const UNREGISTERED: u8 = 67;
+    pub const INVOCATION: u8 = 68;
     pub const INTERRUPT: u8 = 69;
+    pub const YIELD: u8 = 70;
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 #[serde(default)]
 pub struct RoleDesc {
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    pub features: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, bool>,
+}
+
+impl RoleDesc {
+    pub fn with_features(features: &[&str]) -> Self {
+        RoleDesc {
+            features: features
+                .iter()
+                .map(|feature| (feature.to_string(), true))
+                .collect(),
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.get(feature).copied().unwrap_or_default()
+    }
 }
 
-#[derive(Serialize, Deserialize, Hash, PartialOrd, PartialEq, Eq, Ord)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialOrd, PartialEq, Eq, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     Caller,
     Callee,
     Publisher,
+    Subscriber,
     Dealer,
+    Broker,
 }
 
 /*
@@ -56,9 +82,13 @@ pub enum Role {
 
 #[derive(Debug, Clone)]
 pub struct WampError {
+    /// Error uri, unknown ones are kept in [`ErrorKind::Other`].
     pub code: ErrorKind,
     pub message: String,
+    /// Named arguments (or ABORT details) converted to json.
     pub extra: Dict,
+    /// Positional and named arguments of ERROR as sent by peer.
+    pub payload: Payload,
 }
 
 impl Display for WampError {
@@ -68,6 +98,43 @@ impl Display for WampError {
 }
 
 impl WampError {
+    /// Error without arguments, e.g. to be returned from procedure handler.
+    pub fn with_message(code: ErrorKind, message: impl Into<String>) -> Self {
+        WampError {
+            code,
+            message: message.into(),
+            extra: Dict::new(),
+            payload: Payload::default(),
+        }
+    }
+
+    /// Replaces arguments sent to caller, by default message is sent as
+    /// the only positional argument and `extra` as named ones.
+    pub fn with_payload(mut self, payload: Payload) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    pub fn uri(&self) -> &str {
+        self.code.uri()
+    }
+
+    /// Deserializes positional arguments of error, e.g. as tuple.
+    pub fn parse_args<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.payload.parse_args()
+    }
+
+    /// Deserializes single positional argument of error.
+    pub fn parse_arg<T: DeserializeOwned>(&self, index: usize) -> Result<T, Error> {
+        self.payload.parse_arg(index)
+    }
+
+    /// Deserializes named arguments of error, e.g. as struct with application
+    /// specific details.
+    pub fn parse_kw_args<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.payload.parse_kw_args()
+    }
+
     pub fn new(uri: &str, args: &rmpv::Value, kwargs: &rmpv::Value) -> Self {
         let code = ErrorKind::from_uri(uri);
         let extra: Dict = kwargs
@@ -97,6 +164,36 @@ impl WampError {
             code,
             message,
             extra,
+            payload: Payload::from_message(Some(args), Some(kwargs)),
+        }
+    }
+
+    /// Builds error from reason uri and details of ABORT or GOODBYE message.
+    pub fn from_details(uri: &str, details: &[(rmpv::Value, rmpv::Value)]) -> Self {
+        let code = ErrorKind::from_uri(uri);
+        let extra: Dict = details
+            .iter()
+            .filter_map(|(k, v)| {
+                let key = match k {
+                    rmpv::Value::String(key) => key.clone().into_str()?,
+                    _ => return None,
+                };
+                let value = serde_json::to_value(v).ok()?;
+
+                Some((key, value))
+            })
+            .collect();
+        let message = extra
+            .get("message")
+            .and_then(|v| v.as_str())
+            .unwrap_or_else(|| code.uri())
+            .to_string();
+
+        WampError {
+            code,
+            message,
+            extra,
+            payload: Payload::default(),
         }
     }
 }
@@ -230,6 +327,9 @@ pub struct HelloSpec<'a> {
     pub auth_methods: Vec<&'a str>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub authid: Option<&'a str>,
+    #[serde(rename = "authextra")]
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    pub auth_extra: Dict,
 }
 
 #[cfg(test)]
@@ -257,6 +357,7 @@ mod test {
                 .collect(),
             auth_methods: vec![],
             authid: None,
+            auth_extra: Dict::new(),
         };
 
         eprintln!("json={}", serde_json::to_value(&val).unwrap());
@@ -0,0 +1,497 @@
+//! Hooks for observing session activity, e.g. to collect metrics.
+
+use crate::error::Error;
+use crate::session::SessionEvent;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Receives notifications about session activity.
+///
+/// Observers are added with [`SessionBuilder::with_observer`](crate::SessionBuilder::with_observer)
+/// and called from connection driver, so they should return quickly. All methods do nothing
+/// by default.
+pub trait SessionObserver: Send + Sync {
+    /// CALL was sent to dealer.
+    fn call_started(&self, _call: &CallStarted<'_>) {}
+
+    /// Call completed, failed or was cancelled. Progressive results are not reported,
+    /// only the final one.
+    fn call_finished(&self, _call: &CallFinished<'_>) {}
+
+    /// EVENT was delivered to consumers of subscription.
+    fn event_delivered(&self, _event: &EventDelivered<'_>) {}
+
+    /// Broker subscription was created or removed.
+    fn subscription_changed(&self, _change: &SubscriptionChange<'_>) {}
+
+    /// Session state changed, same as [`SessionEndpoint::session_events`](crate::SessionEndpoint::session_events).
+    fn session_event(&self, _event: &SessionEvent) {}
+}
+
+#[derive(Debug)]
+pub struct CallStarted<'a> {
+    pub uri: &'a str,
+    pub request_id: u64,
+    /// Size of encoded CALL message in bytes.
+    pub request_size: usize,
+}
+
+#[derive(Debug)]
+pub struct CallFinished<'a> {
+    pub uri: &'a str,
+    pub request_id: u64,
+    /// Time since CALL was sent.
+    pub latency: Duration,
+    pub outcome: CallOutcome<'a>,
+    /// Size of encoded CALL message in bytes.
+    pub request_size: usize,
+    /// Size of RESULT or ERROR message in bytes, zero when call failed locally.
+    pub response_size: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CallOutcome<'a> {
+    Success,
+    /// Error returned by callee, timeout or lost session.
+    Failed(&'a Error),
+    /// Caller dropped response before it arrived.
+    Cancelled,
+}
+
+#[derive(Debug)]
+pub struct EventDelivered<'a> {
+    pub topic: &'a str,
+    pub subscription_id: u64,
+    pub publication_id: u64,
+    /// Size of EVENT message in bytes.
+    pub size: usize,
+}
+
+#[derive(Debug)]
+pub enum SubscriptionChange<'a> {
+    Subscribed {
+        topic: &'a str,
+        subscription_id: u64,
+    },
+    Unsubscribed {
+        subscription_id: u64,
+    },
+}
+
+/// Observer aggregating call counts and latencies per procedure uri.
+///
+/// Clones share statistics, so one clone can be given to session and another
+/// used for reporting.
+///
+/// ```
+/// # use actix_wamp::{CallStats, SessionBuilder};
+/// let stats = CallStats::new();
+/// let builder = SessionBuilder::anonymous("realm1".into()).with_observer(stats.clone());
+/// // ...
+/// for (uri, procedure) in stats.snapshot() {
+///     println!("{}: {} calls, p99 {:?}", uri, procedure.calls, procedure.latency.quantile(0.99));
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct CallStats {
+    procedures: Arc<Mutex<HashMap<String, ProcedureStats>>>,
+}
+
+impl CallStats {
+    pub fn new() -> Self {
+        CallStats::default()
+    }
+
+    /// Statistics collected so far, by procedure uri.
+    pub fn snapshot(&self) -> BTreeMap<String, ProcedureStats> {
+        let procedures = self.procedures.lock().unwrap();
+        procedures
+            .iter()
+            .map(|(uri, stats)| (uri.clone(), stats.clone()))
+            .collect()
+    }
+
+    /// Clears collected statistics.
+    pub fn reset(&self) {
+        self.procedures.lock().unwrap().clear();
+    }
+}
+
+impl SessionObserver for CallStats {
+    fn call_finished(&self, call: &CallFinished<'_>) {
+        let mut procedures = self.procedures.lock().unwrap();
+        let stats = match procedures.get_mut(call.uri) {
+            Some(stats) => stats,
+            None => procedures.entry(call.uri.to_string()).or_default(),
+        };
+        stats.calls += 1;
+        match call.outcome {
+            CallOutcome::Success => (),
+            CallOutcome::Failed(Error::Timeout) => {
+                stats.errors += 1;
+                stats.timeouts += 1;
+            }
+            CallOutcome::Failed(_) => stats.errors += 1,
+            CallOutcome::Cancelled => stats.cancelled += 1,
+        }
+        stats.request_bytes += call.request_size as u64;
+        stats.response_bytes += call.response_size as u64;
+        stats.latency.record(call.latency);
+    }
+}
+
+/// Observer opening [`tracing`] span for every call, with fields `uri`, `request_id`,
+/// `outcome` (`success`, `failed` or `cancelled`) and `latency_ms`.
+///
+/// Span lasts from sending CALL until call is finished. Available with `tracing` feature.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+pub struct CallTracing {
+    spans: Arc<Mutex<HashMap<u64, tracing::Span>>>,
+}
+
+#[cfg(feature = "tracing")]
+impl CallTracing {
+    pub fn new() -> Self {
+        CallTracing::default()
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl SessionObserver for CallTracing {
+    fn call_started(&self, call: &CallStarted<'_>) {
+        let span = tracing::info_span!(
+            "wamp_call",
+            uri = call.uri,
+            request_id = call.request_id,
+            outcome = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        self.spans.lock().unwrap().insert(call.request_id, span);
+    }
+
+    fn call_finished(&self, call: &CallFinished<'_>) {
+        let span = match self.spans.lock().unwrap().remove(&call.request_id) {
+            Some(span) => span,
+            None => return,
+        };
+        let outcome = match call.outcome {
+            CallOutcome::Success => "success",
+            CallOutcome::Failed(_) => "failed",
+            CallOutcome::Cancelled => "cancelled",
+        };
+        span.record("outcome", outcome);
+        span.record("latency_ms", call.latency.as_millis() as u64);
+        if let CallOutcome::Failed(e) = call.outcome {
+            span.in_scope(|| tracing::debug!(error = %e, "call failed"));
+        }
+    }
+}
+
+/// Statistics of single procedure, see [`CallStats`].
+#[derive(Debug, Clone, Default)]
+pub struct ProcedureStats {
+    /// Finished calls, including failed and cancelled.
+    pub calls: u64,
+    /// Calls failed with error, including timeouts.
+    pub errors: u64,
+    pub timeouts: u64,
+    pub cancelled: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub latency: LatencyHistogram,
+}
+
+/// Upper bounds of histogram buckets are powers of two milliseconds, the last bucket
+/// holds everything above.
+const BUCKETS: usize = 18;
+
+/// Latency histogram with exponential buckets from 1ms to about 65s.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    fn bucket_bound(bucket: usize) -> Option<Duration> {
+        if bucket + 1 < BUCKETS {
+            Some(Duration::from_millis(1 << bucket))
+        } else {
+            None
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = (0..BUCKETS)
+            .find(|&bucket| match Self::bucket_bound(bucket) {
+                Some(bound) => latency <= bound,
+                None => true,
+            })
+            .unwrap_or(BUCKETS - 1);
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.total / self.count as u32)
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Upper bound of bucket containing given quantile, e.g. `0.99`. Quantiles in the
+    /// last bucket are reported as [`max`](#method.max).
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(
+                    Self::bucket_bound(bucket)
+                        .map(|bound| bound.min(self.max))
+                        .unwrap_or(self.max),
+                );
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Non-empty buckets as upper bound and count, `None` bound is the overflow bucket.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| (Self::bucket_bound(bucket), count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::Peer;
+    use crate::messages::types::*;
+    use crate::{RpcCallRequest, RpcEndpoint, Serializer, SessionBuilder};
+    use serde_json::json;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl SessionObserver for Arc<Recorder> {
+        fn call_started(&self, call: &CallStarted<'_>) {
+            self.0.lock().unwrap().push(format!("start {}", call.uri));
+        }
+
+        fn call_finished(&self, call: &CallFinished<'_>) {
+            let outcome = match call.outcome {
+                CallOutcome::Success => "ok",
+                CallOutcome::Failed(_) => "failed",
+                CallOutcome::Cancelled => "cancelled",
+            };
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("finish {} {}", call.uri, outcome));
+        }
+
+        fn session_event(&self, event: &SessionEvent) {
+            if let SessionEvent::Established { .. } = event {
+                self.0.lock().unwrap().push("established".into());
+            }
+        }
+    }
+
+    #[test]
+    fn test_observe_calls() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let stats = CallStats::new();
+        let recorder = Arc::new(Recorder::default());
+        let (transport, mut peer) = Peer::pair();
+        let (driver, session) = SessionBuilder::anonymous("realm".into())
+            .with_serializers(&[Serializer::Json])
+            .with_observer(stats.clone())
+            .with_observer(recorder.clone())
+            .create_driven(transport);
+
+        let client = async move {
+            let session = session.await.unwrap();
+            let call = |uri: &'static str| session.rpc_call(RpcCallRequest::with_no_args(uri));
+            call("test.ok").await.unwrap();
+            assert!(call("test.err").await.is_err());
+        };
+        let router = async move {
+            assert_eq!(peer.recv().await[0], json!(HELLO));
+            peer.send(json!([WELCOME, 1, {}]));
+            let request = peer.recv().await;
+            peer.send(json!([RESULT, request[1], {}, ["done"]]));
+            let request = peer.recv().await;
+            peer.send(json!([
+                ERROR,
+                CALL,
+                request[1],
+                {},
+                "wamp.error.invalid_argument"
+            ]));
+            peer
+        };
+        runtime.block_on(async { futures::join!(client, router, driver) });
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                "established",
+                "start test.ok",
+                "finish test.ok ok",
+                "start test.err",
+                "finish test.err failed",
+            ]
+        );
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot["test.ok"].calls, 1);
+        assert_eq!(snapshot["test.ok"].errors, 0);
+        assert!(snapshot["test.ok"].response_bytes > 0);
+        assert_eq!(snapshot["test.err"].errors, 1);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        histogram.record(Duration::from_secs(100));
+
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.quantile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(64)));
+        assert_eq!(histogram.quantile(0.95), Some(Duration::from_millis(128)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(100)));
+        assert_eq!(histogram.max(), Duration::from_secs(100));
+        assert_eq!(histogram.buckets().last(), Some((None, 1)));
+    }
+
+    #[test]
+    fn test_call_stats() {
+        let stats = CallStats::new();
+        let timeout = Error::Timeout;
+        for (outcome, latency) in &[
+            (CallOutcome::Success, 3),
+            (CallOutcome::Failed(&timeout), 5000),
+            (CallOutcome::Cancelled, 1),
+        ] {
+            stats.call_finished(&CallFinished {
+                uri: "golem.test",
+                request_id: 1,
+                latency: Duration::from_millis(*latency),
+                outcome: *outcome,
+                request_size: 10,
+                response_size: 20,
+            });
+        }
+
+        let procedure = &stats.snapshot()["golem.test"];
+        assert_eq!(procedure.calls, 3);
+        assert_eq!(procedure.errors, 1);
+        assert_eq!(procedure.timeouts, 1);
+        assert_eq!(procedure.cancelled, 1);
+        assert_eq!(procedure.request_bytes, 30);
+        assert_eq!(procedure.latency.max(), Duration::from_secs(5));
+        stats.reset();
+        assert!(stats.snapshot().is_empty());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_call_tracing() {
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        /// Keeps fields of all spans, in order of recording.
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<Vec<String>>>);
+
+        impl Visit for Spans {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                let field = format!("{}={:?}", field.name(), value);
+                self.0.lock().unwrap().push(field);
+            }
+        }
+
+        impl tracing::Subscriber for Spans {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                span.record(&mut self.clone());
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _span: &Id, values: &Record<'_>) {
+                values.record(&mut self.clone());
+            }
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, _event: &Event<'_>) {}
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let spans = Spans::default();
+        let observer = CallTracing::new();
+        let timeout = Error::Timeout;
+        tracing::subscriber::with_default(spans.clone(), || {
+            observer.call_started(&CallStarted {
+                uri: "golem.test",
+                request_id: 7,
+                request_size: 10,
+            });
+            observer.call_finished(&CallFinished {
+                uri: "golem.test",
+                request_id: 7,
+                latency: Duration::from_millis(12),
+                outcome: CallOutcome::Failed(&timeout),
+                request_size: 10,
+                response_size: 0,
+            });
+        });
+
+        assert_eq!(
+            *spans.0.lock().unwrap(),
+            vec![
+                "uri=\"golem.test\"",
+                "request_id=7",
+                "outcome=\"failed\"",
+                "latency_ms=12",
+            ]
+        );
+        assert!(observer.spans.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+//! Call and event arguments kept as msgpack values.
+
+use crate::args::RpcCallResponse;
+use crate::error::Error;
+use crate::messages::Dict;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+pub(crate) mod value;
+
+/// Positional and named arguments of call, result or event.
+///
+/// Unlike json based [`RpcCallResponse`], binary data and 64-bit integers are kept as sent
+/// by peer. Typed arguments are (de)serialized straight from msgpack values, with the
+/// same data layout as `serde_json` uses.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Payload {
+    pub args: Vec<rmpv::Value>,
+    pub kw_args: BTreeMap<String, rmpv::Value>,
+}
+
+impl Payload {
+    /// Creates payload from tuple or sequence of positional arguments.
+    pub fn with_args<T: Serialize + ?Sized>(args: &T) -> Result<Self, Error> {
+        let args = match value::to_value(args)? {
+            rmpv::Value::Array(args) => args,
+            rmpv::Value::Nil => Vec::new(),
+            _ => return Err(Error::protocol_err("arguments must be a sequence")),
+        };
+        Ok(Payload {
+            args,
+            kw_args: BTreeMap::new(),
+        })
+    }
+
+    /// Adds named arguments from struct or map.
+    pub fn with_kw_args<T: Serialize + ?Sized>(mut self, kw_args: &T) -> Result<Self, Error> {
+        let entries = match value::to_value(kw_args)? {
+            rmpv::Value::Map(entries) => entries,
+            _ => return Err(Error::protocol_err("named arguments must be a map")),
+        };
+        for (key, value) in entries {
+            match key {
+                rmpv::Value::String(key) => match key.into_str() {
+                    Some(key) => {
+                        self.kw_args.insert(key, value);
+                    }
+                    None => return Err(Error::protocol_err("invalid argument name")),
+                },
+                _ => return Err(Error::protocol_err("argument name must be a string")),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Deserializes positional arguments, e.g. as tuple.
+    pub fn parse_args<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(value::from_value(rmpv::Value::Array(self.args.clone()))?)
+    }
+
+    /// Deserializes leading positional arguments, ignoring trailing ones not consumed
+    /// by `T`, e.g. arguments appended by newer publisher.
+    pub fn parse_leading_args<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        Ok(value::from_leading_args(self.args.clone())?)
+    }
+
+    /// Deserializes single positional argument.
+    pub fn parse_arg<T: DeserializeOwned>(&self, index: usize) -> Result<T, Error> {
+        match self.args.get(index) {
+            Some(arg) => Ok(value::from_value(arg.clone())?),
+            None => Err(Error::protocol_err("missing argument")),
+        }
+    }
+
+    /// Deserializes named arguments, e.g. as struct.
+    pub fn parse_kw_args<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        let entries = self
+            .kw_args
+            .iter()
+            .map(|(key, value)| (key.as_str().into(), value.clone()))
+            .collect();
+        Ok(value::from_value(rmpv::Value::Map(entries))?)
+    }
+
+    pub(crate) fn from_message(args: Option<&rmpv::Value>, kw_args: Option<&rmpv::Value>) -> Self {
+        let args = args
+            .and_then(rmpv::Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let kw_args = kw_args
+            .and_then(rmpv::Value::as_map)
+            .into_iter()
+            .flatten()
+            .filter_map(|(key, value)| Some((key.as_str()?.to_string(), value.clone())))
+            .collect();
+        Payload { args, kw_args }
+    }
+
+    pub(crate) fn json_args(&self) -> Vec<serde_json::Value> {
+        self.args.iter().map(json_value).collect()
+    }
+
+    pub(crate) fn json_kw_args(&self) -> Option<Dict> {
+        if self.kw_args.is_empty() {
+            return None;
+        }
+        Some(
+            self.kw_args
+                .iter()
+                .map(|(key, value)| (key.clone(), json_value(value)))
+                .collect(),
+        )
+    }
+}
+
+/// Converts argument to json, `null` if it has no json form (e.g. map with non-string
+/// keys), so that positions of following arguments are kept.
+fn json_value(value: &rmpv::Value) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_else(|e| {
+        log::warn!(
+            "argument not representable as json, replaced with null: {}",
+            e
+        );
+        serde_json::Value::Null
+    })
+}
+
+/// Converts json argument to msgpack, `nil` if conversion fails.
+fn msgpack_value(value: &serde_json::Value) -> rmpv::Value {
+    value::to_value(value).unwrap_or_else(|e| {
+        log::warn!(
+            "argument not representable as msgpack, replaced with nil: {}",
+            e
+        );
+        rmpv::Value::Nil
+    })
+}
+
+impl From<Payload> for RpcCallResponse {
+    fn from(payload: Payload) -> Self {
+        RpcCallResponse {
+            args: payload.json_args(),
+            kw_args: payload.json_kw_args(),
+        }
+    }
+}
+
+impl From<RpcCallResponse> for Payload {
+    fn from(response: RpcCallResponse) -> Self {
+        let kw_args = response
+            .kw_args
+            .into_iter()
+            .flatten()
+            .map(|(key, value)| (key, msgpack_value(&value)))
+            .collect();
+        Payload {
+            args: response.args.iter().map(msgpack_value).collect(),
+            kw_args,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        let payload = Payload::with_args(&(u64::MAX, "x"))
+            .unwrap()
+            .with_kw_args(&json!({"n": -1}))
+            .unwrap();
+        assert_eq!(
+            payload.parse_args::<(u64, String)>().unwrap(),
+            (u64::MAX, "x".to_string())
+        );
+        assert_eq!(payload.parse_arg::<u64>(0).unwrap(), u64::MAX);
+        assert_eq!(
+            payload.parse_kw_args::<BTreeMap<String, i64>>().unwrap()["n"],
+            -1
+        );
+        assert!(payload.parse_arg::<u64>(2).is_err());
+
+        let response = RpcCallResponse::from(payload);
+        assert_eq!(response.args, vec![json!(u64::MAX), json!("x")]);
+        assert_eq!(response.kw_args.unwrap()["n"], json!(-1));
+    }
+
+    #[test]
+    fn test_json_keeps_positions() {
+        // Map with array keys has no json form.
+        let key = rmpv::Value::Array(vec![1.into()]);
+        let array_keys = rmpv::Value::Map(vec![(key, 2.into())]);
+        let mut payload = Payload {
+            args: vec![array_keys.clone(), "x".into()],
+            kw_args: BTreeMap::new(),
+        };
+        payload.kw_args.insert("a".into(), array_keys);
+        payload.kw_args.insert("b".into(), 1.into());
+
+        let response = RpcCallResponse::from(payload);
+        assert_eq!(response.args, vec![json!(null), json!("x")]);
+        assert_eq!(
+            response.kw_args.unwrap(),
+            json!({"a": null, "b": 1}).as_object().cloned().unwrap()
+        );
+    }
+}
@@ -0,0 +1,618 @@
+//! Serde bridge between Rust types and msgpack values.
+//!
+//! Data is laid out the same way as by `serde_json` (structs are maps, unit variants are
+//! strings, other variants are single entry maps), so peers see no difference between
+//! serializers, but bytes stay binary and integers keep full 64-bit range.
+//!
+//! Scalars are handled by `rmpv::ext`, only containers and enums are wrapped here, as
+//! `rmpv::ext::to_value` writes structs as arrays and variants by index, at every level.
+
+use rmpv::ext::Error as ValueError;
+use rmpv::Value;
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+fn error(msg: impl Into<String>) -> ValueError {
+    ValueError::Syntax(msg.into())
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, ValueError> {
+    value.serialize(ValueSerializer)
+}
+
+pub fn from_value<T: de::DeserializeOwned>(value: Value) -> Result<T, ValueError> {
+    T::deserialize(ValueDeserializer(value))
+}
+
+/// Deserializes sequence from leading `args`, ignoring those not consumed by `T`.
+pub fn from_leading_args<T: de::DeserializeOwned>(args: Vec<Value>) -> Result<T, ValueError> {
+    T::deserialize(LeadingArgs(args.into_iter()))
+}
+
+struct ValueSerializer;
+
+pub struct SerializeVec {
+    items: Vec<Value>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeVec {
+    fn end(self) -> Value {
+        wrap_variant(self.variant, Value::Array(self.items))
+    }
+}
+
+pub struct SerializeMap {
+    entries: Vec<(Value, Value)>,
+    next_key: Option<Value>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeMap {
+    fn end(self) -> Value {
+        wrap_variant(self.variant, Value::Map(self.entries))
+    }
+}
+
+fn wrap_variant(variant: Option<&'static str>, value: Value) -> Value {
+    match variant {
+        Some(variant) => Value::Map(vec![(variant.into(), value)]),
+        None => value,
+    }
+}
+
+macro_rules! serialize_scalars {
+    ($($method:ident: $ty:ty),*) => {
+        $(
+            fn $method(self, v: $ty) -> Result<Value, ValueError> {
+                rmpv::ext::to_value(v)
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = ValueError;
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVec;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    serialize_scalars! {
+        serialize_bool: bool,
+        serialize_i8: i8,
+        serialize_i16: i16,
+        serialize_i32: i32,
+        serialize_i64: i64,
+        serialize_u8: u8,
+        serialize_u16: u16,
+        serialize_u32: u32,
+        serialize_u64: u64,
+        serialize_f32: f32,
+        serialize_f64: f64,
+        serialize_char: char,
+        serialize_str: &str
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, ValueError> {
+        Ok(Value::Binary(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, ValueError> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, ValueError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, ValueError> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, ValueError> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, ValueError> {
+        Ok(variant.into())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, ValueError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, ValueError> {
+        Ok(wrap_variant(Some(variant), value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec, ValueError> {
+        Ok(SerializeVec {
+            items: Vec::with_capacity(len.unwrap_or_default()),
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec, ValueError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec, ValueError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec, ValueError> {
+        Ok(SerializeVec {
+            items: Vec::with_capacity(len),
+            variant: Some(variant),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap, ValueError> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len.unwrap_or_default()),
+            next_key: None,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, ValueError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeMap, ValueError> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len),
+            next_key: None,
+            variant: Some(variant),
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ValueError> {
+        self.items.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeVec::end(self))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ValueError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeVec::end(self))
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ValueError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeVec::end(self))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVec {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ValueError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeVec::end(self))
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), ValueError> {
+        self.next_key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), ValueError> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| error("map value without key"))?;
+        self.entries.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeMap::end(self))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ValueError> {
+        self.entries
+            .push((key.into(), value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeMap::end(self))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = Value;
+    type Error = ValueError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), ValueError> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Value, ValueError> {
+        Ok(SerializeMap::end(self))
+    }
+}
+
+struct ValueDeserializer(Value);
+
+impl<'de> IntoDeserializer<'de, ValueError> for ValueDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        match self.0 {
+            Value::Array(v) => visitor.visit_seq(de::value::SeqDeserializer::new(
+                v.into_iter().map(ValueDeserializer),
+            )),
+            Value::Map(v) => visitor.visit_map(de::value::MapDeserializer::new(
+                v.into_iter()
+                    .map(|(k, v)| (KeyDeserializer(k), ValueDeserializer(v))),
+            )),
+            value => de::Deserializer::deserialize_any(value, visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        match self.0 {
+            Value::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    /// Binary is accepted as sequence too, e.g. for `Vec<u8>`.
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        match self.0 {
+            Value::Binary(v) => visitor.visit_seq(de::value::SeqDeserializer::new(v.into_iter())),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        match self.0 {
+            Value::String(variant) => match variant.into_str() {
+                Some(variant) => visitor.visit_enum(variant.into_deserializer()),
+                None => Err(error("invalid utf-8 string")),
+            },
+            Value::Map(mut entries) if entries.len() == 1 => {
+                let (variant, value) = entries.pop().unwrap();
+                visitor.visit_enum(EnumDeserializer { variant, value })
+            }
+            _ => Err(error("expected string or map with single key for enum")),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+/// Sequence without check that all its elements are consumed.
+struct LeadingArgs(std::vec::IntoIter<Value>);
+
+impl<'de> de::SeqAccess<'de> for LeadingArgs {
+    type Error = ValueError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, ValueError> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(ValueDeserializer(value)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+impl<'de> de::Deserializer<'de> for LeadingArgs {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        visitor.visit_seq(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct
+        map struct enum identifier ignored_any
+    }
+}
+
+/// Map key, which json peers send as string even if it is a number.
+struct KeyDeserializer(Value);
+
+impl<'de> IntoDeserializer<'de, ValueError> for KeyDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+                match self.0.as_str() {
+                    Some(key) => visitor.$visit(
+                        key.parse().map_err(|_| error(format!("invalid key: {}", key)))?,
+                    ),
+                    None => ValueDeserializer(self.0).$method(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ValueError> {
+        ValueDeserializer(self.0).deserialize_any(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        ValueDeserializer(self.0).deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    deserialize_parsed_key! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i128 u128 f32 f64 char str string bytes byte_buf option unit unit_struct
+        seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct EnumDeserializer {
+    variant: Value,
+    value: Value,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+    type Error = ValueError;
+    type Variant = ValueDeserializer;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, ValueDeserializer), ValueError> {
+        let variant = seed.deserialize(ValueDeserializer(self.variant))?;
+        Ok((variant, ValueDeserializer(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for ValueDeserializer {
+    type Error = ValueError;
+
+    fn unit_variant(self) -> Result<(), ValueError> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, ValueError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ValueError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Kind {
+        Plain,
+        Sized(u32),
+        Point { x: i64, y: i64 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Item {
+        id: u64,
+        kinds: Vec<Kind>,
+        data: Vec<u8>,
+        label: Option<String>,
+    }
+
+    #[test]
+    fn test_json_compatible_layout() {
+        let item = Item {
+            id: u64::MAX,
+            kinds: vec![Kind::Plain, Kind::Sized(3), Kind::Point { x: -1, y: 2 }],
+            data: vec![1, 2],
+            label: None,
+        };
+        let value = to_value(&item).unwrap();
+        assert_eq!(
+            serde_json::to_value(&value).unwrap(),
+            serde_json::to_value(&item).unwrap()
+        );
+        assert_eq!(from_value::<Item>(value).unwrap(), item);
+    }
+
+    #[test]
+    fn test_binary() {
+        let value = Value::Array(vec![Value::Binary(vec![0, 255])]);
+        assert_eq!(from_value::<(Vec<u8>,)>(value).unwrap(), (vec![0, 255],));
+
+        let value = Value::Map(vec![("40102".into(), "open".into())]);
+        let ports: std::collections::BTreeMap<u16, String> = from_value(value).unwrap();
+        assert_eq!(ports[&40102], "open");
+
+        struct Bytes(Vec<u8>);
+        impl Serialize for Bytes {
+            fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.serialize_bytes(&self.0)
+            }
+        }
+        assert_eq!(to_value(&Bytes(vec![7])).unwrap(), Value::Binary(vec![7]));
+    }
+
+    #[test]
+    fn test_leading_args() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Event {
+            id: u64,
+            label: Option<String>,
+        }
+
+        let args = vec![Value::from(1), Value::from("a"), Value::from(true)];
+        assert_eq!(
+            from_leading_args::<Event>(args.clone()).unwrap(),
+            Event {
+                id: 1,
+                label: Some("a".into())
+            }
+        );
+        assert_eq!(from_leading_args::<(u64,)>(args).unwrap(), (1,));
+        assert!(from_leading_args::<Event>(vec![]).is_err());
+    }
+}
@@ -1,28 +1,161 @@
 use crate::error::Error;
-use crate::messages::{Dict, WampError};
+use crate::messages::Dict;
+use crate::payload::Payload;
+use crate::session_core::Notify;
 use actix::Message;
-use futures::channel::mpsc;
 use futures::prelude::*;
 use futures::task::{Context, Poll};
 use futures::StreamExt;
+use serde_derive::Serialize;
 use serde_json::Value;
 use std::borrow::Cow;
 use std::pin::Pin;
 
-#[derive(Debug)]
+mod queue;
+
+pub(crate) use queue::{event_queue, EventReceiver, EventSender};
+pub use queue::{BufferOptions, DroppedEvents, OverflowPolicy};
+
+#[derive(Debug, Clone)]
 pub struct WampMessage {
     pub args: Vec<Value>,
     pub kw_args: Option<Dict>,
+    pub details: EventDetails,
+    /// Arguments as sent by publisher, without conversion to json.
+    pub payload: Payload,
+}
+
+/// Details of EVENT message.
+#[derive(Debug, Clone, Default)]
+pub struct EventDetails {
+    /// Topic event was published to. For pattern-based subscriptions it is the actual
+    /// topic, not the pattern.
+    pub topic: String,
+    /// Publisher session id, when disclosed by broker.
+    pub publisher: Option<u64>,
+    pub publication_id: u64,
+    /// All details as sent by broker.
+    pub extra: Dict,
 }
 
 pub trait PubSubEndpoint {
     type Events: Stream<Item = Result<WampMessage, Error>>;
+    type Published: Future<Output = Result<Option<u64>, Error>> + 'static;
+
+    fn subscribe(&self, uri: &str) -> Self::Events {
+        self.subscribe_with(uri, SubscribeOptions::default())
+    }
+
+    /// Subscribes to topic or, depending on [`SubscribeOptions::match_policy`], to all topics
+    /// matching given pattern.
+    fn subscribe_with(&self, uri: &str, options: SubscribeOptions) -> Self::Events;
+
+    /// Publishes event to given topic.
+    ///
+    /// When publication is acknowledged (see [`PublishOptions::acknowledge`]) resolves to
+    /// publication id assigned by broker, otherwise resolves to `None` as soon as
+    /// message is sent.
+    fn publish(
+        &self,
+        topic: &str,
+        args: Vec<Value>,
+        kw_args: Option<Dict>,
+        options: PublishOptions,
+    ) -> Self::Published;
+}
+
+/// How subscription topic is matched against topic of published event.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchPolicy {
+    #[default]
+    Exact,
+    /// Topic starts with subscription uri, e.g. `evt.comp.` matches `evt.comp.started`.
+    Prefix,
+    /// Empty uri components match any component, e.g. `evt..started` matches
+    /// `evt.comp.started`.
+    Wildcard,
+}
+
+impl MatchPolicy {
+    fn is_exact(&self) -> bool {
+        *self == MatchPolicy::Exact
+    }
+}
+
+/// Options of SUBSCRIBE message.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct SubscribeOptions {
+    #[serde(rename = "match")]
+    #[serde(skip_serializing_if = "MatchPolicy::is_exact")]
+    pub match_policy: MatchPolicy,
+    /// Local buffer of events not yet consumed. Unbounded by default.
+    #[serde(skip)]
+    pub buffer: Option<BufferOptions>,
+}
+
+impl SubscribeOptions {
+    pub fn prefix() -> Self {
+        SubscribeOptions {
+            match_policy: MatchPolicy::Prefix,
+            ..SubscribeOptions::default()
+        }
+    }
+
+    pub fn wildcard() -> Self {
+        SubscribeOptions {
+            match_policy: MatchPolicy::Wildcard,
+            ..SubscribeOptions::default()
+        }
+    }
 
-    fn subscribe(&self, uri: &str) -> Self::Events;
+    pub fn with_buffer(mut self, buffer: BufferOptions) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+}
+
+/// Options of PUBLISH message.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct PublishOptions {
+    /// Request PUBLISHED (or ERROR) reply from broker.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub acknowledge: bool,
+    /// When set to `false` publisher will receive its own event (if subscribed).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_me: Option<bool>,
+    /// Session ids of subscribers allowed to receive event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eligible: Option<Vec<u64>>,
+    /// Session ids of subscribers excluded from receiving event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<u64>>,
+}
+
+impl PublishOptions {
+    pub fn acknowledged() -> Self {
+        PublishOptions {
+            acknowledge: true,
+            ..PublishOptions::default()
+        }
+    }
+}
+
+pub struct Publish {
+    pub topic: Cow<'static, str>,
+    pub args: Vec<Value>,
+    pub kw_args: Option<Dict>,
+    pub options: PublishOptions,
+}
+
+impl Message for Publish {
+    type Result = Result<Option<u64>, Error>;
 }
 
+/// Drops one consumer of subscription. UNSUBSCRIBE is sent when last one is gone.
 pub struct Unsubscribe {
     pub subscription_id: u64,
+    pub consumer_id: u64,
 }
 
 impl Message for Unsubscribe {
@@ -31,6 +164,7 @@ impl Message for Unsubscribe {
 
 pub struct Subscribe {
     pub topic: Cow<'static, str>,
+    pub options: SubscribeOptions,
 }
 
 impl Message for Subscribe {
@@ -39,14 +173,24 @@ impl Message for Subscribe {
 
 pub struct Subscription {
     pub(crate) subscription_id: u64,
-    pub(crate) stream: mpsc::UnboundedReceiver<Result<WampMessage, WampError>>,
-    pub(crate) connection: actix::Recipient<Unsubscribe>,
+    pub(crate) consumer_id: u64,
+    pub(crate) stream: EventReceiver,
+    pub(crate) connection: Notify<Unsubscribe>,
 }
 
 impl Drop for Subscription {
     fn drop(&mut self) {
-        let subscription_id = self.subscription_id;
-        let _ = self.connection.do_send(Unsubscribe { subscription_id });
+        (self.connection)(Unsubscribe {
+            subscription_id: self.subscription_id,
+            consumer_id: self.consumer_id,
+        });
+    }
+}
+
+impl Subscription {
+    /// Number of events dropped because buffer was full, see [`BufferOptions`].
+    pub fn dropped(&self) -> u64 {
+        self.stream.dropped()
     }
 }
 
@@ -54,8 +198,31 @@ impl Stream for Subscription {
     type Item = Result<WampMessage, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        self.stream
-            .poll_next_unpin(cx)
-            .map(|v| v.map(|v| v.map_err(Error::WampError)))
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subscribe_options() {
+        assert_eq!(
+            serde_json::to_string(&SubscribeOptions::default()).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            serde_json::to_string(&SubscribeOptions::prefix()).unwrap(),
+            r#"{"match":"prefix"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(
+                &SubscribeOptions::wildcard()
+                    .with_buffer(BufferOptions::new(10, OverflowPolicy::DropOldest))
+            )
+            .unwrap(),
+            r#"{"match":"wildcard"}"#
+        );
     }
 }
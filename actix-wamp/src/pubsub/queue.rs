@@ -0,0 +1,259 @@
+//! Per-consumer event buffer of subscription.
+
+use super::WampMessage;
+use crate::error::Error;
+use futures::prelude::*;
+use futures::task::{Context, Poll, Waker};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// What to do with event arriving to full subscription buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard oldest buffered event to make room for the new one.
+    DropOldest,
+    /// Discard arriving event.
+    DropNewest,
+    /// Deliver buffered events, then end stream with [`Error::BufferOverflow`].
+    Close,
+}
+
+/// Number of events discarded because consumer did not keep up.
+#[derive(Debug, Clone, Default)]
+pub struct DroppedEvents(Arc<AtomicU64>);
+
+impl DroppedEvents {
+    pub fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Limits number of events waiting to be consumed from subscription.
+#[derive(Debug, Clone)]
+pub struct BufferOptions {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    dropped: DroppedEvents,
+}
+
+impl BufferOptions {
+    /// Buffers at most `capacity` events, at least one.
+    pub fn new(capacity: usize, overflow: OverflowPolicy) -> Self {
+        BufferOptions {
+            capacity: capacity.max(1),
+            overflow,
+            dropped: DroppedEvents::default(),
+        }
+    }
+
+    /// Counter of events dropped from buffer, also available from subscription stream.
+    pub fn dropped(&self) -> DroppedEvents {
+        self.dropped.clone()
+    }
+}
+
+type Item = Result<WampMessage, Error>;
+
+struct State {
+    items: VecDeque<Item>,
+    /// No more items will be delivered after buffered ones.
+    closed: bool,
+    receiver_gone: bool,
+    waker: Option<Waker>,
+}
+
+struct Queue {
+    state: Mutex<State>,
+    senders: AtomicUsize,
+    buffer: Option<BufferOptions>,
+}
+
+impl Queue {
+    fn close(&self, state: &mut State) {
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Creates event queue, unbounded if `buffer` is `None`.
+pub(crate) fn event_queue(buffer: Option<BufferOptions>) -> (EventSender, EventReceiver) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            closed: false,
+            receiver_gone: false,
+            waker: None,
+        }),
+        senders: AtomicUsize::new(1),
+        buffer,
+    });
+    (EventSender(queue.clone()), EventReceiver(queue))
+}
+
+pub(crate) struct EventSender(Arc<Queue>);
+
+impl EventSender {
+    /// Queues event. Fails when consumer is gone or queue was closed on overflow.
+    ///
+    /// Errors are never dropped.
+    pub fn send(&self, item: Item) -> Result<(), ()> {
+        let queue = &self.0;
+        let mut state = queue.state.lock().unwrap();
+        if state.closed || state.receiver_gone {
+            return Err(());
+        }
+        match &queue.buffer {
+            Some(buffer) if item.is_ok() && state.items.len() >= buffer.capacity => {
+                buffer.dropped.inc();
+                match buffer.overflow {
+                    OverflowPolicy::DropOldest => {
+                        state.items.pop_front();
+                    }
+                    OverflowPolicy::DropNewest => return Ok(()),
+                    OverflowPolicy::Close => {
+                        state.items.push_back(Err(Error::BufferOverflow));
+                        queue.close(&mut state);
+                        return Err(());
+                    }
+                }
+            }
+            _ => (),
+        }
+        state.items.push_back(item);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let state = self.0.state.lock().unwrap();
+        state.closed || state.receiver_gone
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::Relaxed);
+        EventSender(self.0.clone())
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        if self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let mut state = self.0.state.lock().unwrap();
+            self.0.close(&mut state);
+        }
+    }
+}
+
+pub(crate) struct EventReceiver(Arc<Queue>);
+
+impl EventReceiver {
+    /// Number of events dropped from buffer, always 0 for unbounded queue.
+    pub fn dropped(&self) -> u64 {
+        self.0
+            .buffer
+            .as_ref()
+            .map_or(0, |buffer| buffer.dropped.count())
+    }
+}
+
+impl Stream for EventReceiver {
+    type Item = Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.receiver_gone = true;
+        state.items.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn event(n: u64) -> Item {
+        Ok(WampMessage {
+            args: vec![json!(n)],
+            kw_args: None,
+            details: Default::default(),
+            payload: Default::default(),
+        })
+    }
+
+    fn drain(rx: &mut EventReceiver) -> Vec<Option<u64>> {
+        let mut items = Vec::new();
+        while let Some(item) = rx.next().now_or_never() {
+            match item {
+                Some(Ok(msg)) => items.push(msg.args[0].as_u64()),
+                Some(Err(_)) => items.push(None),
+                None => break,
+            }
+        }
+        items
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let buffer = BufferOptions::new(2, OverflowPolicy::DropOldest);
+        let dropped = buffer.dropped();
+        let (tx, mut rx) = event_queue(Some(buffer));
+        for n in 0..4 {
+            tx.send(event(n)).unwrap();
+        }
+        assert_eq!(drain(&mut rx), vec![Some(2), Some(3)]);
+        assert_eq!(dropped.count(), 2);
+        assert_eq!(rx.dropped(), 2);
+
+        let buffer = BufferOptions::new(2, OverflowPolicy::DropNewest);
+        let (tx, mut rx) = event_queue(Some(buffer));
+        for n in 0..4 {
+            tx.send(event(n)).unwrap();
+        }
+        assert_eq!(drain(&mut rx), vec![Some(0), Some(1)]);
+        assert_eq!(rx.dropped(), 2);
+
+        // Zero capacity is raised to one, so events still get through.
+        let buffer = BufferOptions::new(0, OverflowPolicy::DropNewest);
+        let (tx, mut rx) = event_queue(Some(buffer));
+        tx.send(event(0)).unwrap();
+        tx.send(event(1)).unwrap();
+        assert_eq!(drain(&mut rx), vec![Some(0)]);
+        assert_eq!(rx.dropped(), 1);
+
+        let buffer = BufferOptions::new(2, OverflowPolicy::Close);
+        let (tx, mut rx) = event_queue(Some(buffer));
+        tx.send(event(0)).unwrap();
+        tx.send(event(1)).unwrap();
+        assert!(tx.send(event(2)).is_err());
+        assert!(tx.is_closed());
+        // Buffered events, overflow error, end of stream.
+        assert_eq!(drain(&mut rx), vec![Some(0), Some(1), None]);
+        assert!(rx.next().now_or_never().unwrap().is_none());
+    }
+}
@@ -0,0 +1,774 @@
+//! Session which reconnects to router after connection loss.
+
+use crate::args::{RpcCallRequest, RpcCallResponse, RpcEndpoint};
+use crate::error::Error;
+use crate::messages::Dict;
+use crate::payload::Payload;
+use crate::pubsub::{PubSubEndpoint, PublishOptions, SubscribeOptions, WampMessage};
+use crate::session::SessionEndpoint;
+use actix::prelude::*;
+use futures::channel::{mpsc, oneshot};
+use futures::future::LocalBoxFuture;
+use futures::prelude::*;
+use futures::task::{Context as TaskContext, Poll};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Delays between consecutive connection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+    /// Gives up after that many failed attempts in a row. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before next attempt after `failures` failed attempts.
+    fn delay(&self, failures: u32) -> Duration {
+        let mut delay = self.initial_delay;
+        for _ in 0..failures {
+            if delay >= self.max_delay {
+                break;
+            }
+            delay *= self.multiplier;
+        }
+        delay.min(self.max_delay)
+    }
+}
+
+/// What happens to calls interrupted by connection loss.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum CallPolicy {
+    /// Call fails with [`Error::ConnectionClosed`].
+    #[default]
+    Fail,
+    /// Call is sent again after session is re-established, at most given number of times.
+    ///
+    /// Use only with idempotent procedures.
+    Retry(u32),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectOptions {
+    pub backoff: Backoff,
+    pub call_policy: CallPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connecting {
+        attempt: u32,
+    },
+    Connected,
+    Disconnected,
+    /// Reconnecting has been given up after [`Backoff::max_attempts`] failures.
+    Failed,
+}
+
+type Connect<E> = Box<dyn Fn() -> LocalBoxFuture<'static, Result<E, Error>>>;
+
+type EventSender = crate::pubsub::EventSender;
+
+struct TopicSubscription {
+    topic: String,
+    options: SubscribeOptions,
+    tx: EventSender,
+    forwarder: Option<SpawnHandle>,
+}
+
+struct Supervisor<E> {
+    connect: Connect<E>,
+    backoff: Backoff,
+    endpoint: Option<E>,
+    /// Incremented on every connection, tells stale endpoints apart from current one.
+    generation: u64,
+    status: ConnectionStatus,
+    failures: u32,
+    waiting: Vec<oneshot::Sender<Result<(u64, E), Error>>>,
+    subscriptions: HashMap<u64, TopicSubscription>,
+    watchers: Vec<mpsc::UnboundedSender<ConnectionStatus>>,
+}
+
+impl<E> Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    fn set_status(&mut self, status: ConnectionStatus) {
+        log::debug!("connection status: {:?}", status);
+        self.watchers
+            .retain(|tx| tx.unbounded_send(status.clone()).is_ok());
+        self.status = status;
+    }
+
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        self.set_status(ConnectionStatus::Connecting {
+            attempt: self.failures + 1,
+        });
+        ctx.spawn(
+            (self.connect)()
+                .into_actor(self)
+                .map(|result, act, ctx| match result {
+                    Ok(endpoint) => act.connected(endpoint, ctx),
+                    Err(e) => {
+                        log::warn!("unable to connect: {}", e);
+                        act.failures += 1;
+                        act.reconnect_later(ctx)
+                    }
+                }),
+        );
+    }
+
+    fn reconnect_later(&mut self, ctx: &mut Context<Self>) {
+        if let Some(max_attempts) = self.backoff.max_attempts {
+            if self.failures >= max_attempts {
+                self.set_status(ConnectionStatus::Failed);
+                for tx in self.waiting.drain(..) {
+                    let _ = tx.send(Err(Error::ConnectionClosed));
+                }
+                ctx.stop();
+                return;
+            }
+        }
+        let delay = self.backoff.delay(self.failures);
+        log::debug!("reconnecting in {:?}", delay);
+        ctx.run_later(delay, |act, ctx| act.connect(ctx));
+    }
+
+    fn connected(&mut self, endpoint: E, ctx: &mut Context<Self>) {
+        self.failures = 0;
+        ctx.spawn(
+            endpoint
+                .closed()
+                .into_actor(self)
+                .map(|(), act, ctx| act.disconnected(ctx)),
+        );
+
+        let subscription_ids: Vec<u64> = self.subscriptions.keys().cloned().collect();
+        for subscription_id in subscription_ids {
+            self.subscribe(subscription_id, &endpoint, ctx);
+        }
+        self.generation += 1;
+        for tx in self.waiting.drain(..) {
+            let _ = tx.send(Ok((self.generation, endpoint.clone())));
+        }
+        self.endpoint = Some(endpoint);
+        self.set_status(ConnectionStatus::Connected);
+    }
+
+    fn disconnected(&mut self, ctx: &mut Context<Self>) {
+        self.endpoint = None;
+        for subscription in self.subscriptions.values_mut() {
+            if let Some(forwarder) = subscription.forwarder.take() {
+                ctx.cancel_future(forwarder);
+            }
+        }
+        self.set_status(ConnectionStatus::Disconnected);
+        self.reconnect_later(ctx);
+    }
+
+    /// Subscribes topic on current session and forwards its events to consumer.
+    fn subscribe(&mut self, subscription_id: u64, endpoint: &E, ctx: &mut Context<Self>) {
+        let (topic, options, tx) = match self.subscriptions.get(&subscription_id) {
+            Some(subscription) if !subscription.tx.is_closed() => (
+                subscription.topic.clone(),
+                subscription.options.clone(),
+                subscription.tx.clone(),
+            ),
+            _ => {
+                self.subscriptions.remove(&subscription_id);
+                return;
+            }
+        };
+        let mut events = Box::pin(endpoint.subscribe_with(&topic, options));
+        let endpoint = endpoint.clone();
+        let forwarder = ctx.spawn(
+            async move {
+                while let Some(event) = events.next().await {
+                    match event {
+                        Ok(event) => {
+                            if tx.send(Ok(event)).is_err() {
+                                break;
+                            }
+                        }
+                        // Session ended, topic is subscribed again on next one.
+                        Err(e) if session_ended(&endpoint, &e).await => {
+                            log::debug!("subscription to {} interrupted: {}", topic, e);
+                            break;
+                        }
+                        Err(e) => {
+                            log::warn!("subscription to {} failed: {}", topic, e);
+                            let _ = tx.send(Err(e));
+                            return true;
+                        }
+                    }
+                }
+                false
+            }
+            .into_actor(self)
+            .map(move |failed, act, _ctx| {
+                if failed {
+                    act.subscriptions.remove(&subscription_id);
+                }
+            }),
+        );
+        if let Some(subscription) = self.subscriptions.get_mut(&subscription_id) {
+            subscription.forwarder = Some(forwarder);
+        }
+    }
+}
+
+/// Tells subscription interrupted by end of session from one rejected by broker
+/// or closed on buffer overflow, which would fail again on next session.
+async fn session_ended<E: SessionEndpoint>(endpoint: &E, error: &Error) -> bool {
+    match error {
+        Error::ConnectionClosed | Error::HeartbeatTimeout | Error::MailboxError(_) => true,
+        Error::BufferOverflow => false,
+        // GOODBYE and ABORT fail subscriptions with error of closed session.
+        _ => endpoint.session_info().await.is_err(),
+    }
+}
+
+impl<E> Actor for Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        log::debug!("session supervisor stopped");
+    }
+}
+
+struct GetEndpoint<E>(PhantomData<E>);
+
+impl<E: 'static> Message for GetEndpoint<E> {
+    type Result = Result<(u64, E), Error>;
+}
+
+impl<E> Handler<GetEndpoint<E>> for Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Result = ActorResponse<Self, (u64, E), Error>;
+
+    fn handle(&mut self, _msg: GetEndpoint<E>, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(endpoint) = &self.endpoint {
+            return ActorResponse::reply(Ok((self.generation, endpoint.clone())));
+        }
+        let (tx, rx) = oneshot::channel();
+        self.waiting.push(tx);
+        ActorResponse::r#async(
+            async move { rx.await.map_err(|_| Error::ConnectionClosed)? }.into_actor(self),
+        )
+    }
+}
+
+/// Call on endpoint of given generation failed with connection loss. Endpoint is
+/// forgotten right away, so retries wait for next session instead of reusing it
+/// until supervisor notices it is closed.
+struct EndpointLost {
+    generation: u64,
+}
+
+impl Message for EndpointLost {
+    type Result = ();
+}
+
+impl<E> Handler<EndpointLost> for Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: EndpointLost, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.generation == self.generation {
+            self.endpoint = None;
+        }
+    }
+}
+
+struct SubscribeTopic {
+    subscription_id: u64,
+    topic: String,
+    options: SubscribeOptions,
+    tx: EventSender,
+}
+
+impl Message for SubscribeTopic {
+    type Result = ();
+}
+
+impl<E> Handler<SubscribeTopic> for Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeTopic, ctx: &mut Self::Context) -> Self::Result {
+        self.subscriptions.insert(
+            msg.subscription_id,
+            TopicSubscription {
+                topic: msg.topic,
+                options: msg.options,
+                tx: msg.tx,
+                forwarder: None,
+            },
+        );
+        if let Some(endpoint) = self.endpoint.clone() {
+            self.subscribe(msg.subscription_id, &endpoint, ctx);
+        }
+    }
+}
+
+struct UnsubscribeTopic {
+    subscription_id: u64,
+}
+
+impl Message for UnsubscribeTopic {
+    type Result = ();
+}
+
+impl<E> Handler<UnsubscribeTopic> for Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: UnsubscribeTopic, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(subscription) = self.subscriptions.remove(&msg.subscription_id) {
+            if let Some(forwarder) = subscription.forwarder {
+                ctx.cancel_future(forwarder);
+            }
+        }
+    }
+}
+
+struct WatchStatus;
+
+impl Message for WatchStatus {
+    type Result = mpsc::UnboundedReceiver<ConnectionStatus>;
+}
+
+impl<E> Handler<WatchStatus> for Supervisor<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Result = MessageResult<WatchStatus>;
+
+    fn handle(&mut self, _msg: WatchStatus, _ctx: &mut Self::Context) -> Self::Result {
+        let (tx, rx) = mpsc::unbounded();
+        let _ = tx.unbounded_send(self.status.clone());
+        self.watchers.push(tx);
+        MessageResult(rx)
+    }
+}
+
+/// Session re-established with configurable backoff after connection loss.
+///
+/// Live subscriptions are transparently subscribed again on new session, while one rejected
+/// by broker ends with its error. Calls interrupted by connection loss are failed or retried
+/// according to [`CallPolicy`], calls made while disconnected wait for next session.
+pub struct ReconnectingSession<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    supervisor: Addr<Supervisor<E>>,
+    call_policy: CallPolicy,
+}
+
+impl<E> Clone for ReconnectingSession<E>
+where
+    E: PubSubEndpoint + SessionEndpoint + Clone + Unpin + 'static,
+    E::Events: 'static,
+{
+    fn clone(&self) -> Self {
+        ReconnectingSession {
+            supervisor: self.supervisor.clone(),
+            call_policy: self.call_policy,
+        }
+    }
+}
+
+impl<E> ReconnectingSession<E>
+where
+    E: RpcEndpoint + PubSubEndpoint + SessionEndpoint + Clone + Send + Unpin + 'static,
+    E::Events: 'static,
+{
+    /// Starts supervisor, which opens sessions with `connect` (for example
+    /// `SessionBuilder::create_wss`).
+    ///
+    /// Must be called within running actix system.
+    pub fn new<F, Fut>(connect: F, options: ReconnectOptions) -> Self
+    where
+        F: Fn() -> Fut + 'static,
+        Fut: Future<Output = Result<E, Error>> + 'static,
+    {
+        let supervisor = Supervisor {
+            connect: Box::new(move || connect().boxed_local()),
+            backoff: options.backoff,
+            endpoint: None,
+            generation: 0,
+            status: ConnectionStatus::Disconnected,
+            failures: 0,
+            waiting: Vec::new(),
+            subscriptions: HashMap::new(),
+            watchers: Vec::new(),
+        }
+        .start();
+
+        ReconnectingSession {
+            supervisor,
+            call_policy: options.call_policy,
+        }
+    }
+
+    /// Stream of connection status changes, starting with current status.
+    pub fn status_changes(&self) -> impl Stream<Item = ConnectionStatus> + 'static {
+        self.supervisor
+            .send(WatchStatus)
+            .map(|rx| rx.ok())
+            .into_stream()
+            .filter_map(future::ready)
+            .flatten()
+    }
+
+    fn endpoint(&self) -> impl Future<Output = Result<E, Error>> + 'static {
+        self.current_endpoint().map_ok(|(_, endpoint)| endpoint)
+    }
+
+    /// Endpoint of current session with its generation.
+    fn current_endpoint(&self) -> impl Future<Output = Result<(u64, E), Error>> + 'static {
+        self.supervisor
+            .send(GetEndpoint(PhantomData))
+            .then(|resp| match resp {
+                Err(e) => future::err(Error::MailboxError(e)),
+                Ok(v) => future::ready(v),
+            })
+    }
+}
+
+impl<E> RpcEndpoint for ReconnectingSession<E>
+where
+    E: RpcEndpoint + PubSubEndpoint + SessionEndpoint + Clone + Send + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Response = LocalBoxFuture<'static, Result<RpcCallResponse, Error>>;
+
+    fn rpc_call(&self, request: RpcCallRequest) -> Self::Response {
+        self.rpc_call_payload(request)
+            .map_ok(RpcCallResponse::from)
+            .boxed_local()
+    }
+
+    fn rpc_call_payload(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<Payload, Error>> + 'static>> {
+        let session = self.clone();
+        let mut retries = match self.call_policy {
+            CallPolicy::Fail => 0,
+            CallPolicy::Retry(retries) => retries,
+        };
+
+        async move {
+            loop {
+                let (generation, endpoint) = session.current_endpoint().await?;
+                match endpoint.rpc_call_payload(request.clone()).await {
+                    Err(Error::ConnectionClosed)
+                    | Err(Error::HeartbeatTimeout)
+                    | Err(Error::MailboxError(_))
+                        if retries > 0 =>
+                    {
+                        log::debug!("retrying {} after connection loss", request.uri);
+                        session.supervisor.do_send(EndpointLost { generation });
+                        retries -= 1;
+                    }
+                    result => return result,
+                }
+            }
+        }
+        .boxed_local()
+    }
+
+    fn rpc_call_progressive(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Stream<Item = Result<RpcCallResponse, Error>> + 'static>> {
+        self.endpoint()
+            .map_ok(move |endpoint| endpoint.rpc_call_progressive(request))
+            .try_flatten_stream()
+            .boxed_local()
+    }
+}
+
+static NEXT_SUBSCRIPTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Events of topic subscribed with [`ReconnectingSession`].
+///
+/// Subscription is kept across reconnects until this stream is dropped.
+pub struct TopicEvents {
+    subscription_id: u64,
+    rx: crate::pubsub::EventReceiver,
+    supervisor: Recipient<UnsubscribeTopic>,
+}
+
+impl TopicEvents {
+    /// Number of events dropped because subscription buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.rx.dropped()
+    }
+}
+
+impl Stream for TopicEvents {
+    type Item = Result<WampMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for TopicEvents {
+    fn drop(&mut self) {
+        let subscription_id = self.subscription_id;
+        let _ = self
+            .supervisor
+            .do_send(UnsubscribeTopic { subscription_id });
+    }
+}
+
+impl<E> PubSubEndpoint for ReconnectingSession<E>
+where
+    E: RpcEndpoint + PubSubEndpoint + SessionEndpoint + Clone + Send + Unpin + 'static,
+    E::Events: 'static,
+{
+    type Events = TopicEvents;
+    type Published = LocalBoxFuture<'static, Result<Option<u64>, Error>>;
+
+    fn subscribe_with(&self, uri: &str, mut options: SubscribeOptions) -> Self::Events {
+        let subscription_id = NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::Relaxed);
+        // Events are buffered here, session subscriptions only feed the forwarder.
+        let (tx, rx) = crate::pubsub::event_queue(options.buffer.take());
+
+        self.supervisor.do_send(SubscribeTopic {
+            subscription_id,
+            topic: uri.to_string(),
+            options,
+            tx,
+        });
+
+        TopicEvents {
+            subscription_id,
+            rx,
+            supervisor: self.supervisor.clone().recipient(),
+        }
+    }
+
+    fn publish(
+        &self,
+        topic: &str,
+        args: Vec<Value>,
+        kw_args: Option<Dict>,
+        options: PublishOptions,
+    ) -> Self::Published {
+        let topic = topic.to_string();
+
+        self.endpoint()
+            .and_then(move |endpoint| endpoint.publish(&topic, args, kw_args, options))
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::session::{SessionEvent, SessionInfo};
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    /// Endpoint of `generation`-th session. First one loses connection once used,
+    /// but resolves [`SessionEndpoint::closed`] only a while later, like real session
+    /// noticing closed transport.
+    #[derive(Clone)]
+    struct MockEndpoint {
+        generation: u64,
+        lost: Arc<AtomicBool>,
+    }
+
+    impl MockEndpoint {
+        fn is_stale(&self) -> bool {
+            self.generation == 1
+        }
+
+        fn event(arg: &str) -> Result<WampMessage, Error> {
+            Ok(WampMessage {
+                args: vec![json!(arg)],
+                kw_args: None,
+                details: Default::default(),
+                payload: Default::default(),
+            })
+        }
+    }
+
+    impl RpcEndpoint for MockEndpoint {
+        type Response = future::Ready<Result<RpcCallResponse, Error>>;
+
+        fn rpc_call(&self, _request: RpcCallRequest) -> Self::Response {
+            if self.is_stale() {
+                self.lost.store(true, Ordering::SeqCst);
+                return future::err(Error::ConnectionClosed);
+            }
+            future::ok(RpcCallResponse {
+                args: vec![json!(self.generation)],
+                kw_args: None,
+            })
+        }
+    }
+
+    impl PubSubEndpoint for MockEndpoint {
+        type Events = stream::BoxStream<'static, Result<WampMessage, Error>>;
+        type Published = future::Ready<Result<Option<u64>, Error>>;
+
+        fn subscribe_with(&self, _uri: &str, _options: SubscribeOptions) -> Self::Events {
+            if self.is_stale() {
+                self.lost.store(true, Ordering::SeqCst);
+                // Session core fails its subscriptions as soon as it ends.
+                return stream::iter(vec![Self::event("first"), Err(Error::ConnectionClosed)])
+                    .boxed();
+            }
+            stream::iter(vec![Self::event("second")])
+                .chain(stream::pending())
+                .boxed()
+        }
+
+        fn publish(
+            &self,
+            _topic: &str,
+            _args: Vec<Value>,
+            _kw_args: Option<Dict>,
+            _options: PublishOptions,
+        ) -> Self::Published {
+            future::ok(None)
+        }
+    }
+
+    impl SessionEndpoint for MockEndpoint {
+        type Closing = future::Ready<Result<(), Error>>;
+        type Closed = LocalBoxFuture<'static, ()>;
+        type SessionEvents = stream::Empty<SessionEvent>;
+        type SessionInfo = future::Ready<Result<SessionInfo, Error>>;
+
+        fn close(&self, _reason: &str) -> Self::Closing {
+            future::ok(())
+        }
+
+        fn closed(&self) -> Self::Closed {
+            let lost = self.lost.clone();
+            async move {
+                while !lost.load(Ordering::SeqCst) {
+                    tokio::time::delay_for(Duration::from_millis(5)).await;
+                }
+                tokio::time::delay_for(Duration::from_millis(100)).await;
+            }
+            .boxed_local()
+        }
+
+        fn session_events(&self) -> Self::SessionEvents {
+            stream::empty()
+        }
+
+        fn session_info(&self) -> Self::SessionInfo {
+            future::err(Error::InvalidState("mock session"))
+        }
+    }
+
+    fn mock_session(call_policy: CallPolicy) -> ReconnectingSession<MockEndpoint> {
+        let connections = Cell::new(0);
+        ReconnectingSession::new(
+            move || {
+                connections.set(connections.get() + 1);
+                future::ok(MockEndpoint {
+                    generation: connections.get(),
+                    lost: Default::default(),
+                })
+            },
+            ReconnectOptions {
+                backoff: Backoff {
+                    initial_delay: Duration::from_millis(10),
+                    ..Backoff::default()
+                },
+                call_policy,
+            },
+        )
+    }
+
+    #[test]
+    fn test_retry_on_next_session() {
+        System::new("test").block_on(async {
+            let session = mock_session(CallPolicy::Retry(2));
+            let response = session
+                .rpc_call(RpcCallRequest::with_no_args("test.call"))
+                .await
+                .unwrap();
+            assert_eq!(response.args, vec![json!(2)]);
+        });
+    }
+
+    #[test]
+    fn test_subscription_error_not_forwarded() {
+        System::new("test").block_on(async {
+            let session = mock_session(CallPolicy::Fail);
+            let mut events = session.subscribe("evt.a");
+            let event = events.try_next().await.unwrap().unwrap();
+            assert_eq!(event.args, vec![json!("first")]);
+            let event = events.try_next().await.unwrap().unwrap();
+            assert_eq!(event.args, vec![json!("second")]);
+        });
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(3),
+            multiplier: 2,
+            max_attempts: None,
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(500));
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(3), Duration::from_secs(3));
+        assert_eq!(backoff.delay(100), Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::error::Error;
+use crate::messages::{Dict, WampError};
+use crate::payload::Payload;
+use crate::session_core::Notify;
+use actix::Message;
+use futures::prelude::*;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::pin::Pin;
+
+/// Single call of registered procedure, as received from dealer.
+#[derive(Debug)]
+pub struct Invocation {
+    pub args: Vec<Value>,
+    pub kw_args: Option<Dict>,
+    pub details: Dict,
+    /// Arguments as sent by caller, without conversion to json.
+    pub payload: Payload,
+}
+
+pub(crate) type InvocationReply = Pin<Box<dyn Future<Output = Result<Payload, WampError>>>>;
+
+pub(crate) type InvocationHandler = Box<dyn Fn(Invocation) -> InvocationReply + Send>;
+
+/// Callee role. Allows to expose procedures on router.
+pub trait RpcRegistry {
+    type Registration: Future<Output = Result<Registration, Error>> + 'static;
+
+    /// Registers `handler` under given procedure `uri`.
+    ///
+    /// Procedure stays registered as long as returned [`Registration`] is alive. Handler
+    /// replies with [`Payload`], to keep binary data and 64-bit integers as they are, or with
+    /// json based [`RpcCallResponse`](crate::RpcCallResponse).
+    fn register<Handler, Reply, Response>(&self, uri: &str, handler: Handler) -> Self::Registration
+    where
+        Handler: Fn(Invocation) -> Reply + Send + 'static,
+        Reply: Future<Output = Result<Response, WampError>> + 'static,
+        Response: Into<Payload> + 'static;
+}
+
+pub struct Register {
+    pub uri: Cow<'static, str>,
+    pub(crate) handler: InvocationHandler,
+}
+
+impl Message for Register {
+    type Result = Result<Registration, Error>;
+}
+
+pub struct Unregister {
+    pub registration_id: u64,
+}
+
+impl Message for Unregister {
+    type Result = ();
+}
+
+/// Active procedure registration.
+///
+/// Procedure is unregistered when this handle is dropped.
+pub struct Registration {
+    pub(crate) registration_id: u64,
+    pub(crate) connection: Notify<Unregister>,
+}
+
+impl Registration {
+    #[inline]
+    pub fn registration_id(&self) -> u64 {
+        self.registration_id
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let registration_id = self.registration_id;
+        (self.connection)(Unregister { registration_id });
+    }
+}
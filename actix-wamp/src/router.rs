@@ -0,0 +1,884 @@
+//! In-process WAMP router for tests.
+//!
+//! Implements dealer and broker roles with anonymous, WAMP-CRA and ticket authentication, enough to
+//! exercise client code end-to-end without crossbar. Sessions connect over
+//! [`MemoryTransport`], which can be passed to [`SessionBuilder::create`](crate::SessionBuilder::create).
+//!
+//! Available with `test-router` feature.
+
+use crate::messages::types::*;
+use crate::serializer::{self, Serializer};
+use crate::session::CLOSE_GOODBYE_AND_OUT;
+use actix::prelude::*;
+use actix_http::ws;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use futures::task::{Context as TaskContext, Poll};
+use hmac::Mac;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+
+const NO_SUCH_REALM: &str = "wamp.error.no_such_realm";
+const NOT_AUTHORIZED: &str = "wamp.error.not_authorized";
+const PROTOCOL_VIOLATION: &str = "wamp.error.protocol_violation";
+const NO_SUCH_PROCEDURE: &str = "wamp.error.no_such_procedure";
+const PROCEDURE_ALREADY_EXISTS: &str = "wamp.error.procedure_already_exists";
+const NO_SUCH_REGISTRATION: &str = "wamp.error.no_such_registration";
+const NO_SUCH_SUBSCRIPTION: &str = "wamp.error.no_such_subscription";
+const CANCELED: &str = "wamp.error.canceled";
+
+const CRA_ITERATIONS: usize = 100;
+const CRA_KEYLEN: usize = 32;
+
+/// Client end of in-memory connection to [`Router`].
+pub struct MemoryTransport {
+    tx: mpsc::UnboundedSender<ws::Message>,
+    rx: mpsc::UnboundedReceiver<Result<ws::Frame, ws::ProtocolError>>,
+}
+
+impl Sink<ws::Message> for MemoryTransport {
+    type Error = ws::ProtocolError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ws::Message) -> Result<(), Self::Error> {
+        // Router going away is noticed on the receiving side.
+        let _ = self.tx.unbounded_send(item);
+        Ok(())
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        _cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.tx.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for MemoryTransport {
+    type Item = Result<ws::Frame, ws::ProtocolError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx)
+    }
+}
+
+/// How user proves its identity.
+enum Credentials {
+    /// WAMP-CRA, with key derived from secret when salted.
+    Cra {
+        secret: Vec<u8>,
+        salt: Option<String>,
+    },
+    Ticket(String),
+}
+
+impl Credentials {
+    fn auth_method(&self) -> &'static str {
+        match self {
+            Credentials::Cra { .. } => "wampcra",
+            Credentials::Ticket(_) => "ticket",
+        }
+    }
+}
+
+struct User {
+    role: String,
+    credentials: Credentials,
+}
+
+/// Configures and starts [`Router`].
+pub struct RouterBuilder {
+    realm: String,
+    users: HashMap<String, User>,
+    allow_anonymous: bool,
+    denied_topics: HashSet<String>,
+}
+
+impl RouterBuilder {
+    /// Router serving single `realm`, open to anonymous sessions.
+    pub fn new(realm: impl Into<String>) -> Self {
+        RouterBuilder {
+            realm: realm.into(),
+            users: HashMap::new(),
+            allow_anonymous: true,
+            denied_topics: HashSet::new(),
+        }
+    }
+
+    fn with_user(
+        mut self,
+        auth_id: impl Into<String>,
+        auth_role: impl Into<String>,
+        credentials: Credentials,
+    ) -> Self {
+        self.users.insert(
+            auth_id.into(),
+            User {
+                role: auth_role.into(),
+                credentials,
+            },
+        );
+        self
+    }
+
+    /// Adds user authenticated with WAMP-CRA.
+    pub fn with_cra_user(
+        self,
+        auth_id: impl Into<String>,
+        auth_role: impl Into<String>,
+        secret: impl AsRef<[u8]>,
+    ) -> Self {
+        let credentials = Credentials::Cra {
+            secret: secret.as_ref().to_vec(),
+            salt: None,
+        };
+        self.with_user(auth_id, auth_role, credentials)
+    }
+
+    /// Adds user authenticated with salted WAMP-CRA, signing with key derived from
+    /// `secret` and `salt` by PBKDF2.
+    pub fn with_salted_cra_user(
+        self,
+        auth_id: impl Into<String>,
+        auth_role: impl Into<String>,
+        secret: impl AsRef<[u8]>,
+        salt: impl Into<String>,
+    ) -> Self {
+        let credentials = Credentials::Cra {
+            secret: secret.as_ref().to_vec(),
+            salt: Some(salt.into()),
+        };
+        self.with_user(auth_id, auth_role, credentials)
+    }
+
+    /// Adds user authenticated with ticket.
+    pub fn with_ticket_user(
+        self,
+        auth_id: impl Into<String>,
+        auth_role: impl Into<String>,
+        ticket: impl Into<String>,
+    ) -> Self {
+        self.with_user(auth_id, auth_role, Credentials::Ticket(ticket.into()))
+    }
+
+    /// Rejects sessions which do not authenticate.
+    pub fn deny_anonymous(mut self) -> Self {
+        self.allow_anonymous = false;
+        self
+    }
+
+    /// Rejects subscriptions to `topic` with `wamp.error.not_authorized`.
+    pub fn deny_subscribe(mut self, topic: impl Into<String>) -> Self {
+        self.denied_topics.insert(topic.into());
+        self
+    }
+
+    /// Starts router on current actix system.
+    pub fn start(self) -> Router {
+        let addr = RouterActor {
+            realm: self.realm,
+            users: self.users,
+            allow_anonymous: self.allow_anonymous,
+            denied_topics: self.denied_topics,
+            next_id: 1,
+            peers: HashMap::new(),
+            subscriptions: HashMap::new(),
+            registrations: HashMap::new(),
+            invocations: HashMap::new(),
+        }
+        .start();
+        Router { addr }
+    }
+}
+
+/// Handle to running in-memory router.
+#[derive(Clone)]
+pub struct Router {
+    addr: Addr<RouterActor>,
+}
+
+impl Router {
+    /// Opens new connection to router.
+    pub fn connect(&self) -> MemoryTransport {
+        let (client_tx, router_rx) = mpsc::unbounded();
+        let (router_tx, client_rx) = mpsc::unbounded();
+        self.addr.do_send(Accept {
+            tx: router_tx,
+            rx: router_rx,
+        });
+        MemoryTransport {
+            tx: client_tx,
+            rx: client_rx,
+        }
+    }
+
+    /// Closes all sessions with GOODBYE, e.g. `wamp.close.system_shutdown`.
+    pub fn shutdown(&self, reason: &str) {
+        self.addr.do_send(Shutdown {
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Drops all connections without closing sessions, as if network went down.
+    pub fn disconnect_all(&self) {
+        self.addr.do_send(DisconnectAll);
+    }
+}
+
+enum PeerState {
+    Connected,
+    Challenged { auth_id: String, signature: String },
+    Established,
+    ShuttingDown,
+}
+
+struct Peer {
+    tx: mpsc::UnboundedSender<Result<ws::Frame, ws::ProtocolError>>,
+    serializer: Serializer,
+    state: PeerState,
+}
+
+struct Topic {
+    uri: String,
+    policy: String,
+    subscribers: HashSet<u64>,
+}
+
+impl Topic {
+    fn matches(&self, topic: &str) -> bool {
+        match self.policy.as_str() {
+            "prefix" => topic.starts_with(&self.uri),
+            "wildcard" => {
+                let pattern: Vec<&str> = self.uri.split('.').collect();
+                let parts: Vec<&str> = topic.split('.').collect();
+                pattern.len() == parts.len()
+                    && pattern
+                        .iter()
+                        .zip(parts)
+                        .all(|(p, part)| p.is_empty() || *p == part)
+            }
+            _ => topic == self.uri,
+        }
+    }
+}
+
+struct Procedure {
+    uri: String,
+    callee: u64,
+}
+
+struct PendingInvocation {
+    caller: u64,
+    request_id: u64,
+    callee: u64,
+}
+
+struct RouterActor {
+    realm: String,
+    users: HashMap<String, User>,
+    allow_anonymous: bool,
+    denied_topics: HashSet<String>,
+    next_id: u64,
+    /// Connections by id. Id of connection doubles as its session id.
+    peers: HashMap<u64, Peer>,
+    subscriptions: HashMap<u64, Topic>,
+    registrations: HashMap<u64, Procedure>,
+    invocations: HashMap<u64, PendingInvocation>,
+}
+
+fn to_frame(message: ws::Message) -> ws::Frame {
+    match message {
+        ws::Message::Text(text) => ws::Frame::Text(text.into()),
+        ws::Message::Binary(bytes) => ws::Frame::Binary(bytes),
+        _ => unreachable!(),
+    }
+}
+
+/// Salted WAMP-CRA key, base64 encoded PBKDF2 of secret.
+fn derive_cra_key(secret: &[u8], salt: &str) -> Vec<u8> {
+    let mut key = vec![0u8; CRA_KEYLEN];
+    openssl::pkcs5::pbkdf2_hmac(
+        secret,
+        salt.as_bytes(),
+        CRA_ITERATIONS,
+        openssl::hash::MessageDigest::sha256(),
+        &mut key,
+    )
+    .unwrap();
+    base64::encode(&key).into_bytes()
+}
+
+/// Appends arguments of one message to another, e.g. from YIELD to RESULT.
+fn with_payload(mut msg: Vec<Value>, from: &[Value], start: usize) -> Value {
+    msg.extend(from.iter().skip(start).take(2).cloned());
+    Value::Array(msg)
+}
+
+impl RouterActor {
+    fn gen_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn send(&self, peer_id: u64, msg: Value) {
+        if let Some(peer) = self.peers.get(&peer_id) {
+            match peer.serializer.encode_json(&msg) {
+                Ok(message) => {
+                    let _ = peer.tx.unbounded_send(Ok(to_frame(message)));
+                }
+                Err(e) => log::error!("router failed to encode {}: {}", msg, e),
+            }
+        }
+    }
+
+    fn is_established(&self, peer_id: u64) -> bool {
+        matches!(
+            self.peers.get(&peer_id),
+            Some(Peer {
+                state: PeerState::Established,
+                ..
+            })
+        )
+    }
+
+    fn abort(&mut self, peer_id: u64, reason: &str, message: &str) {
+        self.send(peer_id, json!([ABORT, {"message": message}, reason]));
+        self.end_session(peer_id);
+        self.peers.remove(&peer_id);
+    }
+
+    fn error(&self, peer_id: u64, request_type: u8, request_id: u64, error: &str) {
+        self.send(peer_id, json!([ERROR, request_type, request_id, {}, error]));
+    }
+
+    fn welcome(&mut self, peer_id: u64, auth_id: Option<String>) {
+        let (auth_id, auth_role, auth_method) = match auth_id {
+            Some(auth_id) => {
+                let user = &self.users[&auth_id];
+                let (role, auth_method) = (user.role.clone(), user.credentials.auth_method());
+                (auth_id, role, auth_method)
+            }
+            None => (peer_id.to_string(), "anonymous".to_string(), "anonymous"),
+        };
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.state = PeerState::Established;
+        }
+        self.send(
+            peer_id,
+            json!([WELCOME, peer_id, {
+                "realm": self.realm,
+                "authid": auth_id,
+                "authrole": auth_role,
+                "authmethod": auth_method,
+                "roles": {
+                    "broker": {"features": {
+                        "pattern_based_subscription": true,
+                        "publisher_exclusion": true,
+                        "publisher_identification": true,
+                        "subscriber_blackwhite_listing": true,
+                    }},
+                    "dealer": {"features": {
+                        "call_canceling": true,
+                        "caller_identification": true,
+                        "progressive_call_results": true,
+                    }},
+                },
+            }]),
+        );
+    }
+
+    /// Drops everything session has registered or subscribed.
+    fn end_session(&mut self, peer_id: u64) {
+        for topic in self.subscriptions.values_mut() {
+            topic.subscribers.remove(&peer_id);
+        }
+        self.subscriptions
+            .retain(|_, topic| !topic.subscribers.is_empty());
+        self.registrations
+            .retain(|_, procedure| procedure.callee != peer_id);
+
+        let invocations: Vec<u64> = self
+            .invocations
+            .iter()
+            .filter(|(_, inv)| inv.caller == peer_id || inv.callee == peer_id)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in invocations {
+            let inv = self.invocations.remove(&id).unwrap();
+            if inv.callee == peer_id {
+                self.error(inv.caller, CALL, inv.request_id, CANCELED);
+            }
+        }
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.state = PeerState::Connected;
+        }
+    }
+
+    fn handle_message(&mut self, peer_id: u64, msg: Vec<Value>) {
+        let code = msg.first().and_then(Value::as_u64).unwrap_or_default() as u8;
+        let u64_at = |i: usize| msg.get(i).and_then(Value::as_u64).unwrap_or_default();
+        let str_at = |i: usize| msg.get(i).and_then(Value::as_str).unwrap_or_default();
+        let dict_at = |i: usize| msg.get(i).cloned().unwrap_or_else(|| json!({}));
+
+        if code != HELLO && code != AUTHENTICATE && code != GOODBYE && !self.is_established(peer_id)
+        {
+            return self.abort(peer_id, PROTOCOL_VIOLATION, "session not established");
+        }
+
+        match code {
+            HELLO => self.handle_hello(peer_id, str_at(1), &dict_at(2)),
+            AUTHENTICATE => self.handle_authenticate(peer_id, str_at(1)),
+            GOODBYE => {
+                let shutting_down = match self.peers.get(&peer_id) {
+                    Some(peer) => matches!(peer.state, PeerState::ShuttingDown),
+                    None => return,
+                };
+                if !shutting_down {
+                    self.send(peer_id, json!([GOODBYE, {}, CLOSE_GOODBYE_AND_OUT]));
+                }
+                self.end_session(peer_id);
+            }
+            SUBSCRIBE => {
+                let request_id = u64_at(1);
+                let policy = dict_at(2)["match"].as_str().unwrap_or("exact").to_string();
+                let uri = str_at(3).to_string();
+                if self.denied_topics.contains(&uri) {
+                    return self.error(peer_id, SUBSCRIBE, request_id, NOT_AUTHORIZED);
+                }
+                let existing = self
+                    .subscriptions
+                    .iter()
+                    .find(|(_, topic)| topic.uri == uri && topic.policy == policy)
+                    .map(|(&id, _)| id);
+                let subscription_id = match existing {
+                    Some(id) => id,
+                    None => {
+                        let id = self.gen_id();
+                        self.subscriptions.insert(
+                            id,
+                            Topic {
+                                uri,
+                                policy,
+                                subscribers: HashSet::new(),
+                            },
+                        );
+                        id
+                    }
+                };
+                self.subscriptions
+                    .get_mut(&subscription_id)
+                    .unwrap()
+                    .subscribers
+                    .insert(peer_id);
+                self.send(peer_id, json!([SUBSCRIBED, request_id, subscription_id]));
+            }
+            UNSUBSCRIBE => {
+                let request_id = u64_at(1);
+                let subscription_id = u64_at(2);
+                let removed = self
+                    .subscriptions
+                    .get_mut(&subscription_id)
+                    .map(|topic| topic.subscribers.remove(&peer_id))
+                    .unwrap_or(false);
+                if removed {
+                    self.subscriptions
+                        .retain(|_, topic| !topic.subscribers.is_empty());
+                    self.send(peer_id, json!([UNSUBSCRIBED, request_id]));
+                } else {
+                    self.error(peer_id, UNSUBSCRIBE, request_id, NO_SUCH_SUBSCRIPTION);
+                }
+            }
+            PUBLISH => self.handle_publish(peer_id, &msg),
+            REGISTER => {
+                let request_id = u64_at(1);
+                let uri = str_at(3);
+                if self.registrations.values().any(|p| p.uri == uri) {
+                    return self.error(peer_id, REGISTER, request_id, PROCEDURE_ALREADY_EXISTS);
+                }
+                let registration_id = self.gen_id();
+                self.registrations.insert(
+                    registration_id,
+                    Procedure {
+                        uri: uri.to_string(),
+                        callee: peer_id,
+                    },
+                );
+                self.send(peer_id, json!([REGISTERED, request_id, registration_id]));
+            }
+            UNREGISTER => {
+                let request_id = u64_at(1);
+                let registration_id = u64_at(2);
+                match self.registrations.get(&registration_id) {
+                    Some(procedure) if procedure.callee == peer_id => {
+                        self.registrations.remove(&registration_id);
+                        self.send(peer_id, json!([UNREGISTERED, request_id]));
+                    }
+                    _ => self.error(peer_id, UNREGISTER, request_id, NO_SUCH_REGISTRATION),
+                }
+            }
+            CALL => self.handle_call(peer_id, &msg),
+            CANCEL => {
+                let request_id = u64_at(1);
+                let found = self
+                    .invocations
+                    .iter()
+                    .find(|(_, inv)| inv.caller == peer_id && inv.request_id == request_id)
+                    .map(|(&id, _)| id);
+                if let Some(invocation_id) = found {
+                    let inv = self.invocations.remove(&invocation_id).unwrap();
+                    let mode = dict_at(2)["mode"].as_str().unwrap_or("kill").to_string();
+                    self.send(
+                        inv.callee,
+                        json!([INTERRUPT, invocation_id, { "mode": mode }]),
+                    );
+                    self.error(peer_id, CALL, request_id, CANCELED);
+                }
+            }
+            YIELD => {
+                let invocation_id = u64_at(1);
+                let progress = dict_at(2)["progress"].as_bool().unwrap_or(false);
+                let inv = match self.invocations.get(&invocation_id) {
+                    Some(inv) if inv.callee == peer_id => inv,
+                    _ => return,
+                };
+                let (caller, request_id) = (inv.caller, inv.request_id);
+                let details = if progress {
+                    json!({"progress": true})
+                } else {
+                    self.invocations.remove(&invocation_id);
+                    json!({})
+                };
+                self.send(
+                    caller,
+                    with_payload(vec![json!(RESULT), json!(request_id), details], &msg, 3),
+                );
+            }
+            ERROR if u64_at(1) == INVOCATION as u64 => {
+                let invocation_id = u64_at(2);
+                match self.invocations.get(&invocation_id) {
+                    Some(inv) if inv.callee == peer_id => (),
+                    _ => return,
+                }
+                let inv = self.invocations.remove(&invocation_id).unwrap();
+                self.send(
+                    inv.caller,
+                    with_payload(
+                        vec![
+                            json!(ERROR),
+                            json!(CALL),
+                            json!(inv.request_id),
+                            dict_at(3),
+                            json!(str_at(4)),
+                        ],
+                        &msg,
+                        5,
+                    ),
+                );
+            }
+            _ => self.abort(peer_id, PROTOCOL_VIOLATION, "unexpected message"),
+        }
+    }
+
+    fn handle_hello(&mut self, peer_id: u64, realm: &str, details: &Value) {
+        if realm != self.realm {
+            return self.abort(peer_id, NO_SUCH_REALM, "no such realm");
+        }
+        let auth_id = details["authid"].as_str();
+        let offers = |auth_method: &str| {
+            details["authmethods"]
+                .as_array()
+                .map(|methods| methods.iter().any(|m| m == auth_method))
+                .unwrap_or(false)
+        };
+
+        match auth_id.and_then(|auth_id| self.users.get_key_value(auth_id)) {
+            Some((auth_id, user)) if offers(user.credentials.auth_method()) => {
+                let (extra, signature) = match &user.credentials {
+                    Credentials::Cra { secret, salt } => {
+                        let nonce: [u8; 16] = rand::random();
+                        let challenge = json!({
+                            "authid": auth_id,
+                            "authrole": user.role,
+                            "authmethod": "wampcra",
+                            "authprovider": "static",
+                            "nonce": hex::encode(nonce),
+                            "timestamp": "1970-01-01T00:00:00.000Z",
+                            "session": peer_id,
+                        })
+                        .to_string();
+                        let mut extra = json!({ "challenge": challenge });
+                        let key = match salt {
+                            Some(salt) => {
+                                extra["salt"] = json!(salt);
+                                extra["iterations"] = json!(CRA_ITERATIONS);
+                                extra["keylen"] = json!(CRA_KEYLEN);
+                                derive_cra_key(secret, salt)
+                            }
+                            None => secret.clone(),
+                        };
+                        let mut hmac = hmac::Hmac::<sha2::Sha256>::new_varkey(&key).unwrap();
+                        hmac.input(challenge.as_bytes());
+                        (extra, base64::encode(&hmac.result().code()))
+                    }
+                    Credentials::Ticket(ticket) => (json!({}), ticket.clone()),
+                };
+                let auth_method = user.credentials.auth_method();
+
+                let auth_id = auth_id.clone();
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.state = PeerState::Challenged { auth_id, signature };
+                }
+                self.send(peer_id, json!([CHALLENGE, auth_method, extra]));
+            }
+            _ if self.allow_anonymous => self.welcome(peer_id, None),
+            _ => self.abort(peer_id, NOT_AUTHORIZED, "authentication required"),
+        }
+    }
+
+    fn handle_authenticate(&mut self, peer_id: u64, signature: &str) {
+        let peer = match self.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        match std::mem::replace(&mut peer.state, PeerState::Connected) {
+            PeerState::Challenged {
+                auth_id,
+                signature: expected,
+            } if expected == signature => self.welcome(peer_id, Some(auth_id)),
+            _ => self.abort(peer_id, NOT_AUTHORIZED, "invalid signature"),
+        }
+    }
+
+    // [PUBLISH, Request|id, Options|dict, Topic|uri, Arguments|list, ArgumentsKw|dict]
+    fn handle_publish(&mut self, peer_id: u64, msg: &[Value]) {
+        let request_id = msg.get(1).and_then(Value::as_u64).unwrap_or_default();
+        let options = msg.get(2).cloned().unwrap_or_else(|| json!({}));
+        let topic = msg.get(3).and_then(Value::as_str).unwrap_or_default();
+        let publication_id = self.gen_id();
+
+        let exclude_me = options["exclude_me"].as_bool().unwrap_or(true);
+        let ids = |name: &str| -> Option<Vec<u64>> {
+            options[name]
+                .as_array()
+                .map(|ids| ids.iter().filter_map(Value::as_u64).collect())
+        };
+        let (eligible, exclude) = (ids("eligible"), ids("exclude"));
+
+        let mut events = Vec::new();
+        for (&subscription_id, sub) in &self.subscriptions {
+            if !sub.matches(topic) {
+                continue;
+            }
+            let mut details = json!({});
+            if sub.policy != "exact" {
+                details["topic"] = json!(topic);
+            }
+            if options["disclose_me"].as_bool().unwrap_or(false) {
+                details["publisher"] = json!(peer_id);
+            }
+            for &subscriber in &sub.subscribers {
+                let skip = (exclude_me && subscriber == peer_id)
+                    || eligible.as_ref().is_some_and(|e| !e.contains(&subscriber))
+                    || exclude.as_ref().is_some_and(|e| e.contains(&subscriber));
+                if !skip {
+                    let event = vec![
+                        json!(EVENT),
+                        json!(subscription_id),
+                        json!(publication_id),
+                        details.clone(),
+                    ];
+                    events.push((subscriber, with_payload(event, msg, 4)));
+                }
+            }
+        }
+        for (subscriber, event) in events {
+            self.send(subscriber, event);
+        }
+
+        if options["acknowledge"].as_bool().unwrap_or(false) {
+            self.send(peer_id, json!([PUBLISHED, request_id, publication_id]));
+        }
+    }
+
+    // [CALL, Request|id, Options|dict, Procedure|uri, Arguments|list, ArgumentsKw|dict]
+    fn handle_call(&mut self, peer_id: u64, msg: &[Value]) {
+        let request_id = msg.get(1).and_then(Value::as_u64).unwrap_or_default();
+        let options = msg.get(2).cloned().unwrap_or_else(|| json!({}));
+        let uri = msg.get(3).and_then(Value::as_str).unwrap_or_default();
+
+        let (registration_id, callee) = match self.registrations.iter().find(|(_, p)| p.uri == uri)
+        {
+            Some((&id, procedure)) => (id, procedure.callee),
+            None => return self.error(peer_id, CALL, request_id, NO_SUCH_PROCEDURE),
+        };
+
+        let mut details = json!({});
+        if options["receive_progress"].as_bool().unwrap_or(false) {
+            details["receive_progress"] = json!(true);
+        }
+        if options["disclose_me"].as_bool().unwrap_or(false) {
+            details["caller"] = json!(peer_id);
+        }
+        let invocation_id = self.gen_id();
+        self.invocations.insert(
+            invocation_id,
+            PendingInvocation {
+                caller: peer_id,
+                request_id,
+                callee,
+            },
+        );
+        let invocation = vec![
+            json!(INVOCATION),
+            json!(invocation_id),
+            json!(registration_id),
+            details,
+        ];
+        self.send(callee, with_payload(invocation, msg, 4));
+    }
+}
+
+impl Actor for RouterActor {
+    type Context = Context<Self>;
+}
+
+struct Accept {
+    tx: mpsc::UnboundedSender<Result<ws::Frame, ws::ProtocolError>>,
+    rx: mpsc::UnboundedReceiver<ws::Message>,
+}
+
+impl Message for Accept {
+    type Result = ();
+}
+
+enum Incoming {
+    Message(u64, ws::Message),
+    Closed(u64),
+}
+
+impl Handler<Accept> for RouterActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Accept, ctx: &mut Self::Context) -> Self::Result {
+        let peer_id = self.gen_id();
+        self.peers.insert(
+            peer_id,
+            Peer {
+                tx: msg.tx,
+                serializer: Serializer::default(),
+                state: PeerState::Connected,
+            },
+        );
+        ctx.add_stream(
+            msg.rx
+                .map(move |message| Incoming::Message(peer_id, message))
+                .chain(stream::once(future::ready(Incoming::Closed(peer_id)))),
+        );
+    }
+}
+
+impl StreamHandler<Incoming> for RouterActor {
+    fn handle(&mut self, item: Incoming, _ctx: &mut Self::Context) {
+        let (peer_id, frame) = match item {
+            Incoming::Message(peer_id, ws::Message::Text(text)) => {
+                (peer_id, ws::Frame::Text(text.into()))
+            }
+            Incoming::Message(peer_id, ws::Message::Binary(bytes)) => {
+                (peer_id, ws::Frame::Binary(bytes))
+            }
+            Incoming::Message(peer_id, ws::Message::Close(_)) | Incoming::Closed(peer_id) => {
+                self.end_session(peer_id);
+                self.peers.remove(&peer_id);
+                return;
+            }
+            Incoming::Message(peer_id, ws::Message::Ping(payload)) => {
+                if let Some(peer) = self.peers.get(&peer_id) {
+                    let _ = peer.tx.unbounded_send(Ok(ws::Frame::Pong(payload)));
+                }
+                return;
+            }
+            Incoming::Message(..) => return,
+        };
+        let peer = match self.peers.get_mut(&peer_id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        // Peers do not negotiate serializer, router replies in the one client uses.
+        peer.serializer = match frame {
+            ws::Frame::Text(_) => Serializer::Json,
+            _ => Serializer::MsgPack,
+        };
+
+        let msg = serializer::decode_frame(&frame)
+            .ok()
+            .map(|value| serializer::to_json(&value));
+        match msg {
+            Some(Value::Array(msg)) => self.handle_message(peer_id, msg),
+            _ => self.abort(peer_id, PROTOCOL_VIOLATION, "invalid message"),
+        }
+    }
+
+    fn finished(&mut self, _ctx: &mut Self::Context) {
+        // Each connection is separate stream, router lives on.
+    }
+}
+
+struct Shutdown {
+    reason: String,
+}
+
+impl Message for Shutdown {
+    type Result = ();
+}
+
+impl Handler<Shutdown> for RouterActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        let established: Vec<u64> = self
+            .peers
+            .keys()
+            .cloned()
+            .filter(|&peer_id| self.is_established(peer_id))
+            .collect();
+        for peer_id in established {
+            self.send(peer_id, json!([GOODBYE, {}, msg.reason]));
+            self.peers.get_mut(&peer_id).unwrap().state = PeerState::ShuttingDown;
+        }
+    }
+}
+
+struct DisconnectAll;
+
+impl Message for DisconnectAll {
+    type Result = ();
+}
+
+impl Handler<DisconnectAll> for RouterActor {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DisconnectAll, _ctx: &mut Self::Context) -> Self::Result {
+        // Peers are gone before cleanup, so nobody hears about the others,
+        // e.g. callers get no CANCELED for calls to disconnected callees.
+        let peers = std::mem::take(&mut self.peers);
+        for peer_id in peers.keys() {
+            self.end_session(*peer_id);
+        }
+    }
+}
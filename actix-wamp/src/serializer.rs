@@ -0,0 +1,187 @@
+//! WAMP message serialization formats.
+
+use crate::error::Error;
+use crate::payload::value;
+use actix_http::ws;
+use serde::Serialize;
+use std::io::Cursor;
+
+/// Serialization format negotiated with router as websocket subprotocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Serializer {
+    /// `wamp.2.json`, messages are sent as text frames.
+    Json,
+    /// `wamp.2.msgpack`, messages are sent as binary frames.
+    #[default]
+    MsgPack,
+}
+
+impl Serializer {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Serializer::Json => "wamp.2.json",
+            Serializer::MsgPack => "wamp.2.msgpack",
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "wamp.2.json" => Some(Serializer::Json),
+            "wamp.2.msgpack" => Some(Serializer::MsgPack),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn encode<M: Serialize>(&self, msg: &M) -> Result<ws::Message, Error> {
+        self.encode_value(&value::to_value(msg)?)
+    }
+
+    /// Encodes message in json wire form, i.e. with binary data as base64 strings.
+    #[cfg(feature = "test-router")]
+    pub(crate) fn encode_json(&self, msg: &serde_json::Value) -> Result<ws::Message, Error> {
+        let mut msg = value::to_value(msg)?;
+        decode_json_binary(&mut msg);
+        self.encode_value(&msg)
+    }
+
+    fn encode_value(&self, msg: &rmpv::Value) -> Result<ws::Message, Error> {
+        log::debug!("send message {}", msg);
+        match self {
+            Serializer::Json => Ok(ws::Message::Text(to_json(msg).to_string())),
+            Serializer::MsgPack => {
+                let mut bytes = Vec::new();
+                rmpv::encode::write_value(&mut bytes, msg)?;
+                Ok(ws::Message::Binary(bytes.into()))
+            }
+        }
+    }
+}
+
+/// Prefix of binary data encoded as base64 string in json messages.
+const JSON_BINARY_PREFIX: char = '\0';
+
+/// Converts message to json wire form.
+pub(crate) fn to_json(value: &rmpv::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value {
+        rmpv::Value::Nil => Value::Null,
+        rmpv::Value::Boolean(v) => Value::Bool(*v),
+        rmpv::Value::Integer(v) => match (v.as_u64(), v.as_i64()) {
+            (Some(v), _) => v.into(),
+            (None, Some(v)) => v.into(),
+            _ => Value::Null,
+        },
+        rmpv::Value::F32(v) => (*v).into(),
+        rmpv::Value::F64(v) => (*v).into(),
+        rmpv::Value::String(v) => Value::String(String::from_utf8_lossy(v.as_bytes()).into()),
+        rmpv::Value::Binary(v) | rmpv::Value::Ext(_, v) => {
+            Value::String(format!("{}{}", JSON_BINARY_PREFIX, base64::encode(v)))
+        }
+        rmpv::Value::Array(v) => Value::Array(v.iter().map(to_json).collect()),
+        rmpv::Value::Map(v) => Value::Object(
+            v.iter()
+                .map(|(key, value)| {
+                    let key = match key.as_str() {
+                        Some(key) => key.to_string(),
+                        None => key.to_string(),
+                    };
+                    (key, to_json(value))
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Restores binary data sent in json message.
+fn decode_json_binary(value: &mut rmpv::Value) {
+    match value {
+        rmpv::Value::String(v) => {
+            let data = match v.as_str() {
+                Some(v) if v.starts_with(JSON_BINARY_PREFIX) => base64::decode(&v[1..]).ok(),
+                _ => None,
+            };
+            if let Some(data) = data {
+                *value = rmpv::Value::Binary(data);
+            }
+        }
+        rmpv::Value::Array(v) => v.iter_mut().for_each(decode_json_binary),
+        rmpv::Value::Map(v) => v
+            .iter_mut()
+            .for_each(|(_key, value)| decode_json_binary(value)),
+        _ => (),
+    }
+}
+
+/// Decodes WAMP message from data frame.
+///
+/// Format is recognized by frame type, so both serializers are accepted regardless of
+/// negotiated one.
+pub(crate) fn decode_frame(frame: &ws::Frame) -> Result<rmpv::Value, Error> {
+    match frame {
+        ws::Frame::Binary(bytes) => Ok(rmpv::decode::read_value(&mut Cursor::new(bytes.as_ref()))?),
+        ws::Frame::Text(bytes) => {
+            let mut value = serde_json::from_slice(bytes.as_ref())?;
+            decode_json_binary(&mut value);
+            Ok(value)
+        }
+        _ => Err(Error::protocol_err("expected data frame")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_protocol() {
+        for serializer in &[Serializer::Json, Serializer::MsgPack] {
+            assert_eq!(
+                Serializer::from_protocol(serializer.protocol()),
+                Some(*serializer)
+            );
+        }
+        assert_eq!(Serializer::from_protocol("wamp.2.cbor"), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let msg = (48u8, 7u64, serde_json::json!({}), "golem.version");
+
+        for serializer in &[Serializer::Json, Serializer::MsgPack] {
+            let frame = match serializer.encode(&msg).unwrap() {
+                ws::Message::Text(text) => ws::Frame::Text(text.into()),
+                ws::Message::Binary(bytes) => ws::Frame::Binary(bytes),
+                _ => panic!("unexpected message"),
+            };
+            let value = decode_frame(&frame).unwrap();
+            assert_eq!(value[0].as_u64(), Some(48));
+            assert_eq!(value[1].as_u64(), Some(7));
+            assert_eq!(value[3].as_str(), Some("golem.version"));
+        }
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let msg = (
+            50u8,
+            7u64,
+            serde_json::json!({}),
+            vec![rmpv::Value::Binary(vec![0, 1, 255]), u64::MAX.into()],
+        );
+
+        for serializer in &[Serializer::Json, Serializer::MsgPack] {
+            let frame = match serializer.encode(&msg).unwrap() {
+                ws::Message::Text(text) => {
+                    assert!(text.contains(r#""\u0000AAH/""#));
+                    ws::Frame::Text(text.into())
+                }
+                ws::Message::Binary(bytes) => ws::Frame::Binary(bytes),
+                _ => panic!("unexpected message"),
+            };
+            let value = decode_frame(&frame).unwrap();
+            assert_eq!(value[3][0].as_slice(), Some(&[0u8, 1, 255][..]));
+            assert_eq!(value[3][1].as_u64(), Some(u64::MAX));
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::Error;
+use crate::messages::{Dict, Role, RoleDesc, RoleMap};
+use actix::Message;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::borrow::Cow;
+
+/// Default GOODBYE reason for closing session.
+pub const CLOSE_NORMAL: &str = "wamp.close.normal";
+
+/// GOODBYE reason replied to peer closing session.
+pub(crate) const CLOSE_GOODBYE_AND_OUT: &str = "wamp.close.goodbye_and_out";
+
+/// Why session has ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Session closed with GOODBYE handshake.
+    Goodbye {
+        /// Reason given by peer which initiated closing, e.g. `wamp.close.system_shutdown`.
+        uri: String,
+        message: Option<String>,
+        /// Whether closing was initiated by router.
+        by_router: bool,
+    },
+    /// Session aborted by router.
+    Abort {
+        uri: String,
+        message: Option<String>,
+    },
+    /// Connection lost without closing session.
+    ConnectionLost,
+}
+
+/// Change of session state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    Established { session_id: u64 },
+    Closing,
+    Closed { reason: CloseReason },
+}
+
+/// Session details sent by router in WELCOME.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub session_id: u64,
+    pub auth_id: Option<String>,
+    pub auth_role: Option<String>,
+    pub auth_method: Option<String>,
+    /// Roles played by router, with features it advertises.
+    pub roles: RoleMap,
+    /// All details as sent by router.
+    pub extra: Dict,
+}
+
+impl SessionInfo {
+    pub(crate) fn from_welcome(session_id: u64, extra: Dict) -> Self {
+        let field = |name: &str| extra.get(name).and_then(|v| v.as_str()).map(Into::into);
+        // Unknown roles and non-boolean features are skipped.
+        let roles = extra
+            .get("roles")
+            .and_then(|roles| roles.as_object())
+            .into_iter()
+            .flatten()
+            .filter_map(|(role, desc)| {
+                let role: Role = serde_json::from_value(role.as_str().into()).ok()?;
+                let features = desc
+                    .get("features")
+                    .and_then(|features| features.as_object())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(name, value)| Some((name.clone(), value.as_bool()?)))
+                    .collect();
+                Some((role, RoleDesc { features }))
+            })
+            .collect();
+
+        SessionInfo {
+            session_id,
+            auth_id: field("authid"),
+            auth_role: field("authrole"),
+            auth_method: field("authmethod"),
+            roles,
+            extra,
+        }
+    }
+
+    /// Checks whether router advertises feature of given role,
+    /// e.g. `has_feature(Role::Dealer, "call_canceling")`.
+    pub fn has_feature(&self, role: Role, feature: &str) -> bool {
+        self.roles
+            .get(&role)
+            .is_some_and(|desc| desc.has_feature(feature))
+    }
+}
+
+/// Lifecycle of WAMP session.
+pub trait SessionEndpoint {
+    type Closing: Future<Output = Result<(), Error>> + 'static;
+    type Closed: Future<Output = ()> + 'static;
+    type SessionEvents: Stream<Item = SessionEvent> + 'static;
+    type SessionInfo: Future<Output = Result<SessionInfo, Error>> + 'static;
+
+    /// Closes session with GOODBYE handshake. `reason` is uri sent to router,
+    /// e.g. [`CLOSE_NORMAL`].
+    ///
+    /// Pending calls and subscriptions fail with error carrying `reason`.
+    fn close(&self, reason: &str) -> Self::Closing;
+
+    /// Resolves when session is closed or underlying connection is lost.
+    fn closed(&self) -> Self::Closed;
+
+    /// Stream of session state changes, starting with current state.
+    ///
+    /// Ends after [`SessionEvent::Closed`].
+    fn session_events(&self) -> Self::SessionEvents;
+
+    /// Details of established session, including roles and features of router.
+    ///
+    /// Fails with [`Error::InvalidState`] when session is not established.
+    fn session_info(&self) -> Self::SessionInfo;
+}
+
+pub struct Close {
+    pub reason: Cow<'static, str>,
+}
+
+impl Message for Close {
+    type Result = Result<(), Error>;
+}
+
+pub struct WatchSession;
+
+pub struct GetSessionInfo;
+
+impl Message for GetSessionInfo {
+    type Result = Result<SessionInfo, Error>;
+}
+
+impl Message for WatchSession {
+    type Result = mpsc::UnboundedReceiver<SessionEvent>;
+}
@@ -0,0 +1,1580 @@
+//! WAMP session state machine, independent of async runtime.
+//!
+//! [`SessionCore`] keeps handshake state, pending requests, subscriptions and registrations.
+//! Drivers feed it with received frames and client requests, deliver frames it writes and
+//! run timers on its behalf. Two drivers exist: actix actor in [`connection`](crate::connection)
+//! and future based [`tokio_session`](crate::tokio_session).
+
+use super::messages::types::*;
+use crate::args::*;
+use crate::error::Error;
+use crate::messages::{Dict, WampError};
+use crate::observer::{
+    CallFinished, CallOutcome, CallStarted, EventDelivered, SessionObserver, SubscriptionChange,
+};
+use crate::payload::Payload;
+use crate::pubsub::{
+    self, MatchPolicy, Publish, Subscribe, Subscription, Unsubscribe, WampMessage,
+};
+use crate::registry::{
+    Invocation, InvocationHandler, InvocationReply, Register, Registration, Unregister,
+};
+use crate::serializer::{self, Serializer};
+use crate::session::{self, CloseReason, SessionEvent, SessionInfo};
+use crate::{AuthMethod, ErrorKind};
+use actix_http::ws;
+use futures::future::LocalBoxFuture;
+use futures::{channel::mpsc, channel::oneshot, prelude::*};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for peer to finish closing handshake.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ABORT reason sent when challenge or router signature is rejected.
+const CANNOT_AUTHENTICATE: &str = "wamp.error.cannot_authenticate";
+
+/// Keepalive settings, see [`WsOptions::with_heartbeat`](crate::WsOptions::with_heartbeat).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+pub(crate) fn gen_id() -> u64 {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+
+    rng.gen::<u64>() & 0x1f_ff_ff__ff_ff_ff_ffu64
+}
+
+/// Callback delivering message from handle (e.g. dropped subscription) to its driver.
+pub(crate) type Notify<M> = Box<dyn Fn(M) + Send>;
+
+/// Outgoing side of transport.
+pub(crate) trait FrameWriter {
+    fn write(&mut self, msg: ws::Message) -> Result<(), Error>;
+}
+
+/// Frames queued until driver sends them.
+impl FrameWriter for VecDeque<ws::Message> {
+    fn write(&mut self, msg: ws::Message) -> Result<(), Error> {
+        self.push_back(msg);
+        Ok(())
+    }
+}
+
+/// What driver has to do after frame is handled.
+pub(crate) enum Effect {
+    None,
+    /// Run invocation handler and pass its result to
+    /// [`send_invocation_result`](SessionCore::send_invocation_result).
+    Invocation(u64, InvocationReply),
+    /// Session has ended, transport should be closed with
+    /// [`close_transport`](SessionCore::close_transport).
+    Shutdown,
+}
+
+pub struct OpenSession {
+    realm_id: String,
+    auth_id: Option<String>,
+    auth_methods: Vec<Box<dyn AuthMethod + Send + 'static>>,
+}
+
+impl actix::Message for OpenSession {
+    type Result = Result<u64, crate::error::Error>;
+}
+
+impl OpenSession {
+    pub fn anonymous(realm_id: String) -> Self {
+        OpenSession {
+            realm_id,
+            auth_id: None,
+            auth_methods: Vec::new(),
+        }
+    }
+
+    pub fn with_auth<A: AuthMethod + 'static + Send>(
+        realm_id: String,
+        auth_id: String,
+        auth_method: A,
+    ) -> Self {
+        OpenSession {
+            realm_id,
+            auth_id: Some(auth_id),
+            auth_methods: vec![Box::new(auth_method)],
+        }
+    }
+
+    /// Offers additional auth method to router.
+    pub fn add_auth<A: AuthMethod + 'static + Send>(&mut self, auth_method: A) {
+        self.auth_methods.push(Box::new(auth_method));
+    }
+}
+
+type SubSender = pubsub::EventSender;
+
+/// Broker subscription shared by all local consumers of the same topic and match policy.
+struct SubscriberDesc {
+    /// Subscribed uri, used as event topic when broker does not send one.
+    topic: String,
+    match_policy: MatchPolicy,
+    consumers: HashMap<u64, SubSender>,
+}
+
+/// SUBSCRIBE waiting for reply. Consumers subscribing the same topic meanwhile join it.
+struct PendingSubscription {
+    topic: String,
+    match_policy: MatchPolicy,
+    consumers: HashMap<u64, SubSender>,
+    waiters: Vec<oneshot::Sender<Result<u64, Error>>>,
+}
+
+type PublishSender = oneshot::Sender<Result<Option<u64>, Error>>;
+
+#[allow(clippy::large_enum_variant)]
+enum ConnectionState {
+    Closed,
+    Establishing {
+        auth: Vec<Box<dyn AuthMethod + Send + 'static>>,
+        auth_id: Option<String>,
+        tx: Option<oneshot::Sender<Result<u64, Error>>>,
+    },
+    Authenticating {
+        auth: Box<dyn AuthMethod + Send + 'static>,
+        tx: oneshot::Sender<Result<u64, Error>>,
+    },
+    Established {
+        info: SessionInfo,
+        pending_calls: HashMap<u64, CallDesc>,
+        subscribers: HashMap<u64, SubscriberDesc>,
+        pending_subscriptions: HashMap<u64, PendingSubscription>,
+        pending_publications: HashMap<u64, PublishSender>,
+        registrations: HashMap<u64, InvocationHandler>,
+        pending_registrations: HashMap<u64, PendingRegistration>,
+    },
+    Closing {
+        reason: String,
+        tx: Option<oneshot::Sender<Result<(), Error>>>,
+    },
+    Failed,
+}
+
+struct CallDesc {
+    tx: CallSender,
+    cancel_mode: CancelMode,
+    uri: String,
+    started: Instant,
+    request_size: usize,
+}
+
+enum CallSender {
+    Single(oneshot::Sender<Result<Payload, Error>>),
+    Progressive(mpsc::UnboundedSender<Result<Payload, Error>>),
+}
+
+impl CallSender {
+    fn send(self, result: Result<Payload, Error>) {
+        match self {
+            CallSender::Single(tx) => {
+                let _ = tx.send(result);
+            }
+            CallSender::Progressive(tx) => {
+                let _ = tx.unbounded_send(result);
+            }
+        }
+    }
+}
+
+struct PendingRegistration {
+    tx: oneshot::Sender<Result<u64, Error>>,
+    handler: InvocationHandler,
+}
+
+fn to_kw_args(kw_args: Option<&rmpv::Value>) -> Option<Dict> {
+    kw_args
+        .and_then(|kw_args| serde_json::to_value(kw_args).ok())
+        .and_then(|kw_args| kw_args.as_object().cloned())
+}
+
+/// Reads id (or message type) at `index` of received message.
+fn id_field(message: &rmpv::Value, index: usize) -> Result<u64, Error> {
+    message[index]
+        .as_u64()
+        .ok_or_else(|| Error::protocol_err("expected id in message"))
+}
+
+/// Reads uri at `index` of received message.
+fn uri_field(message: &rmpv::Value, index: usize) -> Result<&str, Error> {
+    message[index]
+        .as_str()
+        .ok_or_else(|| Error::protocol_err("expected uri in message"))
+}
+
+/// Reads dict at `index` of received message, missing one is empty.
+fn dict_field(message: &rmpv::Value, index: usize) -> Result<&[(rmpv::Value, rmpv::Value)], Error> {
+    match &message[index] {
+        rmpv::Value::Map(entries) => Ok(entries),
+        rmpv::Value::Nil => Ok(&[]),
+        _ => Err(Error::protocol_err("expected dict in message")),
+    }
+}
+
+pub(crate) struct SessionCore<W> {
+    writer: W,
+    serializer: Serializer,
+    state: ConnectionState,
+    session_watchers: Vec<mpsc::UnboundedSender<SessionEvent>>,
+    close_reason: Option<CloseReason>,
+    observers: Vec<Arc<dyn SessionObserver>>,
+    /// Size of frame being handled, reported to observers.
+    frame_size: usize,
+}
+
+impl<W: FrameWriter> SessionCore<W> {
+    pub(crate) fn new(writer: W, serializer: Serializer) -> Self {
+        SessionCore {
+            writer,
+            serializer,
+            state: ConnectionState::Closed,
+            session_watchers: Vec::new(),
+            close_reason: None,
+            observers: Vec::new(),
+            frame_size: 0,
+        }
+    }
+
+    pub(crate) fn with_observers(mut self, observers: Vec<Arc<dyn SessionObserver>>) -> Self {
+        self.observers = observers;
+        self
+    }
+
+    pub(crate) fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn send_message<M: Serialize>(&mut self, msg: &M) -> Result<(), Error> {
+        self.send_sized(msg).map(|_| ())
+    }
+
+    /// Sends message, returns its encoded size.
+    fn send_sized<M: Serialize>(&mut self, msg: &M) -> Result<usize, Error> {
+        let message = self.serializer.encode(msg)?;
+        let size = match &message {
+            ws::Message::Text(text) => text.len(),
+            ws::Message::Binary(data) => data.len(),
+            _ => 0,
+        };
+
+        self.writer.write(message)?;
+        Ok(size)
+    }
+
+    fn observe(&self, f: impl Fn(&dyn SessionObserver)) {
+        for observer in &self.observers {
+            f(observer.as_ref())
+        }
+    }
+
+    fn call_finished(
+        &self,
+        request_id: u64,
+        desc: &CallDesc,
+        outcome: CallOutcome<'_>,
+        response_size: usize,
+    ) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let call = CallFinished {
+            uri: &desc.uri,
+            request_id,
+            latency: desc.started.elapsed(),
+            outcome,
+            request_size: desc.request_size,
+            response_size,
+        };
+        self.observe(|observer| observer.call_finished(&call));
+    }
+
+    /// Reports finished call to observers and delivers result to caller.
+    fn finish_call(
+        &self,
+        request_id: u64,
+        desc: CallDesc,
+        result: Result<Payload, Error>,
+        response_size: usize,
+    ) {
+        let outcome = match &result {
+            Ok(_) => CallOutcome::Success,
+            Err(e) => CallOutcome::Failed(e),
+        };
+        self.call_finished(request_id, &desc, outcome, response_size);
+        desc.tx.send(result);
+    }
+
+    fn handle_challenge(&mut self, auth_method: &str, extra: &Dict) -> Result<Effect, Error> {
+        let (auth_methods, auth_id, tx) = match &mut self.state {
+            ConnectionState::Establishing {
+                auth, auth_id, tx, ..
+            } => match auth_id {
+                Some(auth_id) => (auth, auth_id.as_str(), tx),
+                None => {
+                    return Ok(self.abort_handshake(Error::protocol_err(
+                        "unexpected challenge on anonymous handshake",
+                    )))
+                }
+            },
+            _ => {
+                return Err(Error::wamp_error(
+                    ErrorKind::OptionNotAllowed,
+                    "invalid connection state".into(),
+                ))
+            }
+        };
+
+        let index = match auth_methods
+            .iter()
+            .position(|auth| auth.auth_method() == auth_method)
+        {
+            Some(index) => index,
+            None => {
+                return Ok(
+                    self.abort_handshake(Error::protocol_err("unexpected auth method received"))
+                )
+            }
+        };
+        let mut auth = auth_methods.swap_remove(index);
+        let (signature, extra) = match auth.challenge(auth_id, extra) {
+            Ok(reply) => reply,
+            Err(e) => return Ok(self.abort_handshake(e)),
+        };
+        let tx = tx.take().unwrap();
+        self.state = ConnectionState::Authenticating { auth, tx };
+        self.send_message(&(AUTHENTICATE, signature, extra))?;
+        Ok(Effect::None)
+    }
+
+    /// Aborts handshake refused on our side, session open fails with `err`.
+    fn abort_handshake(&mut self, err: Error) -> Effect {
+        log::warn!("authentication failed: {}", err);
+        let mut details = Dict::new();
+        details.insert("message".into(), err.to_string().into());
+        if let Err(e) = self.send_message(&(ABORT, details, CANNOT_AUTHENTICATE)) {
+            log::debug!("unable to send abort: {}", e);
+        }
+        match std::mem::replace(&mut self.state, ConnectionState::Failed) {
+            ConnectionState::Establishing { tx: Some(tx), .. }
+            | ConnectionState::Authenticating { tx, .. } => {
+                let _ = tx.send(Err(err));
+            }
+            _ => (),
+        }
+        Effect::Shutdown
+    }
+
+    fn handle_welcome(&mut self, session_id: u64, extra: Dict) -> Result<Effect, Error> {
+        log::debug!("got welcome: {:?}", extra);
+        match &self.state {
+            ConnectionState::Establishing { .. } | ConnectionState::Authenticating { .. } => (),
+            _ => return Err(Error::protocol_err("unexpected WELCOME")),
+        }
+        if let ConnectionState::Authenticating { auth, .. } = &mut self.state {
+            let auth_extra = extra
+                .get("authextra")
+                .and_then(|v| v.as_object())
+                .cloned()
+                .unwrap_or_default();
+            if let Err(e) = auth.welcome(&auth_extra) {
+                return Ok(self.abort_handshake(e));
+            }
+        }
+        let old_state = std::mem::replace(
+            &mut self.state,
+            ConnectionState::Established {
+                info: SessionInfo::from_welcome(session_id, extra),
+                pending_calls: HashMap::new(),
+                subscribers: HashMap::new(),
+                pending_subscriptions: HashMap::new(),
+                pending_publications: HashMap::new(),
+                registrations: HashMap::new(),
+                pending_registrations: HashMap::new(),
+            },
+        );
+        match old_state {
+            ConnectionState::Establishing { tx, .. } => {
+                let _ = tx.unwrap().send(Ok(session_id));
+            }
+            ConnectionState::Authenticating { tx, .. } => {
+                let _ = tx.send(Ok(session_id));
+            }
+            _ => (),
+        };
+        self.emit(SessionEvent::Established { session_id });
+
+        Ok(Effect::None)
+    }
+
+    fn pending_calls(&mut self) -> Result<&mut HashMap<u64, CallDesc>, Error> {
+        match &mut self.state {
+            ConnectionState::Established { pending_calls, .. } => Ok(pending_calls),
+            _ => Err(Error::InvalidState("session is closed or pending")),
+        }
+    }
+
+    fn start_call(
+        &mut self,
+        id: u64,
+        RpcCallRequest {
+            uri,
+            options,
+            payload,
+            timeout,
+            cancel_mode,
+        }: RpcCallRequest,
+        tx: CallSender,
+    ) -> Result<(), Error> {
+        if self.pending_calls()?.contains_key(&id) {
+            return Err(Error::InvalidState("request id already in use"));
+        }
+        let mut options = options.unwrap_or_default();
+        if let CallSender::Progressive(_) = &tx {
+            options.insert("receive_progress".into(), true.into());
+        }
+
+        if let Some(timeout) = timeout {
+            // Driver cancels call on timeout, router may do so too.
+            options.insert("timeout".into(), (timeout.as_millis() as u64).into());
+        }
+
+        let Payload { args, kw_args } = payload;
+        let request_size = if !kw_args.is_empty() {
+            self.send_sized(&(CALL, id, options, &uri, args, kw_args))?
+        } else if !args.is_empty() {
+            self.send_sized(&(CALL, id, options, &uri, args))?
+        } else {
+            self.send_sized(&(CALL, id, options, &uri))?
+        };
+
+        self.observe(|observer| {
+            observer.call_started(&CallStarted {
+                uri: &uri,
+                request_id: id,
+                request_size,
+            })
+        });
+        self.pending_calls()?.insert(
+            id,
+            CallDesc {
+                tx,
+                cancel_mode,
+                uri: uri.into_owned(),
+                started: Instant::now(),
+                request_size,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Sends CALL with `request_id` (see [`gen_id`]), result is delivered to returned receiver.
+    pub(crate) fn call(
+        &mut self,
+        request_id: u64,
+        request: RpcCallRequest,
+    ) -> Result<CallReceiver, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.start_call(request_id, request, CallSender::Single(tx))?;
+        Ok(rx)
+    }
+
+    /// Sends CALL with `receive_progress` option set.
+    pub(crate) fn call_progressive(
+        &mut self,
+        request_id: u64,
+        request: RpcCallRequest,
+    ) -> Result<ProgressiveReceiver, Error> {
+        let (tx, rx) = mpsc::unbounded();
+        self.start_call(request_id, request, CallSender::Progressive(tx))?;
+        Ok(rx)
+    }
+
+    /// Removes call from pending calls and sends CANCEL to dealer.
+    ///
+    /// When `reason` is given caller is notified with it.
+    pub(crate) fn cancel_call(
+        &mut self,
+        request_id: u64,
+        reason: Option<Error>,
+    ) -> Result<(), Error> {
+        if let Some(desc) = self.pending_calls()?.remove(&request_id) {
+            let cancel_mode = desc.cancel_mode;
+            match reason {
+                Some(reason) => self.finish_call(request_id, desc, Err(reason), 0),
+                None => self.call_finished(request_id, &desc, CallOutcome::Cancelled, 0),
+            }
+            self.send_message(&(
+                CANCEL,
+                request_id,
+                serde_json::json!({ "mode": cancel_mode }),
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn handle_subscribed(&mut self, request_id: u64, subscription_id: u64) -> Result<(), Error> {
+        let PendingSubscription {
+            topic,
+            match_policy,
+            consumers,
+            waiters,
+        } = match self.pending_subscriptions()?.remove(&request_id) {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        self.observe(|observer| {
+            observer.subscription_changed(&SubscriptionChange::Subscribed {
+                topic: &topic,
+                subscription_id,
+            })
+        });
+        // Broker returns the same id for identical subscriptions, so consumers are merged
+        // into one we may already have.
+        self.subscribers()?
+            .entry(subscription_id)
+            .or_insert_with(|| SubscriberDesc {
+                topic,
+                match_policy,
+                consumers: HashMap::new(),
+            })
+            .consumers
+            .extend(consumers);
+        for tx in waiters {
+            let _ = tx.send(Ok(subscription_id));
+        }
+        Ok(())
+    }
+
+    // [RESULT, CALL.Request|id, Details|dict, YIELD.Arguments|list, YIELD.ArgumentsKw|dict]
+    fn handle_result(
+        &mut self,
+        call_id: u64,
+        details: &rmpv::Value,
+        args: Option<&rmpv::Value>,
+        kwargs: Option<&rmpv::Value>,
+    ) -> Result<(), Error> {
+        let progress = details["progress"].as_bool().unwrap_or(false);
+        let pending_calls = self.pending_calls()?;
+        let response = Payload::from_message(args, kwargs);
+
+        if progress {
+            match pending_calls.get(&call_id) {
+                Some(CallDesc {
+                    tx: CallSender::Progressive(tx),
+                    ..
+                }) => {
+                    let _ = tx.unbounded_send(Ok(response));
+                }
+                Some(_) => log::warn!("unexpected progressive result for call {}", call_id),
+                None => (),
+            }
+        } else if let Some(desc) = pending_calls.remove(&call_id) {
+            self.finish_call(call_id, desc, Ok(response), self.frame_size);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn subscribers(&mut self) -> Result<&mut HashMap<u64, SubscriberDesc>, Error> {
+        match &mut self.state {
+            ConnectionState::Established { subscribers, .. } => Ok(subscribers),
+            _ => Err(Error::InvalidState("session is closed or pending")),
+        }
+    }
+
+    #[inline]
+    fn pending_subscriptions(&mut self) -> Result<&mut HashMap<u64, PendingSubscription>, Error> {
+        match &mut self.state {
+            ConnectionState::Established {
+                pending_subscriptions,
+                ..
+            } => Ok(pending_subscriptions),
+            _ => Err(Error::InvalidState("session is closed or pending")),
+        }
+    }
+
+    #[inline]
+    fn pending_publications(&mut self) -> Result<&mut HashMap<u64, PublishSender>, Error> {
+        match &mut self.state {
+            ConnectionState::Established {
+                pending_publications,
+                ..
+            } => Ok(pending_publications),
+            _ => Err(Error::InvalidState("session is closed or pending")),
+        }
+    }
+
+    fn handle_published(&mut self, request_id: u64, publication_id: u64) -> Result<(), Error> {
+        self.pending_publications()?
+            .remove(&request_id)
+            .and_then(|sender| sender.send(Ok(Some(publication_id))).ok());
+        Ok(())
+    }
+
+    #[inline]
+    fn registrations(&mut self) -> Result<&mut HashMap<u64, InvocationHandler>, Error> {
+        match &mut self.state {
+            ConnectionState::Established { registrations, .. } => Ok(registrations),
+            _ => Err(Error::InvalidState("session is closed or pending")),
+        }
+    }
+
+    #[inline]
+    fn pending_registrations(&mut self) -> Result<&mut HashMap<u64, PendingRegistration>, Error> {
+        match &mut self.state {
+            ConnectionState::Established {
+                pending_registrations,
+                ..
+            } => Ok(pending_registrations),
+            _ => Err(Error::InvalidState("session is closed or pending")),
+        }
+    }
+
+    fn handle_registered(&mut self, request_id: u64, registration_id: u64) -> Result<(), Error> {
+        if let Some(PendingRegistration { tx, handler }) =
+            self.pending_registrations()?.remove(&request_id)
+        {
+            self.registrations()?.insert(registration_id, handler);
+            let _ = tx.send(Ok(registration_id));
+        } else {
+            log::warn!("unexpected registered: request_id={}", request_id);
+        }
+        Ok(())
+    }
+
+    // [INVOCATION, Request|id, REGISTERED.Registration|id, Details|dict, CALL.Arguments|list, CALL.ArgumentsKw|dict]
+    fn handle_invocation(
+        &mut self,
+        registration_id: u64,
+        details: &rmpv::Value,
+        args: Option<&rmpv::Value>,
+        kwargs: Option<&rmpv::Value>,
+    ) -> Result<InvocationReply, Box<WampError>> {
+        let handler = match self
+            .registrations()
+            .ok()
+            .and_then(|registrations| registrations.get(&registration_id))
+        {
+            Some(handler) => handler,
+            None => {
+                return Err(Box::new(WampError::with_message(
+                    ErrorKind::NoSuchRegistration,
+                    format!("no registration: {}", registration_id),
+                )))
+            }
+        };
+
+        let payload = Payload::from_message(args, kwargs);
+        Ok(handler(Invocation {
+            args: payload.json_args(),
+            kw_args: payload.json_kw_args(),
+            details: to_kw_args(Some(details)).unwrap_or_default(),
+            payload,
+        }))
+    }
+
+    pub(crate) fn send_invocation_result(
+        &mut self,
+        request_id: u64,
+        result: Result<Payload, WampError>,
+    ) -> Result<(), Error> {
+        match result {
+            Ok(Payload { args, kw_args }) if !kw_args.is_empty() => {
+                self.send_message(&(YIELD, request_id, Dict::default(), args, kw_args))
+            }
+            Ok(Payload { args, .. }) if args.is_empty() => {
+                self.send_message(&(YIELD, request_id, Dict::default()))
+            }
+            Ok(Payload { args, .. }) => {
+                self.send_message(&(YIELD, request_id, Dict::default(), args))
+            }
+            Err(e) if e.payload == Payload::default() => self.send_message(&(
+                ERROR,
+                INVOCATION,
+                request_id,
+                Dict::default(),
+                e.code.uri(),
+                (e.message,),
+                e.extra,
+            )),
+            Err(e) => self.send_message(&(
+                ERROR,
+                INVOCATION,
+                request_id,
+                Dict::default(),
+                e.code.uri(),
+                e.payload.args,
+                e.payload.kw_args,
+            )),
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        sub_id: u64,
+        pub_id: u64,
+        details: Option<&rmpv::Value>,
+        args: Option<&rmpv::Value>,
+        kwargs: Option<&rmpv::Value>,
+    ) -> Result<(), Error> {
+        // Events published before GOODBYE may still arrive while closing.
+        if self.subscribers().is_err() {
+            log::debug!(
+                "event dropped, session not established: subscription_id={}",
+                sub_id
+            );
+            return Ok(());
+        }
+        if let Some(subscriber) = self.subscribers()?.get_mut(&sub_id) {
+            let extra = to_kw_args(details).unwrap_or_default();
+            let details = pubsub::EventDetails {
+                topic: extra
+                    .get("topic")
+                    .and_then(|topic| topic.as_str())
+                    .unwrap_or(&subscriber.topic)
+                    .to_string(),
+                publisher: extra.get("publisher").and_then(|id| id.as_u64()),
+                publication_id: pub_id,
+                extra,
+            };
+
+            let payload = Payload::from_message(args, kwargs);
+            let message = WampMessage {
+                args: payload.json_args(),
+                kw_args: payload.json_kw_args(),
+                details,
+                payload,
+            };
+            // Consumers closed on buffer overflow will not take more events.
+            subscriber
+                .consumers
+                .retain(|_, tx| tx.send(Ok(message.clone())).is_ok());
+            let unsubscribe = subscriber.consumers.is_empty();
+            self.observe(|observer| {
+                observer.event_delivered(&EventDelivered {
+                    topic: &message.details.topic,
+                    subscription_id: sub_id,
+                    publication_id: pub_id,
+                    size: self.frame_size,
+                })
+            });
+            if unsubscribe {
+                self.subscribers()?.remove(&sub_id);
+                self.send_unsubscribe(sub_id)?;
+            }
+        } else {
+            log::warn!("unhandled event: subscription_id={}", sub_id);
+        }
+        Ok(())
+    }
+
+    fn handle_abort(
+        &mut self,
+        error_uri: &str,
+        extra: &[(rmpv::Value, rmpv::Value)],
+    ) -> Result<Effect, Error> {
+        let error = WampError::from_details(error_uri, extra);
+
+        match std::mem::replace(&mut self.state, ConnectionState::Failed) {
+            ConnectionState::Establishing { tx: Some(tx), .. }
+            | ConnectionState::Authenticating { tx, .. } => {
+                log::debug!("session rejected: {}", error);
+                let _ = tx.send(Err(Error::WampError(Box::new(error))));
+            }
+            state @ ConnectionState::Established { .. } => {
+                log::warn!("session aborted: {}", error);
+                self.state = state;
+                self.end_session(
+                    || Error::WampError(Box::new(error.clone())),
+                    ConnectionState::Failed,
+                );
+                self.emit(SessionEvent::Closed {
+                    reason: CloseReason::Abort {
+                        uri: error_uri.to_string(),
+                        message: error
+                            .extra
+                            .get("message")
+                            .and_then(|v| v.as_str())
+                            .map(Into::into),
+                    },
+                });
+                return Ok(Effect::Shutdown);
+            }
+            _ => (),
+        }
+
+        Ok(Effect::None)
+    }
+
+    // [GOODBYE, Details|dict, Reason|uri]
+    fn handle_goodbye(
+        &mut self,
+        reason_uri: &str,
+        details: &[(rmpv::Value, rmpv::Value)],
+    ) -> Result<Effect, Error> {
+        match std::mem::replace(&mut self.state, ConnectionState::Closed) {
+            ConnectionState::Closing { reason, tx } => {
+                log::debug!("session closed: {}", reason_uri);
+                if let Some(tx) = tx {
+                    let _ = tx.send(Ok(()));
+                }
+                self.emit(SessionEvent::Closed {
+                    reason: CloseReason::Goodbye {
+                        uri: reason,
+                        message: None,
+                        by_router: false,
+                    },
+                });
+            }
+            state @ ConnectionState::Established { .. } => {
+                let error = WampError::from_details(reason_uri, details);
+                log::info!("session closed by router: {}", error);
+                self.state = state;
+                self.emit(SessionEvent::Closing);
+                self.end_session(
+                    || Error::WampError(Box::new(error.clone())),
+                    ConnectionState::Closed,
+                );
+                self.send_message(&(GOODBYE, Dict::new(), session::CLOSE_GOODBYE_AND_OUT))?;
+                self.emit(SessionEvent::Closed {
+                    reason: CloseReason::Goodbye {
+                        uri: reason_uri.to_string(),
+                        message: error
+                            .extra
+                            .get("message")
+                            .and_then(|v| v.as_str())
+                            .map(Into::into),
+                        by_router: true,
+                    },
+                });
+            }
+            state => {
+                self.state = state;
+                return Err(Error::protocol_err("unexpected GOODBYE"));
+            }
+        }
+
+        Ok(Effect::Shutdown)
+    }
+
+    /// Leaves established session, failing everything pending on it with `error`.
+    fn end_session(&mut self, err: impl Fn() -> Error, next_state: ConnectionState) {
+        if let ConnectionState::Established {
+            pending_calls,
+            subscribers,
+            pending_subscriptions,
+            pending_publications,
+            pending_registrations,
+            ..
+        } = std::mem::replace(&mut self.state, next_state)
+        {
+            for (call_id, desc) in pending_calls {
+                self.finish_call(call_id, desc, Err(err()), 0);
+            }
+            for (_subscription_id, subscriber) in subscribers {
+                for tx in subscriber.consumers.values() {
+                    let _ = tx.send(Err(err()));
+                }
+            }
+            for (_request_id, pending) in pending_subscriptions {
+                for tx in pending.waiters {
+                    let _ = tx.send(Err(err()));
+                }
+            }
+            for (_request_id, tx) in pending_publications {
+                let _ = tx.send(Err(err()));
+            }
+            for (_request_id, registration) in pending_registrations {
+                let _ = registration.tx.send(Err(err()));
+            }
+        }
+    }
+
+    fn emit(&mut self, event: SessionEvent) {
+        self.observe(|observer| observer.session_event(&event));
+        self.session_watchers
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        if let SessionEvent::Closed { reason } = event {
+            self.session_watchers.clear();
+            self.close_reason = Some(reason);
+        }
+    }
+
+    /// Sends keepalive ping. Returns false when transport is already closed.
+    pub(crate) fn ping(&mut self) -> bool {
+        self.writer.write(ws::Message::Ping("hb".into())).is_ok()
+    }
+
+    /// Fails everything pending after peer stopped responding to pings.
+    ///
+    /// Driver drops connection afterwards.
+    pub(crate) fn heartbeat_timeout(&mut self) {
+        log::warn!("no heartbeat from peer, dropping connection");
+        self.fail_with(|| Error::HeartbeatTimeout);
+    }
+
+    /// Fails everything pending after transport error, e.g. oversized message.
+    ///
+    /// Driver drops connection afterwards.
+    pub(crate) fn transport_error(&mut self, e: &ws::ProtocolError) {
+        log::error!("protocol error: {}", e);
+        match e {
+            ws::ProtocolError::Overflow => self.fail_with(|| Error::MessageTooLarge),
+            e => {
+                let message = e.to_string();
+                self.fail_with(|| Error::ProtocolError(message.clone().into()))
+            }
+        }
+    }
+
+    fn fail_with(&mut self, err: impl Fn() -> Error) {
+        match std::mem::replace(&mut self.state, ConnectionState::Failed) {
+            ConnectionState::Establishing { tx: Some(tx), .. }
+            | ConnectionState::Authenticating { tx, .. } => {
+                let _ = tx.send(Err(err()));
+            }
+            state @ ConnectionState::Established { .. } => {
+                self.state = state;
+                self.end_session(err, ConnectionState::Failed);
+            }
+            ConnectionState::Closing { tx: Some(tx), .. } => {
+                let _ = tx.send(Err(err()));
+            }
+            _ => (),
+        }
+    }
+
+    /// Sends close frame after session has ended. Driver closes transport afterwards.
+    pub(crate) fn close_transport(&mut self) {
+        let _ = self
+            .writer
+            .write(ws::Message::Close(Some(ws::CloseCode::Normal.into())));
+    }
+
+    /// Marks session failed after transport error.
+    pub(crate) fn fail(&mut self) {
+        self.state = ConnectionState::Failed;
+    }
+
+    /// Reports lost connection to pending close and session watchers.
+    pub(crate) fn connection_lost(&mut self) {
+        if let ConnectionState::Closing { tx: Some(tx), .. } =
+            std::mem::replace(&mut self.state, ConnectionState::Closed)
+        {
+            let _ = tx.send(Err(Error::ConnectionClosed));
+        }
+        if self.close_reason.is_none() {
+            self.emit(SessionEvent::Closed {
+                reason: CloseReason::ConnectionLost,
+            });
+        }
+    }
+
+    // [
+    //      ERROR,
+    //      REQUEST.Type|int,
+    //      REQUEST.Request|id,
+    //      Details|dict,
+    //      Error|uri,
+    //      Arguments|list,
+    // ArgumentsKw|dict]
+    fn handle_error(
+        &mut self,
+        request_type: u64,
+        request_id: u64,
+        details: &rmpv::Value,
+        error_uri: &str,
+        args: &rmpv::Value,
+        kwargs: &rmpv::Value,
+    ) -> Result<(), Error> {
+        match request_type.try_into()? {
+            CALL => self.handle_error_call(request_id, details, error_uri, args, kwargs),
+            SUBSCRIBE => self.handle_error_subscribe(request_id, details, error_uri, args, kwargs),
+            PUBLISH => self.handle_error_publish(request_id, details, error_uri, args, kwargs),
+            REGISTER => self.handle_error_register(request_id, details, error_uri, args, kwargs),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_error_call(
+        &mut self,
+        request_id: u64,
+        _details: &rmpv::Value,
+        error_uri: &str,
+        args: &rmpv::Value,
+        kwargs: &rmpv::Value,
+    ) -> Result<(), Error> {
+        log::info!("handle call: {}", request_id);
+        let calls = match &mut self.state {
+            ConnectionState::Established { pending_calls, .. } => pending_calls,
+            _ => return Ok(()),
+        };
+        if let Some(desc) = calls.remove(&request_id) {
+            let error = Error::from_wamp_error_message(error_uri, args, kwargs);
+            self.finish_call(request_id, desc, Err(error), self.frame_size);
+        } else {
+            log::error!("invalid id");
+        }
+        Ok(())
+    }
+
+    fn handle_error_subscribe(
+        &mut self,
+        request_id: u64,
+        _details: &rmpv::Value,
+        error_uri: &str,
+        args: &rmpv::Value,
+        kwargs: &rmpv::Value,
+    ) -> Result<(), Error> {
+        if let Some(pending) = self.pending_subscriptions()?.remove(&request_id) {
+            for tx in pending.waiters {
+                let _ = tx.send(Err(Error::from_wamp_error_message(error_uri, args, kwargs)));
+            }
+        } else {
+            log::error!("invalid id");
+        }
+        Ok(())
+    }
+
+    fn handle_error_publish(
+        &mut self,
+        request_id: u64,
+        _details: &rmpv::Value,
+        error_uri: &str,
+        args: &rmpv::Value,
+        kwargs: &rmpv::Value,
+    ) -> Result<(), Error> {
+        if let Some(tx) = self.pending_publications()?.remove(&request_id) {
+            let _ = tx.send(Err(Error::from_wamp_error_message(error_uri, args, kwargs)));
+        } else {
+            log::error!("invalid id");
+        }
+        Ok(())
+    }
+
+    fn handle_error_register(
+        &mut self,
+        request_id: u64,
+        _details: &rmpv::Value,
+        error_uri: &str,
+        args: &rmpv::Value,
+        kwargs: &rmpv::Value,
+    ) -> Result<(), Error> {
+        if let Some(PendingRegistration { tx, .. }) =
+            self.pending_registrations()?.remove(&request_id)
+        {
+            let _ = tx.send(Err(Error::from_wamp_error_message(error_uri, args, kwargs)));
+        } else {
+            log::error!("invalid id");
+        }
+        Ok(())
+    }
+
+    /// Handles frame received from router.
+    ///
+    /// Malformed message fails the session with [`Error::ProtocolError`].
+    pub(crate) fn handle_frame(&mut self, frame: ws::Frame) -> Effect {
+        match frame {
+            ws::Frame::Binary(ref data) | ws::Frame::Text(ref data) => {
+                self.frame_size = data.len();
+                let result = serializer::decode_frame(&frame)
+                    .map_err(|e| Error::ProtocolError(format!("invalid message: {}", e).into()))
+                    .and_then(|value| self.handle_message(&value));
+                match result {
+                    Ok(effect) => effect,
+                    Err(e) => {
+                        log::error!("protocol error: {}", e);
+                        let message = e.to_string();
+                        self.fail_with(|| Error::ProtocolError(message.clone().into()));
+                        Effect::Shutdown
+                    }
+                }
+            }
+            ws::Frame::Ping(payload) => {
+                let _ = self.writer.write(ws::Message::Pong(payload));
+                Effect::None
+            }
+            _ => {
+                log::debug!("h={:?}", frame);
+                Effect::None
+            }
+        }
+    }
+
+    fn handle_message(&mut self, value: &rmpv::Value) -> Result<Effect, Error> {
+        log::trace!("got message ={}", value);
+        let message_type = id_field(value, 0)?;
+
+        match message_type.try_into().unwrap_or_default() {
+            WELCOME => {
+                return self.handle_welcome(
+                    id_field(value, 1)?,
+                    to_kw_args(value.as_array().and_then(|a| a.get(2))).unwrap_or_default(),
+                );
+            }
+            ABORT => {
+                // [3, {"message": "WAMP-CRA signature is invalid"}, "wamp.error.not_authorized"]
+                if let Ok(effect) = self.handle_abort(uri_field(value, 2)?, dict_field(value, 1)?) {
+                    return Ok(effect);
+                }
+            }
+            GOODBYE => {
+                match self
+                    .handle_goodbye(value[2].as_str().unwrap_or_default(), dict_field(value, 1)?)
+                {
+                    Ok(effect) => return Ok(effect),
+                    Err(e) => log::error!("fail to handle goodbye: {}", e),
+                }
+            }
+
+            CHALLENGE => {
+                match self.handle_challenge(
+                    uri_field(value, 1)?,
+                    &to_kw_args(Some(&value[2])).unwrap_or_default(),
+                ) {
+                    Ok(effect) => return Ok(effect),
+                    Err(e) => log::error!("auth method failed with: {}", e),
+                }
+            }
+            RESULT => {
+                let args = value.as_array().and_then(|a| a.get(3));
+                let kwargs = value.as_array().and_then(|a| a.get(4));
+                let _ = self.handle_result(id_field(value, 1)?, &value[2], args, kwargs);
+            }
+            SUBSCRIBED => {
+                let request_id = id_field(value, 1)?;
+                let subscription_id = id_field(value, 2)?;
+                let _ = self.handle_subscribed(request_id, subscription_id);
+            }
+            PUBLISHED => {
+                let request_id = id_field(value, 1)?;
+                let publication_id = id_field(value, 2)?;
+                let _ = self.handle_published(request_id, publication_id);
+            }
+            REGISTERED => {
+                let request_id = id_field(value, 1)?;
+                let registration_id = id_field(value, 2)?;
+                let _ = self.handle_registered(request_id, registration_id);
+            }
+            UNSUBSCRIBED => {
+                log::debug!("unsubscribed: request_id={}", value[1]);
+            }
+            UNREGISTERED => {
+                log::debug!("unregistered: request_id={}", value[1]);
+            }
+            INVOCATION => {
+                let request_id = id_field(value, 1)?;
+                let registration_id = id_field(value, 2)?;
+                let args = value.as_array().and_then(|a| a.get(4));
+                let kwargs = value.as_array().and_then(|a| a.get(5));
+                match self.handle_invocation(registration_id, &value[3], args, kwargs) {
+                    Ok(reply) => return Ok(Effect::Invocation(request_id, reply)),
+                    Err(e) => {
+                        let _ = self.send_invocation_result(request_id, Err(*e));
+                    }
+                }
+            }
+
+            EVENT => {
+                //[EVENT, SUBSCRIBED.Subscription|id, PUBLISHED.Publication|id, Details|dict, PUBLISH.Arguments|list, PUBLISH.ArgumentKw|dict]
+                let subscription_id = id_field(value, 1)?;
+                let publication_id = id_field(value, 2)?;
+                let details = value.as_array().and_then(|a| a.get(3));
+                let args = value.as_array().and_then(|a| a.get(4));
+                let kwargs = value.as_array().and_then(|a| a.get(5));
+                if let Err(e) =
+                    self.handle_event(subscription_id, publication_id, details, args, kwargs)
+                {
+                    log::error!("fail to handle event: {}", e);
+                }
+            }
+            ERROR => {
+                // There are 2 formats
+                // [
+                //      ERROR,
+                //      REQUEST.Type|int,
+                //      REQUEST.Request|id,
+                //      Details|dict,
+                //      Error|uri,
+                //      Arguments|list,
+                // ArgumentsKw|dict]
+                log::trace!("got error");
+                let _ = self.handle_error(
+                    id_field(value, 1)?,
+                    id_field(value, 2)?,
+                    &value[3],
+                    uri_field(value, 4)?,
+                    &value[5],
+                    &value[6],
+                );
+            }
+            _ => {}
+        }
+        Ok(Effect::None)
+    }
+
+    /// Sends HELLO, returned future resolves to session id.
+    pub(crate) fn open(
+        &mut self,
+        OpenSession {
+            realm_id,
+            auth_id,
+            mut auth_methods,
+        }: OpenSession,
+    ) -> LocalBoxFuture<'static, Result<u64, Error>> {
+        use crate::messages::{HelloSpec, Role, RoleDesc};
+
+        // check state
+        match self.state {
+            ConnectionState::Closed => (),
+            _ => {
+                return Box::pin(future::err(Error::InvalidState(
+                    "session is already opened or operation pending",
+                )))
+            }
+        }
+
+        let mut auth_extra = Dict::new();
+        if let Some(auth_id) = &auth_id {
+            for method in &mut auth_methods {
+                match method.hello_extra(auth_id) {
+                    Ok(extra) => auth_extra.extend(extra),
+                    Err(e) => return Box::pin(future::err(e)),
+                }
+            }
+        }
+
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let auth_methods_id = auth_methods.iter().map(|method| method.auth_method());
+
+        let auth_id_ref = auth_id.as_deref();
+
+        let _ = self.send_message(&(
+            HELLO,
+            realm_id,
+            HelloSpec {
+                roles: vec![
+                    (
+                        Role::Caller,
+                        RoleDesc::with_features(&[
+                            "call_canceling",
+                            "call_timeout",
+                            "progressive_call_results",
+                        ]),
+                    ),
+                    (Role::Callee, RoleDesc::default()),
+                    (Role::Publisher, RoleDesc::default()),
+                    (Role::Subscriber, RoleDesc::default()),
+                ]
+                .into_iter()
+                .collect(),
+                auth_methods: auth_methods_id.collect(),
+                authid: auth_id_ref,
+                auth_extra,
+            },
+        ));
+        self.state = ConnectionState::Establishing {
+            auth: auth_methods,
+            auth_id,
+            tx: Some(tx),
+        };
+
+        Box::pin(rx.then(|r| match r {
+            Err(_e) => future::err(Error::ConnectionClosed),
+            Ok(resp) => future::ready(resp),
+        }))
+    }
+
+    pub(crate) fn publish(
+        &mut self,
+        msg: Publish,
+    ) -> LocalBoxFuture<'static, Result<Option<u64>, Error>> {
+        let Publish {
+            topic,
+            args,
+            kw_args,
+            options,
+        } = msg;
+        let request_id = gen_id();
+
+        let rx = if options.acknowledge {
+            let (tx, rx) = oneshot::channel();
+            match self.pending_publications() {
+                Ok(pending_publications) => pending_publications.insert(request_id, tx),
+                Err(e) => return Box::pin(future::err(e)),
+            };
+            Some(rx)
+        } else if let Err(e) = self.pending_publications() {
+            return Box::pin(future::err(e));
+        } else {
+            None
+        };
+
+        let result = match kw_args {
+            Some(kw_args) => {
+                self.send_message(&(PUBLISH, request_id, options, topic.as_ref(), args, kw_args))
+            }
+            None if args.is_empty() => {
+                self.send_message(&(PUBLISH, request_id, options, topic.as_ref()))
+            }
+            None => self.send_message(&(PUBLISH, request_id, options, topic.as_ref(), args)),
+        };
+
+        if let Err(e) = result {
+            let _ = self.pending_publications().map(|p| p.remove(&request_id));
+            return Box::pin(future::err(e));
+        }
+
+        match rx {
+            Some(rx) => Box::pin(async move { rx.await.map_err(|_| Error::ConnectionClosed)? }),
+            None => Box::pin(future::ok(None)),
+        }
+    }
+
+    /// Adds consumer of topic, sending SUBSCRIBE unless broker subscription already exists.
+    ///
+    /// `unsubscribe` is called when returned subscription is dropped.
+    pub(crate) fn subscribe(
+        &mut self,
+        msg: Subscribe,
+        unsubscribe: Notify<Unsubscribe>,
+    ) -> LocalBoxFuture<'static, Result<Subscription, Error>> {
+        let match_policy = msg.options.match_policy;
+        let consumer_id = gen_id();
+        let (tx, stream) = pubsub::event_queue(msg.options.buffer.clone());
+
+        let subscribers = match self.subscribers() {
+            Ok(subscribers) => subscribers,
+            Err(e) => return Box::pin(future::err(e)),
+        };
+        if let Some((&subscription_id, subscriber)) = subscribers
+            .iter_mut()
+            .find(|(_, s)| s.topic == msg.topic && s.match_policy == match_policy)
+        {
+            subscriber.consumers.insert(consumer_id, tx);
+            return Box::pin(future::ok(Subscription {
+                subscription_id,
+                consumer_id,
+                stream,
+                connection: unsubscribe,
+            }));
+        }
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let pending_subscriptions = self.pending_subscriptions().unwrap();
+        if let Some(pending) = pending_subscriptions
+            .values_mut()
+            .find(|p| p.topic == msg.topic && p.match_policy == match_policy)
+        {
+            pending.consumers.insert(consumer_id, tx);
+            pending.waiters.push(done_tx);
+        } else {
+            let request_id = gen_id();
+            pending_subscriptions.insert(
+                request_id,
+                PendingSubscription {
+                    topic: msg.topic.to_string(),
+                    match_policy,
+                    consumers: std::iter::once((consumer_id, tx)).collect(),
+                    waiters: vec![done_tx],
+                },
+            );
+            if let Err(e) =
+                self.send_message(&(SUBSCRIBE, request_id, &msg.options, msg.topic.as_ref()))
+            {
+                let _ = self.pending_subscriptions().unwrap().remove(&request_id);
+                return Box::pin(future::err(e));
+            }
+        }
+
+        Box::pin(async move {
+            let subscription_id = done_rx.await??;
+            Ok(Subscription {
+                subscription_id,
+                consumer_id,
+                stream,
+                connection: unsubscribe,
+            })
+        })
+    }
+
+    pub(crate) fn unsubscribe(&mut self, msg: Unsubscribe) {
+        let subscribers = match self.subscribers() {
+            Ok(subscribers) => subscribers,
+            Err(_) => return,
+        };
+        // Subscription id may be reused by broker, so consumer is matched as well.
+        let last_consumer = match subscribers.get_mut(&msg.subscription_id) {
+            Some(subscriber) => {
+                subscriber.consumers.remove(&msg.consumer_id).is_some()
+                    && subscriber.consumers.is_empty()
+            }
+            None => false,
+        };
+        if last_consumer {
+            subscribers.remove(&msg.subscription_id);
+            if let Err(e) = self.send_unsubscribe(msg.subscription_id) {
+                log::warn!("failed to unsubscribe {}: {}", msg.subscription_id, e);
+            }
+        }
+    }
+
+    fn send_unsubscribe(&mut self, subscription_id: u64) -> Result<(), Error> {
+        self.observe(|observer| {
+            observer.subscription_changed(&SubscriptionChange::Unsubscribed { subscription_id })
+        });
+        self.send_message(&(UNSUBSCRIBE, gen_id(), subscription_id))
+    }
+
+    /// Sends REGISTER. `unregister` is called when returned registration is dropped.
+    pub(crate) fn register(
+        &mut self,
+        msg: Register,
+        unregister: Notify<Unregister>,
+    ) -> LocalBoxFuture<'static, Result<Registration, Error>> {
+        let (tx, rx) = oneshot::channel();
+
+        let request_id = gen_id();
+
+        match self.pending_registrations() {
+            Ok(pending_registrations) => pending_registrations.insert(
+                request_id,
+                PendingRegistration {
+                    tx,
+                    handler: msg.handler,
+                },
+            ),
+            Err(e) => return Box::pin(future::err(e)),
+        };
+
+        if let Err(e) =
+            self.send_message(&(REGISTER, request_id, Dict::default(), msg.uri.as_ref()))
+        {
+            let _ = self.pending_registrations().map(|p| p.remove(&request_id));
+            return Box::pin(future::err(e));
+        }
+
+        Box::pin(async move {
+            Ok(Registration {
+                registration_id: rx.await??,
+                connection: unregister,
+            })
+        })
+    }
+
+    pub(crate) fn unregister(&mut self, msg: Unregister) {
+        let removed = self
+            .registrations()
+            .map(|r| r.remove(&msg.registration_id).is_some())
+            .unwrap_or(false);
+
+        if removed {
+            let request_id = gen_id();
+            if let Err(e) = self.send_message(&(UNREGISTER, request_id, msg.registration_id)) {
+                log::warn!("unable to unregister {}: {}", msg.registration_id, e);
+            }
+        }
+    }
+
+    /// Starts GOODBYE handshake, returned future resolves when router replies.
+    ///
+    /// Driver has to call [`close_timeout`](#method.close_timeout) after [`CLOSE_TIMEOUT`].
+    /// Fails when GOODBYE could not be sent, driver should stop then.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn close(
+        &mut self,
+        reason: &str,
+    ) -> Result<LocalBoxFuture<'static, Result<(), Error>>, Error> {
+        if !matches!(self.state, ConnectionState::Established { .. }) {
+            return Ok(Box::pin(future::err(Error::InvalidState(
+                "session is not established",
+            ))));
+        }
+        let error = WampError::with_message(ErrorKind::from_uri(reason), "session closed");
+        let (tx, rx) = oneshot::channel();
+
+        self.emit(SessionEvent::Closing);
+        self.end_session(
+            || Error::WampError(Box::new(error.clone())),
+            ConnectionState::Closing {
+                reason: reason.to_string(),
+                tx: Some(tx),
+            },
+        );
+        if let Err(e) = self.send_message(&(GOODBYE, Dict::new(), reason)) {
+            self.state = ConnectionState::Failed;
+            return Err(e);
+        }
+
+        Ok(Box::pin(async move {
+            rx.await.map_err(|_| Error::ConnectionClosed)?
+        }))
+    }
+
+    /// Gives up waiting for GOODBYE reply. Returns true when transport should be closed.
+    pub(crate) fn close_timeout(&mut self) -> bool {
+        if !matches!(self.state, ConnectionState::Closing { .. }) {
+            return false;
+        }
+        if let ConnectionState::Closing { reason, tx } =
+            std::mem::replace(&mut self.state, ConnectionState::Closed)
+        {
+            log::warn!("no GOODBYE reply from router");
+            if let Some(tx) = tx {
+                let _ = tx.send(Err(Error::Timeout));
+            }
+            self.emit(SessionEvent::Closed {
+                reason: CloseReason::Goodbye {
+                    uri: reason,
+                    message: None,
+                    by_router: false,
+                },
+            });
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn session_info(&self) -> Result<SessionInfo, Error> {
+        match &self.state {
+            ConnectionState::Established { info, .. } => Ok(info.clone()),
+            _ => Err(Error::InvalidState("session is not established")),
+        }
+    }
+
+    /// Stream of session events, starting with current state.
+    pub(crate) fn watch(&mut self) -> mpsc::UnboundedReceiver<SessionEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        let current = match (&self.state, &self.close_reason) {
+            (_, Some(reason)) => Some(SessionEvent::Closed {
+                reason: reason.clone(),
+            }),
+            (ConnectionState::Established { info, .. }, _) => Some(SessionEvent::Established {
+                session_id: info.session_id,
+            }),
+            (ConnectionState::Closing { .. }, _) => Some(SessionEvent::Closing),
+            _ => None,
+        };
+        if let Some(event) = current {
+            let _ = tx.unbounded_send(event);
+        }
+        if self.close_reason.is_none() {
+            self.session_watchers.push(tx);
+        }
+        rx
+    }
+}
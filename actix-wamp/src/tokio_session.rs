@@ -0,0 +1,649 @@
+//! Session driven by plain future instead of actix actor.
+//!
+//! [`SessionBuilder::create_driven`](crate::SessionBuilder::create_driven) returns
+//! [`SessionDriver`] together with future of [`TokioSession`] handle. Driver runs
+//! [`SessionCore`] and has to be polled for session to make progress, e.g. spawned with
+//! `tokio::task::spawn_local` or joined with application future. Timers need tokio runtime,
+//! but no actix `System`. Transport is another matter: [`rawsocket`](crate::rawsocket)
+//! needs tokio only, while websocket transports ([`ws`](crate::ws), [`wss`](crate::wss))
+//! are built on `awc` and need actix `System` running.
+//!
+//! Driver is not `Send` (invocation handlers and replies are local futures), so on
+//! multi-threaded runtime it has to run inside `tokio::task::LocalSet`.
+
+use crate::args::*;
+use crate::error::Error;
+use crate::messages::{Dict, WampError};
+use crate::observer::SessionObserver;
+use crate::payload::Payload;
+use crate::pubsub::{
+    Publish, PublishOptions, Subscribe, SubscribeOptions, Unsubscribe, WampMessage,
+};
+use crate::registry::{Invocation, Register, Registration, RpcRegistry, Unregister};
+use crate::serializer::Serializer;
+use crate::session::{SessionEndpoint, SessionEvent, SessionInfo};
+use crate::session_core::{
+    gen_id, Effect, Heartbeat, Notify, OpenSession, SessionCore, CLOSE_TIMEOUT,
+};
+use crate::PubSubEndpoint;
+use actix_http::ws;
+use futures::channel::{mpsc, oneshot};
+use futures::future::LocalBoxFuture;
+use futures::prelude::*;
+use futures::stream::{FusedStream, FuturesUnordered};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Request from [`TokioSession`] handle, run by driver on its state.
+type Command = Box<dyn FnOnce(&mut Driver)>;
+
+/// Messages from dropped handles. Unlike commands they have to be `Send`.
+enum Release {
+    CancelCall(CancelCall),
+    Unsubscribe(Unsubscribe),
+    Unregister(Unregister),
+}
+
+enum Timer {
+    CallTimeout(u64),
+    CloseTimeout,
+    PingTimeout(Instant),
+    Stop,
+}
+
+fn after(duration: Duration, timer: Timer) -> LocalBoxFuture<'static, Timer> {
+    tokio::time::delay_for(duration)
+        .map(move |()| timer)
+        .boxed_local()
+}
+
+fn notify<M: Send + 'static>(
+    releases: &mpsc::UnboundedSender<Release>,
+    release: fn(M) -> Release,
+) -> Notify<M> {
+    let releases = releases.clone();
+    Box::new(move |msg| {
+        let _ = releases.unbounded_send(release(msg));
+    })
+}
+
+struct Driver {
+    core: SessionCore<VecDeque<ws::Message>>,
+    timers: FuturesUnordered<LocalBoxFuture<'static, Timer>>,
+    /// Session has ended, transport is to be closed.
+    shutdown: bool,
+    /// Transport failed, driver stops without closing handshake.
+    stop: bool,
+}
+
+impl Driver {
+    fn watch_timeout(&mut self, request_id: u64, timeout: Option<Duration>) {
+        if let Some(timeout) = timeout {
+            self.timers
+                .push(after(timeout, Timer::CallTimeout(request_id)));
+        }
+    }
+
+    fn call(&mut self, request_id: u64, request: RpcCallRequest) -> Result<CallReceiver, Error> {
+        let timeout = request.timeout;
+        let rx = self.core.call(request_id, request)?;
+        self.watch_timeout(request_id, timeout);
+        Ok(rx)
+    }
+
+    fn call_progressive(
+        &mut self,
+        request_id: u64,
+        request: RpcCallRequest,
+    ) -> Result<ProgressiveReceiver, Error> {
+        let timeout = request.timeout;
+        let rx = self.core.call_progressive(request_id, request)?;
+        self.watch_timeout(request_id, timeout);
+        Ok(rx)
+    }
+
+    fn close(&mut self, reason: &str) -> LocalBoxFuture<'static, Result<(), Error>> {
+        match self.core.close(reason) {
+            Ok(closing) => {
+                self.timers.push(after(CLOSE_TIMEOUT, Timer::CloseTimeout));
+                closing
+            }
+            Err(e) => {
+                self.stop = true;
+                Box::pin(future::err(e))
+            }
+        }
+    }
+
+    fn release(&mut self, release: Release) {
+        match release {
+            Release::CancelCall(CancelCall { request_id }) => {
+                if let Err(e) = self.core.cancel_call(request_id, None) {
+                    log::debug!("unable to cancel call {}: {}", request_id, e);
+                }
+            }
+            Release::Unsubscribe(msg) => self.core.unsubscribe(msg),
+            Release::Unregister(msg) => self.core.unregister(msg),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if !self.shutdown {
+            self.shutdown = true;
+            self.core.close_transport();
+        }
+    }
+}
+
+/// Future running session, see [module documentation](index.html).
+///
+/// Completes when connection is closed, lost, or all handles are dropped.
+#[must_use = "session makes no progress unless driver is polled"]
+pub struct SessionDriver {
+    inner: LocalBoxFuture<'static, ()>,
+}
+
+impl Future for SessionDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.inner.poll_unpin(cx)
+    }
+}
+
+/// Handle of session run by [`SessionDriver`].
+///
+/// Calls fail with [`Error::ConnectionClosed`] once driver is gone. Over websocket
+/// transport actix `System` is still needed, see
+/// [`SessionBuilder::create_driven`](crate::SessionBuilder::create_driven).
+#[derive(Clone)]
+pub struct TokioSession {
+    commands: mpsc::UnboundedSender<Command>,
+    releases: mpsc::UnboundedSender<Release>,
+}
+
+impl TokioSession {
+    /// Runs `f` on driver state, resolves to its result.
+    fn request<T: 'static>(
+        &self,
+        f: impl FnOnce(&mut Driver) -> T + 'static,
+    ) -> impl Future<Output = Result<T, Error>> {
+        self.command(f, false)
+    }
+
+    /// Like [`request`](Self::request), but drops `f` when caller is gone by the time
+    /// driver gets to it. Its cancel may have been handled already, as releases
+    /// are not ordered with commands.
+    fn request_call<T: 'static>(
+        &self,
+        f: impl FnOnce(&mut Driver) -> Result<T, Error> + 'static,
+    ) -> impl Future<Output = Result<T, Error>> {
+        self.command(f, true).and_then(future::ready)
+    }
+
+    fn command<T: 'static>(
+        &self,
+        f: impl FnOnce(&mut Driver) -> T + 'static,
+        skip_when_dropped: bool,
+    ) -> impl Future<Output = Result<T, Error>> {
+        let (tx, rx) = oneshot::channel();
+        let sent = self
+            .commands
+            .unbounded_send(Box::new(move |driver: &mut Driver| {
+                if skip_when_dropped && tx.is_canceled() {
+                    return;
+                }
+                let _ = tx.send(f(driver));
+            }))
+            .is_ok();
+        async move {
+            if !sent {
+                return Err(Error::ConnectionClosed);
+            }
+            rx.await.map_err(|_| Error::ConnectionClosed)
+        }
+    }
+
+    pub(crate) fn open(self, msg: OpenSession) -> impl Future<Output = Result<Self, Error>> {
+        self.request(move |driver| driver.core.open(msg))
+            .and_then(|opening| opening)
+            .map_ok(move |_session_id| self)
+    }
+}
+
+pub(crate) fn connect<Transport>(
+    transport: Transport,
+    serializer: Serializer,
+    heartbeat: Option<Heartbeat>,
+    observers: Vec<Arc<dyn SessionObserver>>,
+) -> (TokioSession, SessionDriver)
+where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + 'static,
+{
+    let (commands_tx, commands) = mpsc::unbounded();
+    let (releases_tx, releases) = mpsc::unbounded();
+    let driver = Driver {
+        core: SessionCore::new(VecDeque::new(), serializer).with_observers(observers),
+        timers: FuturesUnordered::new(),
+        shutdown: false,
+        stop: false,
+    };
+
+    (
+        TokioSession {
+            commands: commands_tx,
+            releases: releases_tx,
+        },
+        SessionDriver {
+            inner: drive(transport, driver, commands, releases, heartbeat).boxed_local(),
+        },
+    )
+}
+
+/// Sends queued frames.
+async fn flush<S>(sink: &mut S, outbox: &mut VecDeque<ws::Message>) -> Result<(), S::Error>
+where
+    S: Sink<ws::Message> + Unpin,
+{
+    if outbox.is_empty() {
+        return Ok(());
+    }
+    while let Some(msg) = outbox.pop_front() {
+        sink.feed(msg).await?;
+    }
+    sink.flush().await
+}
+
+enum Event {
+    Frame(Option<Result<ws::Frame, ws::ProtocolError>>),
+    Command(Option<Command>),
+    Release(Option<Release>),
+    Timer(Timer),
+    InvocationResult(u64, Result<Payload, WampError>),
+    Heartbeat,
+}
+
+async fn drive<Transport>(
+    transport: Transport,
+    mut driver: Driver,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    mut releases: mpsc::UnboundedReceiver<Release>,
+    heartbeat: Option<Heartbeat>,
+) where
+    Transport: Sink<ws::Message, Error = ws::ProtocolError>
+        + Stream<Item = Result<ws::Frame, ws::ProtocolError>>
+        + 'static,
+{
+    let (mut sink, stream) = transport.split();
+    let mut stream = stream.fuse();
+    let mut heartbeats = match heartbeat {
+        Some(heartbeat) => tokio::time::interval_at(
+            tokio::time::Instant::now() + heartbeat.interval,
+            heartbeat.interval,
+        )
+        .map(|_| ())
+        .boxed_local(),
+        None => stream::pending().boxed_local(),
+    }
+    .fuse();
+    let mut invocations = FuturesUnordered::new();
+    let mut last_seen = Instant::now();
+    let mut transport_closed = false;
+
+    loop {
+        if transport_closed {
+            driver.core.writer_mut().clear();
+        } else if let Err(e) = flush(&mut sink, driver.core.writer_mut()).await {
+            log::error!("protocol error: {}", e);
+            driver.core.fail();
+            break;
+        }
+        if driver.stop {
+            break;
+        }
+        if driver.shutdown && !transport_closed {
+            transport_closed = true;
+            let _ = sink.close().await;
+            // stop even if peer does not close its side.
+            driver.timers.push(after(CLOSE_TIMEOUT, Timer::Stop));
+        }
+
+        let event = futures::select! {
+            frame = stream.next() => Event::Frame(frame),
+            command = commands.next() => Event::Command(command),
+            release = releases.next() => Event::Release(release),
+            timer = driver.timers.select_next_some() => Event::Timer(timer),
+            (request_id, result) = invocations.select_next_some() => {
+                Event::InvocationResult(request_id, result)
+            }
+            () = heartbeats.select_next_some() => Event::Heartbeat,
+        };
+
+        match event {
+            Event::Frame(Some(Ok(frame))) => {
+                last_seen = Instant::now();
+                match driver.core.handle_frame(frame) {
+                    Effect::None => (),
+                    Effect::Invocation(request_id, reply) => {
+                        invocations.push(reply.map(move |result| (request_id, result)))
+                    }
+                    Effect::Shutdown => driver.shutdown(),
+                }
+            }
+            Event::Frame(Some(Err(e))) => {
+                driver.core.transport_error(&e);
+                break;
+            }
+            Event::Frame(None) => break,
+            Event::Command(Some(command)) => command(&mut driver),
+            Event::Release(Some(release)) => driver.release(release),
+            // handles, subscriptions and registrations are all gone.
+            Event::Command(None) | Event::Release(None) => {
+                if commands.is_terminated() && releases.is_terminated() {
+                    break;
+                }
+            }
+            Event::Timer(Timer::CallTimeout(request_id)) => {
+                if let Err(e) = driver.core.cancel_call(request_id, Some(Error::Timeout)) {
+                    log::debug!("unable to cancel call {}: {}", request_id, e);
+                }
+            }
+            Event::Timer(Timer::CloseTimeout) => {
+                if driver.core.close_timeout() {
+                    driver.shutdown();
+                }
+            }
+            Event::Timer(Timer::PingTimeout(sent)) => {
+                if last_seen < sent {
+                    driver.core.heartbeat_timeout();
+                    break;
+                }
+            }
+            Event::Timer(Timer::Stop) => break,
+            Event::InvocationResult(request_id, result) => {
+                if let Err(e) = driver.core.send_invocation_result(request_id, result) {
+                    log::error!("unable to send invocation result: {}", e);
+                }
+            }
+            Event::Heartbeat => {
+                if let Some(heartbeat) = heartbeat {
+                    let sent = Instant::now();
+                    if driver.core.ping() {
+                        driver
+                            .timers
+                            .push(after(heartbeat.timeout, Timer::PingTimeout(sent)));
+                    }
+                }
+            }
+        }
+    }
+
+    log::debug!("connection stopped");
+    driver.core.connection_lost();
+}
+
+impl RpcEndpoint for TokioSession {
+    type Response = Pin<Box<dyn Future<Output = Result<RpcCallResponse, Error>> + 'static>>;
+
+    fn rpc_call(&self, request: RpcCallRequest) -> Self::Response {
+        self.rpc_call_payload(request)
+            .map_ok(RpcCallResponse::from)
+            .boxed_local()
+    }
+
+    fn rpc_call_payload(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Future<Output = Result<Payload, Error>> + 'static>> {
+        let request_id = gen_id();
+        // dropping response future before result arrives cancels call.
+        let guard = CancelOnDrop::new(request_id, notify(&self.releases, Release::CancelCall));
+        let started = self.request_call(move |driver| driver.call(request_id, request));
+
+        async move {
+            let result = match started.await {
+                Ok(rx) => rx.await.map_err(|_| Error::ConnectionClosed),
+                Err(e) => Err(e),
+            };
+            guard.disarm();
+            result?
+        }
+        .boxed_local()
+    }
+
+    fn rpc_call_progressive(
+        &self,
+        request: RpcCallRequest,
+    ) -> Pin<Box<dyn Stream<Item = Result<RpcCallResponse, Error>> + 'static>> {
+        let request_id = gen_id();
+        let guard = CancelOnDrop::new(request_id, notify(&self.releases, Release::CancelCall));
+
+        self.request_call(move |driver| driver.call_progressive(request_id, request))
+            .map(move |started| match started {
+                Ok(rx) => Ok(ProgressiveResults::new(rx, guard)),
+                Err(e) => {
+                    guard.disarm();
+                    Err(e)
+                }
+            })
+            .try_flatten_stream()
+            .map_ok(RpcCallResponse::from)
+            .boxed_local()
+    }
+}
+
+impl PubSubEndpoint for TokioSession {
+    type Events = Pin<Box<dyn Stream<Item = Result<WampMessage, Error>> + 'static>>;
+    type Published = Pin<Box<dyn Future<Output = Result<Option<u64>, Error>> + 'static>>;
+
+    fn subscribe_with(&self, uri: &str, options: SubscribeOptions) -> Self::Events {
+        let msg = Subscribe {
+            topic: Cow::Owned(uri.into()),
+            options,
+        };
+        let unsubscribe = notify(&self.releases, Release::Unsubscribe);
+
+        self.request(move |driver| driver.core.subscribe(msg, unsubscribe))
+            .and_then(|subscription| subscription)
+            .try_flatten_stream()
+            .boxed_local()
+    }
+
+    fn publish(
+        &self,
+        topic: &str,
+        args: Vec<serde_json::Value>,
+        kw_args: Option<Dict>,
+        options: PublishOptions,
+    ) -> Self::Published {
+        let msg = Publish {
+            topic: Cow::Owned(topic.into()),
+            args,
+            kw_args,
+            options,
+        };
+
+        self.request(move |driver| driver.core.publish(msg))
+            .and_then(|published| published)
+            .boxed_local()
+    }
+}
+
+impl RpcRegistry for TokioSession {
+    type Registration = Pin<Box<dyn Future<Output = Result<Registration, Error>> + 'static>>;
+
+    fn register<Handler, Reply, Response>(&self, uri: &str, handler: Handler) -> Self::Registration
+    where
+        Handler: Fn(Invocation) -> Reply + Send + 'static,
+        Reply: Future<Output = Result<Response, WampError>> + 'static,
+        Response: Into<Payload> + 'static,
+    {
+        let msg = Register {
+            uri: Cow::Owned(uri.into()),
+            handler: Box::new(move |invocation| {
+                handler(invocation).map_ok(Into::into).boxed_local()
+            }),
+        };
+        let unregister = notify(&self.releases, Release::Unregister);
+
+        self.request(move |driver| driver.core.register(msg, unregister))
+            .and_then(|registration| registration)
+            .boxed_local()
+    }
+}
+
+impl SessionEndpoint for TokioSession {
+    type Closing = Pin<Box<dyn Future<Output = Result<(), Error>> + 'static>>;
+    type Closed = Pin<Box<dyn Future<Output = ()> + 'static>>;
+    type SessionEvents = Pin<Box<dyn Stream<Item = SessionEvent> + 'static>>;
+    type SessionInfo = Pin<Box<dyn Future<Output = Result<SessionInfo, Error>> + 'static>>;
+
+    fn close(&self, reason: &str) -> Self::Closing {
+        let reason = reason.to_string();
+
+        self.request(move |driver| driver.close(&reason))
+            .and_then(|closing| closing)
+            .boxed_local()
+    }
+
+    fn closed(&self) -> Self::Closed {
+        self.session_events()
+            .filter(|event| future::ready(matches!(event, SessionEvent::Closed { .. })))
+            .into_future()
+            .map(|_| ())
+            .boxed_local()
+    }
+
+    fn session_events(&self) -> Self::SessionEvents {
+        // stream is empty when driver is already gone.
+        self.request(|driver| driver.core.watch())
+            .map(Result::ok)
+            .into_stream()
+            .filter_map(future::ready)
+            .flatten()
+            .boxed_local()
+    }
+
+    fn session_info(&self) -> Self::SessionInfo {
+        self.request(|driver| driver.core.session_info())
+            .and_then(future::ready)
+            .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::test::Peer;
+    use crate::messages::types::*;
+    use crate::SessionBuilder;
+    use serde_json::json;
+
+    #[test]
+    fn test_without_actix_system() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let (transport, mut peer) = Peer::pair();
+        let (driver, session) = SessionBuilder::anonymous("realm".into())
+            .with_serializers(&[Serializer::Json])
+            .create_driven(transport);
+
+        let client = async move {
+            let session = session.await?;
+            let mut events = session.subscribe("evt.a");
+            let event = events.next().await.unwrap()?;
+            assert_eq!(event.args, vec![json!("x")]);
+            let response = session
+                .rpc_call(RpcCallRequest::with_va_args("test.echo", &vec![1])?)
+                .await?;
+            assert_eq!(response.args, vec![json!(1)]);
+            let closed = session.closed();
+            session.close(crate::CLOSE_NORMAL).await?;
+            closed.await;
+            Ok::<_, Error>(())
+        };
+        let router = async move {
+            assert_eq!(peer.recv().await[0], json!(HELLO));
+            peer.send(json!([WELCOME, 1, {}]));
+
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(SUBSCRIBE));
+            peer.send(json!([SUBSCRIBED, request[1], 7]));
+            peer.send(json!([EVENT, 7, 100, {}, ["x"]]));
+
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(CALL));
+            assert_eq!(request[3], json!("test.echo"));
+            peer.send(json!([RESULT, request[1], {}, request[4]]));
+
+            let request = peer.recv().await;
+            assert_eq!(request[0], json!(GOODBYE));
+            peer.send(json!([GOODBYE, {}, crate::CLOSE_NORMAL]));
+            peer
+        };
+
+        let (result, _peer, ()) =
+            runtime.block_on(async { futures::join!(client, router, driver) });
+        result.unwrap();
+    }
+
+    #[test]
+    fn test_cancel_on_drop() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let (transport, mut peer) = Peer::pair();
+        let (driver, session) = SessionBuilder::anonymous("realm".into())
+            .with_serializers(&[Serializer::Json])
+            .create_driven(transport);
+        let (called_tx, called) = oneshot::channel();
+        let (done_tx, done) = oneshot::channel();
+
+        let client = async move {
+            let session = session.await?;
+            let request =
+                RpcCallRequest::with_no_args("test.slow").with_cancel_mode(CancelMode::Skip);
+            let result = session.rpc_call(request.clone());
+            called.await.unwrap();
+            drop(result);
+
+            // Dropped before driver got to it, call is not even started.
+            drop(session.rpc_call(request));
+            let _result = session.rpc_call(RpcCallRequest::with_no_args("test.next"));
+            done.await.unwrap();
+            Ok::<_, Error>(())
+        };
+        let router = async move {
+            assert_eq!(peer.recv().await[0], json!(HELLO));
+            peer.send(json!([WELCOME, 1, {}]));
+
+            let call = peer.recv().await;
+            assert_eq!(call[0], json!(CALL));
+            called_tx.send(()).unwrap();
+            assert_eq!(
+                peer.recv().await,
+                json!([CANCEL, call[1], {"mode": "skip"}])
+            );
+            let call = peer.recv().await;
+            assert_eq!(call[0], json!(CALL));
+            assert_eq!(call[3], json!("test.next"));
+            done_tx.send(()).unwrap();
+            peer
+        };
+
+        let (result, _peer, ()) =
+            runtime.block_on(async { futures::join!(client, router, driver) });
+        result.unwrap();
+    }
+}
@@ -1,12 +1,83 @@
+use crate::serializer::Serializer;
+use crate::session_core::Heartbeat;
 use actix_http::ws;
 use awc::error::WsClientError;
+use awc::error::{ConnectError, SendRequestError};
 use awc::*;
 use futures::prelude::*;
-use openssl::ssl;
+use openssl::ssl::SslConnector;
+use proxy::{ProxyConnector, ProxySettings};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use ws_codec::WsCodec;
+
+mod proxy;
+pub mod rawsocket;
+pub mod record;
+mod tls;
+mod ws_codec;
+
+pub use proxy::Proxy;
+pub use tls::TlsPolicy;
+
+// Covers proxy handshake too, direct connections keep awc default.
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default limit of incoming frame and message size (100 MiB).
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 100 * 1024 * 1024;
 
 pub type ClientError = WsClientError;
 
+/// Plain websocket transport offering given serializers, in order of preference.
+///
+/// Returns serializer selected by server together with transport.
+pub fn ws(
+    host: &str,
+    port: u16,
+    serializers: &[Serializer],
+) -> impl Future<
+    Output = Result<
+        (
+            impl Sink<ws::Message, Error = ws::ProtocolError>
+                + Stream<Item = Result<ws::Frame, ws::ProtocolError>>,
+            Serializer,
+        ),
+        WsClientError,
+    >,
+> + 'static {
+    ws_with_options(
+        host,
+        port,
+        &WsOptions::default().with_serializers(serializers),
+    )
+}
+
+/// Plain websocket transport with given options.
+///
+/// Returns serializer selected by server together with transport.
+#[allow(clippy::type_complexity)]
+pub fn ws_with_options(
+    host: &str,
+    port: u16,
+    options: &WsOptions,
+) -> impl Future<
+    Output = Result<
+        (
+            impl Sink<ws::Message, Error = ws::ProtocolError>
+                + Stream<Item = Result<ws::Frame, ws::ProtocolError>>,
+            Serializer,
+        ),
+        WsClientError,
+    >,
+> + 'static {
+    let proxy = match options.proxy.resolve("ws", host) {
+        Ok(proxy) => proxy,
+        Err(e) => return future::err(io_error(e)).left_future(),
+    };
+
+    connect(client(proxy, None), "ws", host, port, options).right_future()
+}
+
 /// Websocket over https transport.
 pub fn wss(
     host: &str,
@@ -21,38 +92,258 @@ pub fn wss(
         WsClientError,
     >,
 > + 'static {
-    let mut builder = ssl::SslConnector::builder(ssl::SslMethod::tls()).unwrap();
-    //builder.set_verify();
-    let cert_hash = Arc::new(Mutex::new(None));
-
-    let cert_hash_w = cert_hash.clone();
-
-    builder.set_verify_callback(ssl::SslVerifyMode::NONE, move |internal_check, cert| {
-        let hash = cert
-            .current_cert()
-            .map(|x| x.digest(openssl::hash::MessageDigest::sha1()).unwrap())
-            .unwrap();
-        log::debug!("internal_check={}, cert={:?}", internal_check, hash);
-        *cert_hash_w.lock().unwrap() = Some(hash.to_vec());
-
-        false
-    });
-
-    let connector = actix_http::client::Connector::new()
-        .ssl(builder.build())
-        .finish();
-
-    Client::build()
-        .connector(connector)
-        .finish()
-        .ws(format!("wss://{}:{}", host, port))
-        .max_frame_size(107_374_182_400) // 100 MB
+    wss_with_serializers(host, port, &[Serializer::MsgPack])
+        .map_ok(|(transport, _serializer, cert_hash)| (transport, cert_hash))
+}
+
+/// Websocket over https transport offering given serializers, in order of preference.
+///
+/// Returns serializer selected by server together with transport.
+#[allow(clippy::type_complexity)]
+pub fn wss_with_serializers(
+    host: &str,
+    port: u16,
+    serializers: &[Serializer],
+) -> impl Future<
+    Output = Result<
+        (
+            impl Sink<ws::Message, Error = ws::ProtocolError>
+                + Stream<Item = Result<ws::Frame, ws::ProtocolError>>,
+            Serializer,
+            Option<Vec<u8>>,
+        ),
+        WsClientError,
+    >,
+> + 'static {
+    wss_with_options(
+        host,
+        port,
+        &WsOptions::default().with_serializers(serializers),
+    )
+}
+
+/// Websocket connection options.
+#[derive(Debug, Clone)]
+pub struct WsOptions {
+    pub(crate) serializers: Vec<Serializer>,
+    pub(crate) tls_policy: TlsPolicy,
+    pub(crate) heartbeat: Option<Heartbeat>,
+    pub(crate) proxy: ProxySettings,
+    pub(crate) max_frame_size: usize,
+    pub(crate) max_message_size: usize,
+    pub(crate) compression: bool,
+}
+
+impl Default for WsOptions {
+    fn default() -> Self {
+        WsOptions {
+            serializers: vec![Serializer::default()],
+            tls_policy: TlsPolicy::default(),
+            heartbeat: None,
+            proxy: ProxySettings::default(),
+            max_frame_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            compression: false,
+        }
+    }
+}
+
+impl WsOptions {
+    /// Sets serializers offered to router, in order of preference.
+    pub fn with_serializers(mut self, serializers: &[Serializer]) -> Self {
+        if !serializers.is_empty() {
+            self.serializers = serializers.to_vec();
+        }
+        self
+    }
+
+    /// Sets server certificate verification policy for wss connections.
+    pub fn with_tls_policy(mut self, tls_policy: TlsPolicy) -> Self {
+        self.tls_policy = tls_policy;
+        self
+    }
+
+    /// Pings peer every `interval` and drops connection when nothing arrives within
+    /// `timeout` after a ping. Pending calls and subscriptions then fail with
+    /// [`Error::HeartbeatTimeout`](crate::Error::HeartbeatTimeout).
+    ///
+    /// Disabled by default.
+    pub fn with_heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat = Some(Heartbeat { interval, timeout });
+        self
+    }
+
+    /// Connects to router through given proxy.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = ProxySettings::Fixed(proxy);
+        self
+    }
+
+    /// Takes proxy from `https_proxy` (wss) or `http_proxy` (ws) environment variable,
+    /// falling back to `all_proxy`. Hosts from `no_proxy` and loopback addresses are
+    /// connected directly.
+    ///
+    /// Variables are read when connecting.
+    pub fn with_env_proxy(mut self) -> Self {
+        self.proxy = ProxySettings::Env;
+        self
+    }
+
+    /// Sets largest websocket frame accepted from router, defaults to
+    /// [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Sets largest message accepted from router, after joining fragments and
+    /// decompression. Defaults to [`DEFAULT_MAX_MESSAGE_SIZE`].
+    ///
+    /// Session receiving bigger message is dropped and pending calls fail with
+    /// [`Error::MessageTooLarge`](crate::Error::MessageTooLarge). RawSocket announces
+    /// the limit to router, rounded down to power of two.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Offers permessage-deflate extension, so that messages are compressed when
+    /// router supports it.
+    ///
+    /// Disabled by default.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+}
+
+/// Websocket over https transport.
+///
+/// Returns serializer selected by server and SHA-1 hash of server certificate
+/// together with transport.
+#[allow(clippy::type_complexity)]
+pub fn wss_with_options(
+    host: &str,
+    port: u16,
+    options: &WsOptions,
+) -> impl Future<
+    Output = Result<
+        (
+            impl Sink<ws::Message, Error = ws::ProtocolError>
+                + Stream<Item = Result<ws::Frame, ws::ProtocolError>>,
+            Serializer,
+            Option<Vec<u8>>,
+        ),
+        WsClientError,
+    >,
+> + 'static {
+    let peer = Arc::new(Mutex::new(tls::PeerCert::default()));
+
+    let (ssl_connector, new_known_host) =
+        match tls::connector(&options.tls_policy, host, port, peer.clone()) {
+            Ok(v) => v,
+            Err(e) => return future::err(io_error(e)).left_future(),
+        };
+    let proxy = match options.proxy.resolve("wss", host) {
+        Ok(proxy) => proxy,
+        Err(e) => return future::err(io_error(e)).left_future(),
+    };
+
+    connect(
+        client(proxy, Some(ssl_connector)),
+        "wss",
+        host,
+        port,
+        options,
+    )
+    .map_ok(move |(framed, serializer)| {
+        let peer = peer.lock().unwrap();
+        if let Some(new_known_host) = new_known_host {
+            if let Err(e) = new_known_host.save(&peer) {
+                log::warn!("unable to save known host: {}", e);
+            }
+        }
+        (framed, serializer, peer.sha1.clone())
+    })
+    .right_future()
+}
+
+fn io_error(e: std::io::Error) -> WsClientError {
+    WsClientError::SendRequest(SendRequestError::Connect(ConnectError::Io(e)))
+}
+
+fn client(proxy: Option<Proxy>, ssl_connector: Option<SslConnector>) -> Client {
+    let connector = actix_http::client::Connector::new();
+    match proxy {
+        Some(proxy) => {
+            let mut connector = connector
+                .connector(ProxyConnector::new(proxy))
+                .timeout(PROXY_CONNECT_TIMEOUT);
+            if let Some(ssl_connector) = ssl_connector {
+                connector = connector.ssl(ssl_connector);
+            }
+            Client::build().connector(connector.finish()).finish()
+        }
+        None => {
+            let mut connector = connector;
+            if let Some(ssl_connector) = ssl_connector {
+                connector = connector.ssl(ssl_connector);
+            }
+            Client::build().connector(connector.finish()).finish()
+        }
+    }
+}
+
+fn connect(
+    client: Client,
+    scheme: &str,
+    host: &str,
+    port: u16,
+    options: &WsOptions,
+) -> impl Future<
+    Output = Result<
+        (
+            impl Sink<ws::Message, Error = ws::ProtocolError>
+                + Stream<Item = Result<ws::Frame, ws::ProtocolError>>,
+            Serializer,
+        ),
+        WsClientError,
+    >,
+> + 'static {
+    let protocols: Vec<&str> = options
+        .serializers
+        .iter()
+        .map(Serializer::protocol)
+        .collect();
+    let preferred = options.serializers.first().cloned().unwrap_or_default();
+    let max_message_size = options.max_message_size;
+
+    let mut request = client
+        .ws(format!("{}://{}:{}", scheme, host, port))
+        .max_frame_size(options.max_frame_size)
         .header("Host", format!("{}:{}", host, port))
-        .protocols(&["wamp.2.msgpack"])
+        .protocols(&protocols);
+    if options.compression {
+        request = request.header("Sec-WebSocket-Extensions", ws_codec::DEFLATE_OFFER);
+    }
+
+    request
         .connect()
         .and_then(move |(resp, framed): (ClientResponse, _)| {
-            log::debug!("wss response={:?}", resp);
+            log::debug!("ws response={:?}", resp);
+            let serializer = resp
+                .headers()
+                .get("sec-websocket-protocol")
+                .and_then(|v| v.to_str().ok())
+                .and_then(Serializer::from_protocol)
+                .unwrap_or(preferred);
+            let deflate = resp
+                .headers()
+                .get("sec-websocket-extensions")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(ws_codec::deflate_accepted);
 
-            future::ok((framed, cert_hash.lock().unwrap().take()))
+            let framed = framed.map_codec(|codec| WsCodec::new(codec, max_message_size, deflate));
+            future::ok((framed, serializer))
         })
 }
@@ -0,0 +1,298 @@
+//! WAMP RawSocket transport.
+//!
+//! Messages are exchanged as length-prefixed frames over plain stream socket. Codec maps them
+//! to websocket frames, so resulting transport can be passed to `SessionBuilder::create`.
+
+use crate::serializer::Serializer;
+use actix_codec::{AsyncRead, AsyncWrite, Decoder, Encoder, Framed};
+use actix_http::ws;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::future::Future;
+use std::io;
+#[cfg(unix)]
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+const MAGIC: u8 = 0x7f;
+
+const MSG_REGULAR: u8 = 0;
+const MSG_PING: u8 = 1;
+const MSG_PONG: u8 = 2;
+
+/// Largest payload which fits in 24 bit length prefix.
+const MAX_FRAME_LEN: usize = 0xff_ff_ff;
+
+/// Default exponent of maximum message length we accept (2^(9 + 15) bytes).
+pub const DEFAULT_MAX_LEN_EXP: u8 = 15;
+
+fn serializer_id(serializer: Serializer) -> u8 {
+    match serializer {
+        Serializer::Json => 1,
+        Serializer::MsgPack => 2,
+    }
+}
+
+fn max_len(exp: u8) -> usize {
+    (1usize << (9 + exp as usize)).min(MAX_FRAME_LEN)
+}
+
+/// Largest length exponent not exceeding `max_len`.
+pub(crate) fn max_len_exp(max_len: usize) -> u8 {
+    (0..=DEFAULT_MAX_LEN_EXP)
+        .rev()
+        .find(|&exp| self::max_len(exp) <= max_len)
+        .unwrap_or(0)
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn handshake_error(code: u8) -> io::Error {
+    match code {
+        1 => io::Error::new(io::ErrorKind::InvalidInput, "serializer unsupported"),
+        2 => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "maximum message length unacceptable",
+        ),
+        3 => invalid_data("use of reserved bits"),
+        4 => io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "maximum connection count reached",
+        ),
+        _ => invalid_data("illegal handshake error code"),
+    }
+}
+
+/// Length-prefixed RawSocket framing.
+#[derive(Debug)]
+pub struct RawSocketCodec {
+    serializer: Serializer,
+    /// Maximum message length accepted by us.
+    max_rx_len: usize,
+    /// Maximum message length accepted by router.
+    max_tx_len: usize,
+}
+
+impl RawSocketCodec {
+    pub fn new(serializer: Serializer, max_rx_len: usize, max_tx_len: usize) -> Self {
+        RawSocketCodec {
+            serializer,
+            max_rx_len: max_rx_len.min(MAX_FRAME_LEN),
+            max_tx_len: max_tx_len.min(MAX_FRAME_LEN),
+        }
+    }
+}
+
+impl Decoder for RawSocketCodec {
+    type Item = ws::Frame;
+    type Error = ws::ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let msg_type = src[0];
+        let len = (src[1] as usize) << 16 | (src[2] as usize) << 8 | src[3] as usize;
+        if len > self.max_rx_len {
+            return Err(ws::ProtocolError::Overflow);
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let payload = src.split_to(len).freeze();
+
+        match msg_type {
+            MSG_REGULAR => Ok(Some(match self.serializer {
+                Serializer::Json => ws::Frame::Text(payload),
+                Serializer::MsgPack => ws::Frame::Binary(payload),
+            })),
+            MSG_PING => Ok(Some(ws::Frame::Ping(payload))),
+            MSG_PONG => Ok(Some(ws::Frame::Pong(payload))),
+            _ => Err(invalid_data("invalid rawsocket message type").into()),
+        }
+    }
+}
+
+impl Encoder for RawSocketCodec {
+    type Item = ws::Message;
+    type Error = ws::ProtocolError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let (msg_type, payload) = match item {
+            ws::Message::Text(text) => (MSG_REGULAR, Bytes::from(text)),
+            ws::Message::Binary(bytes) => (MSG_REGULAR, bytes),
+            ws::Message::Ping(bytes) => (MSG_PING, bytes),
+            ws::Message::Pong(bytes) => (MSG_PONG, bytes),
+            // There is no closing handshake, connection is closed by dropping socket.
+            ws::Message::Close(_) | ws::Message::Continuation(_) | ws::Message::Nop => {
+                return Ok(())
+            }
+        };
+        if payload.len() > self.max_tx_len {
+            return Err(ws::ProtocolError::Overflow);
+        }
+        dst.reserve(4 + payload.len());
+        dst.put_u8(msg_type);
+        dst.put_uint(payload.len() as u64, 3);
+        dst.put_slice(payload.as_ref());
+        Ok(())
+    }
+}
+
+/// Performs RawSocket opening handshake on already connected stream.
+///
+/// `max_len_exp` sets maximum length of incoming messages to 2^(9 + `max_len_exp`) bytes.
+pub async fn handshake<T>(
+    mut io: T,
+    serializer: Serializer,
+    max_len_exp: u8,
+) -> io::Result<Framed<T, RawSocketCodec>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if max_len_exp > 0x0f {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "max length exponent out of range",
+        ));
+    }
+    io.write_all(&[MAGIC, max_len_exp << 4 | serializer_id(serializer), 0, 0])
+        .await?;
+
+    let mut reply = [0u8; 4];
+    io.read_exact(&mut reply).await?;
+    if reply[0] != MAGIC {
+        return Err(invalid_data("peer is not WAMP RawSocket router"));
+    }
+    let router_exp = reply[1] >> 4;
+    match reply[1] & 0x0f {
+        0 => return Err(handshake_error(router_exp)),
+        id if id != serializer_id(serializer) => {
+            return Err(invalid_data("router selected unexpected serializer"))
+        }
+        _ => (),
+    }
+    log::debug!(
+        "rawsocket handshake serializer={:?}, max_len={}",
+        serializer,
+        max_len(router_exp)
+    );
+
+    Ok(Framed::new(
+        io,
+        RawSocketCodec::new(serializer, max_len(max_len_exp), max_len(router_exp)),
+    ))
+}
+
+/// RawSocket transport over TCP.
+pub fn tcp(
+    host: &str,
+    port: u16,
+    serializer: Serializer,
+) -> impl Future<Output = io::Result<Framed<TcpStream, RawSocketCodec>>> + 'static {
+    tcp_with_max_len(host, port, serializer, DEFAULT_MAX_LEN_EXP)
+}
+
+/// RawSocket transport over TCP accepting messages up to 2^(9 + `max_len_exp`) bytes.
+pub fn tcp_with_max_len(
+    host: &str,
+    port: u16,
+    serializer: Serializer,
+    max_len_exp: u8,
+) -> impl Future<Output = io::Result<Framed<TcpStream, RawSocketCodec>>> + 'static {
+    let host = host.to_owned();
+
+    async move {
+        let stream = TcpStream::connect((host.as_str(), port)).await?;
+        stream.set_nodelay(true)?;
+        handshake(stream, serializer, max_len_exp).await
+    }
+}
+
+/// RawSocket transport over Unix domain socket.
+#[cfg(unix)]
+pub fn unix(
+    path: impl AsRef<Path>,
+    serializer: Serializer,
+) -> impl Future<Output = io::Result<Framed<UnixStream, RawSocketCodec>>> + 'static {
+    unix_with_max_len(path, serializer, DEFAULT_MAX_LEN_EXP)
+}
+
+/// RawSocket transport over Unix domain socket accepting messages up to
+/// 2^(9 + `max_len_exp`) bytes.
+#[cfg(unix)]
+pub fn unix_with_max_len(
+    path: impl AsRef<Path>,
+    serializer: Serializer,
+    max_len_exp: u8,
+) -> impl Future<Output = io::Result<Framed<UnixStream, RawSocketCodec>>> + 'static {
+    let path = path.as_ref().to_owned();
+
+    async move {
+        let stream = UnixStream::connect(path).await?;
+        handshake(stream, serializer, max_len_exp).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_codec_roundtrip() {
+        let mut codec = RawSocketCodec::new(Serializer::MsgPack, max_len(0), max_len(0));
+        let mut buf = BytesMut::new();
+
+        codec
+            .encode(
+                ws::Message::Binary(Bytes::from_static(b"\x93\x01\x02\x03")),
+                &mut buf,
+            )
+            .unwrap();
+        codec
+            .encode(ws::Message::Ping(Bytes::from_static(b"hb")), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 4]);
+
+        let mut partial = buf.split_to(6);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some(ws::Frame::Binary(Bytes::from_static(b"\x93\x01\x02\x03")))
+        );
+        assert_eq!(
+            codec.decode(&mut partial).unwrap(),
+            Some(ws::Frame::Ping(Bytes::from_static(b"hb")))
+        );
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn test_codec_max_len() {
+        let mut codec = RawSocketCodec::new(Serializer::Json, 4, 4);
+        let mut buf = BytesMut::new();
+
+        assert!(codec
+            .encode(ws::Message::Text("[1,2,3]".into()), &mut buf)
+            .is_err());
+
+        buf.extend_from_slice(&[0, 0, 0, 5]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_max_len_exp() {
+        assert_eq!(max_len_exp(100 * 1024 * 1024), DEFAULT_MAX_LEN_EXP);
+        assert_eq!(max_len_exp(1024), 1);
+        assert_eq!(max_len_exp(1500), 1);
+        assert_eq!(max_len_exp(0), 0);
+    }
+}
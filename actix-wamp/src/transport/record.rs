@@ -0,0 +1,419 @@
+//! Recording websocket sessions and replaying them without router.
+//!
+//! [`Recorder`] wraps any transport passed to [`SessionBuilder::create`](crate::SessionBuilder::create)
+//! and writes every frame to a file, one json object per line:
+//!
+//! ```text
+//! {"ms":0,"dir":"out","binary":"lQGmcmVhbG0x..."}
+//! {"ms":3,"dir":"in","text":"[2,7,{}]"}
+//! ```
+//!
+//! [`Replay`] plays such file back as a fake router: recorded incoming frames are delivered
+//! once all outgoing frames preceding them were sent by client. Request ids chosen by client
+//! are substituted in router replies, so recording of one session can be replayed by another.
+//! Timestamps are informational only, replay does not wait.
+
+use crate::messages::types::*;
+use crate::serializer;
+use actix_http::ws;
+use futures::prelude::*;
+use futures::task::{Context, Poll, Waker};
+use pin_project::pin_project;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordedFrame {
+    Text(String),
+    /// Base64 encoded.
+    Binary(String),
+    Ping(String),
+    Pong(String),
+    Close {
+        code: Option<u16>,
+        description: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    ms: u64,
+    dir: Direction,
+    #[serde(flatten)]
+    frame: RecordedFrame,
+}
+
+impl RecordedFrame {
+    fn close(reason: &Option<ws::CloseReason>) -> Self {
+        RecordedFrame::Close {
+            code: reason.as_ref().map(|r| r.code.into()),
+            description: reason.as_ref().and_then(|r| r.description.clone()),
+        }
+    }
+
+    fn from_message(msg: &ws::Message) -> Option<Self> {
+        Some(match msg {
+            ws::Message::Text(text) => RecordedFrame::Text(text.clone()),
+            ws::Message::Binary(bytes) => RecordedFrame::Binary(base64::encode(bytes)),
+            ws::Message::Ping(bytes) => RecordedFrame::Ping(base64::encode(bytes)),
+            ws::Message::Pong(bytes) => RecordedFrame::Pong(base64::encode(bytes)),
+            ws::Message::Close(reason) => RecordedFrame::close(reason),
+            ws::Message::Continuation(_) | ws::Message::Nop => return None,
+        })
+    }
+
+    fn from_frame(frame: &ws::Frame) -> Option<Self> {
+        Some(match frame {
+            ws::Frame::Text(bytes) => RecordedFrame::Text(String::from_utf8_lossy(bytes).into()),
+            ws::Frame::Binary(bytes) => RecordedFrame::Binary(base64::encode(bytes)),
+            ws::Frame::Ping(bytes) => RecordedFrame::Ping(base64::encode(bytes)),
+            ws::Frame::Pong(bytes) => RecordedFrame::Pong(base64::encode(bytes)),
+            ws::Frame::Close(reason) => RecordedFrame::close(reason),
+            ws::Frame::Continuation(_) => return None,
+        })
+    }
+
+    fn to_frame(&self) -> io::Result<ws::Frame> {
+        let decode = |data: &str| {
+            base64::decode(data)
+                .map(Into::into)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        Ok(match self {
+            RecordedFrame::Text(text) => ws::Frame::Text(text.clone().into()),
+            RecordedFrame::Binary(data) => ws::Frame::Binary(decode(data)?),
+            RecordedFrame::Ping(data) => ws::Frame::Ping(decode(data)?),
+            RecordedFrame::Pong(data) => ws::Frame::Pong(decode(data)?),
+            RecordedFrame::Close { code, description } => {
+                ws::Frame::Close(code.map(|code| ws::CloseReason {
+                    code: code.into(),
+                    description: description.clone(),
+                }))
+            }
+        })
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, RecordedFrame::Ping(_) | RecordedFrame::Pong(_))
+    }
+
+    fn decode(&self) -> Option<rmpv::Value> {
+        serializer::decode_frame(&self.to_frame().ok()?).ok()
+    }
+
+    fn encode_like(&self, msg: &rmpv::Value) -> io::Result<Self> {
+        match self {
+            RecordedFrame::Binary(_) => {
+                let mut bytes = Vec::new();
+                rmpv::encode::write_value(&mut bytes, msg)?;
+                Ok(RecordedFrame::Binary(base64::encode(&bytes)))
+            }
+            _ => Ok(RecordedFrame::Text(serializer::to_json(msg).to_string())),
+        }
+    }
+}
+
+/// Transport adapter writing all frames passing through to file.
+///
+/// Ping and pong frames are recorded too, but skipped by [`Replay`].
+#[pin_project]
+pub struct Recorder<T> {
+    #[pin]
+    inner: T,
+    out: Box<dyn Write + Send>,
+    started: Instant,
+}
+
+impl<T> Recorder<T> {
+    /// Records frames of `transport` to given writer.
+    pub fn new(transport: T, out: impl Write + Send + 'static) -> Self {
+        Recorder {
+            inner: transport,
+            out: Box::new(out),
+            started: Instant::now(),
+        }
+    }
+
+    /// Records frames of `transport` to newly created file.
+    pub fn create(transport: T, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Recorder::new(transport, LineWriter::new(file)))
+    }
+}
+
+fn write_entry(out: &mut dyn Write, started: &Instant, dir: Direction, frame: RecordedFrame) {
+    let entry = Entry {
+        ms: started.elapsed().as_millis() as u64,
+        dir,
+        frame,
+    };
+    let result = serde_json::to_writer(&mut *out, &entry)
+        .map_err(io::Error::from)
+        .and_then(|()| out.write_all(b"\n"));
+    if let Err(e) = result {
+        log::warn!("unable to record frame: {}", e);
+    }
+}
+
+impl<T: Sink<ws::Message>> Sink<ws::Message> for Recorder<T> {
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ws::Message) -> Result<(), Self::Error> {
+        let this = self.project();
+        if let Some(frame) = RecordedFrame::from_message(&item) {
+            write_entry(this.out.as_mut(), this.started, Direction::Out, frame);
+        }
+        this.inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.project();
+        let _ = this.out.flush();
+        this.inner.poll_close(cx)
+    }
+}
+
+impl<T, E> Stream for Recorder<T>
+where
+    T: Stream<Item = Result<ws::Frame, E>>,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let item = futures::ready!(this.inner.poll_next(cx));
+        if let Some(Ok(frame)) = &item {
+            if let Some(frame) = RecordedFrame::from_frame(frame) {
+                write_entry(this.out.as_mut(), this.started, Direction::In, frame);
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// Transport playing back session recorded with [`Recorder`].
+///
+/// Sink fails with [`ProtocolError::Io`](ws::ProtocolError::Io) when client sends different
+/// message (type or uri) than recorded. Stream ends after last recorded frame.
+pub struct Replay {
+    entries: VecDeque<Entry>,
+    request_ids: HashMap<u64, u64>,
+    waker: Option<Waker>,
+}
+
+impl Replay {
+    /// Loads recording from file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Replay::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Loads recording, e.g. embedded in test with `include_bytes!`.
+    pub fn from_reader(reader: impl BufRead) -> io::Result<Self> {
+        let mut entries = VecDeque::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line)?;
+            // Keepalives depend on timing, not on session.
+            if !entry.frame.is_control() {
+                entries.push_back(entry);
+            }
+        }
+        Ok(Replay {
+            entries,
+            request_ids: HashMap::new(),
+            waker: None,
+        })
+    }
+
+    fn check_sent(&mut self, expected: &RecordedFrame, sent: &RecordedFrame) -> io::Result<()> {
+        if let (RecordedFrame::Close { .. }, RecordedFrame::Close { .. }) = (expected, sent) {
+            return Ok(());
+        }
+        let (expected_msg, sent_msg) = match (expected.decode(), sent.decode()) {
+            (Some(expected), Some(sent)) => (expected, sent),
+            _ => return Err(diverged(expected, sent)),
+        };
+        let msg_type = sent_msg[0].as_u64();
+        if msg_type != expected_msg[0].as_u64() {
+            return Err(diverged(expected, sent));
+        }
+        let has_uri = matches!(
+            msg_type.map(|t| t as u8),
+            Some(CALL) | Some(SUBSCRIBE) | Some(PUBLISH) | Some(REGISTER)
+        );
+        if has_uri && sent_msg[3].as_str() != expected_msg[3].as_str() {
+            return Err(diverged(expected, sent));
+        }
+        if is_request(msg_type) {
+            if let (Some(recorded), Some(actual)) = (expected_msg[1].as_u64(), sent_msg[1].as_u64())
+            {
+                self.request_ids.insert(recorded, actual);
+            }
+        }
+        Ok(())
+    }
+
+    /// Substitutes request id in router reply with one used by client.
+    fn rewrite_received(&self, frame: RecordedFrame) -> io::Result<RecordedFrame> {
+        let mut msg = match frame.decode() {
+            Some(msg) => msg,
+            None => return Ok(frame),
+        };
+        let index = match msg[0].as_u64().map(|t| t as u8) {
+            Some(SUBSCRIBED) | Some(UNSUBSCRIBED) | Some(PUBLISHED) | Some(RESULT)
+            | Some(REGISTERED) | Some(UNREGISTERED) => 1,
+            Some(ERROR) if is_request(msg[1].as_u64()) => 2,
+            _ => return Ok(frame),
+        };
+        match msg[index]
+            .as_u64()
+            .and_then(|recorded| self.request_ids.get(&recorded))
+        {
+            Some(&actual) => {
+                if let rmpv::Value::Array(items) = &mut msg {
+                    items[index] = actual.into();
+                }
+                frame.encode_like(&msg)
+            }
+            None => Ok(frame),
+        }
+    }
+}
+
+/// Whether message type starts new client request with id at index 1.
+fn is_request(msg_type: Option<u64>) -> bool {
+    matches!(
+        msg_type.map(|t| t as u8),
+        Some(SUBSCRIBE)
+            | Some(UNSUBSCRIBE)
+            | Some(PUBLISH)
+            | Some(CALL)
+            | Some(REGISTER)
+            | Some(UNREGISTER)
+    )
+}
+
+fn diverged(expected: &RecordedFrame, sent: &RecordedFrame) -> io::Error {
+    let describe = |frame: &RecordedFrame| match frame.decode() {
+        Some(msg) => msg.to_string(),
+        None => format!("{:?}", frame),
+    };
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "replay diverged, expected {} but client sent {}",
+            describe(expected),
+            describe(sent)
+        ),
+    )
+}
+
+impl Sink<ws::Message> for Replay {
+    type Error = ws::ProtocolError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: ws::Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let sent = match RecordedFrame::from_message(&item) {
+            Some(frame) if !frame.is_control() => frame,
+            _ => return Ok(()),
+        };
+        let expected = match this.entries.iter().position(|e| e.dir == Direction::Out) {
+            Some(pos) => this.entries.remove(pos).unwrap().frame,
+            None => {
+                log::debug!("recording finished, dropping {:?}", sent);
+                return Ok(());
+            }
+        };
+        this.check_sent(&expected, &sent)?;
+        if let Some(waker) = this.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for Replay {
+    type Item = Result<ws::Frame, ws::ProtocolError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.entries.front() {
+            None => Poll::Ready(None),
+            Some(entry) if entry.dir == Direction::Out => {
+                this.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Some(_) => {
+                let frame = this.entries.pop_front().unwrap().frame;
+                let frame = this
+                    .rewrite_received(frame)
+                    .and_then(|frame| frame.to_frame());
+                Poll::Ready(Some(frame.map_err(ws::ProtocolError::Io)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replay_diverged() {
+        let recording = concat!(
+            r#"{"ms":0,"dir":"out","text":"[48,1,{},\"golem.version\"]"}"#,
+            "\n",
+            r#"{"ms":1,"dir":"in","text":"[50,1,{},[\"0.23\"]]"}"#,
+            "\n",
+        );
+
+        let mut replay = Replay::from_reader(recording.as_bytes()).unwrap();
+        let call = ws::Message::Text("[48,7,{},\"golem.version\"]".into());
+        Pin::new(&mut replay).start_send(call).unwrap();
+        match replay.next().now_or_never() {
+            Some(Some(Ok(ws::Frame::Text(text)))) => assert_eq!(text, "[50,7,{},[\"0.23\"]]"),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        let mut replay = Replay::from_reader(recording.as_bytes()).unwrap();
+        assert!(replay.next().now_or_never().is_none());
+        let call = ws::Message::Text("[48,7,{},\"golem.quit\"]".into());
+        assert!(Pin::new(&mut replay).start_send(call).is_err());
+    }
+}
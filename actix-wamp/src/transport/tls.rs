@@ -0,0 +1,405 @@
+//! Server certificate verification for wss transport.
+
+use openssl::hash::MessageDigest;
+use openssl::ssl;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContextRef, X509};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// How server certificate is verified during TLS handshake.
+///
+/// Connection is refused before any WAMP message is sent if certificate does not satisfy
+/// the policy.
+#[derive(Debug, Clone, Default)]
+pub enum TlsPolicy {
+    /// No verification. Certificate hash is only recorded and returned to caller.
+    #[default]
+    Insecure,
+    /// SHA-256 fingerprint of server certificate must be equal to given one.
+    PinnedSha256(Vec<u8>),
+    /// SHA-256 fingerprint of server certificate must be equal to one of given ones.
+    PinnedSha256Any(Vec<Vec<u8>>),
+    /// Certificate chain must be signed by CA from given PEM bundle file.
+    CaFile(PathBuf),
+    /// Certificate chain must be signed by given PEM encoded CA certificate(s).
+    CaPem(Vec<u8>),
+    /// Certificate chain must be signed by CA from system trust store.
+    SystemRoots,
+    /// Certificate seen on first connection is stored in given known hosts file
+    /// and pinned for all subsequent connections.
+    TrustOnFirstUse(PathBuf),
+}
+
+impl TlsPolicy {
+    /// Pins SHA-256 fingerprint given as hex string, optionally separated with colons
+    /// (as printed by `openssl x509 -fingerprint -sha256`).
+    pub fn pinned(fingerprint: &str) -> Option<Self> {
+        parse_fingerprint(fingerprint).map(TlsPolicy::PinnedSha256)
+    }
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = fingerprint
+        .bytes()
+        .filter(|&ch| ch != b':')
+        .map(|ch| (ch as char).to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()?;
+
+    if digits.len() != 64 {
+        return None;
+    }
+    Some(digits.chunks(2).map(|p| p[0] << 4 | p[1]).collect())
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Digests of server certificate seen during handshake.
+#[derive(Default)]
+pub(super) struct PeerCert {
+    pub sha1: Option<Vec<u8>>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+enum Check {
+    Any,
+    Chain,
+    Pinned(Vec<Vec<u8>>),
+}
+
+/// Known hosts entry to be stored after successful handshake.
+pub(super) struct NewKnownHost {
+    path: PathBuf,
+    host: String,
+}
+
+impl NewKnownHost {
+    pub fn save(&self, peer: &PeerCert) -> io::Result<()> {
+        let fingerprint = match &peer.sha256 {
+            Some(fingerprint) => fingerprint,
+            None => return Ok(()),
+        };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(f, "{} {}", self.host, format_fingerprint(fingerprint))
+    }
+}
+
+fn load_known_host(path: &Path, host: &str) -> io::Result<Option<Vec<u8>>> {
+    let f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    for line in io::BufReader::new(f).lines() {
+        let line = line?;
+        let mut it = line.split_whitespace();
+        match (it.next(), it.next()) {
+            (Some(entry_host), Some(fingerprint)) if entry_host == host => {
+                return parse_fingerprint(fingerprint).map(Some).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid fingerprint for {} in {}", host, path.display()),
+                    )
+                })
+            }
+            _ => (),
+        }
+    }
+    Ok(None)
+}
+
+/// Builds TLS connector enforcing `policy` for `host:port`.
+pub(super) fn connector(
+    policy: &TlsPolicy,
+    host: &str,
+    port: u16,
+    peer: Arc<Mutex<PeerCert>>,
+) -> io::Result<(ssl::SslConnector, Option<NewKnownHost>)> {
+    let mut builder = ssl::SslConnector::builder(ssl::SslMethod::tls())?;
+    let mut new_known_host = None;
+
+    let check = match policy {
+        TlsPolicy::Insecure => Check::Any,
+        TlsPolicy::PinnedSha256(fingerprint) => Check::Pinned(vec![fingerprint.clone()]),
+        TlsPolicy::PinnedSha256Any(fingerprints) => Check::Pinned(fingerprints.clone()),
+        TlsPolicy::CaFile(path) => {
+            builder.set_cert_store(X509StoreBuilder::new()?.build());
+            builder.set_ca_file(path)?;
+            Check::Chain
+        }
+        TlsPolicy::CaPem(pem) => {
+            let mut store = X509StoreBuilder::new()?;
+            for cert in X509::stack_from_pem(pem)? {
+                store.add_cert(cert)?;
+            }
+            builder.set_cert_store(store.build());
+            Check::Chain
+        }
+        TlsPolicy::SystemRoots => {
+            builder.set_default_verify_paths()?;
+            Check::Chain
+        }
+        TlsPolicy::TrustOnFirstUse(path) => {
+            let host = format!("{}:{}", host, port);
+            match load_known_host(path, &host)? {
+                Some(fingerprint) => Check::Pinned(vec![fingerprint]),
+                None => {
+                    log::info!(
+                        "{} not found in {}, trusting on first use",
+                        host,
+                        path.display()
+                    );
+                    new_known_host = Some(NewKnownHost {
+                        path: path.clone(),
+                        host,
+                    });
+                    Check::Any
+                }
+            }
+        }
+    };
+
+    builder.set_verify_callback(
+        ssl::SslVerifyMode::PEER,
+        move |preverify_ok, ctx: &mut X509StoreContextRef| {
+            if ctx.error_depth() > 0 {
+                return match check {
+                    Check::Chain => preverify_ok,
+                    _ => true,
+                };
+            }
+            let cert = match ctx.current_cert() {
+                Some(cert) => cert,
+                None => return false,
+            };
+            let (sha1, sha256) = match (
+                cert.digest(MessageDigest::sha1()),
+                cert.digest(MessageDigest::sha256()),
+            ) {
+                (Ok(sha1), Ok(sha256)) => (sha1.to_vec(), sha256.to_vec()),
+                _ => return false,
+            };
+            log::debug!(
+                "preverify_ok={}, cert sha256={}",
+                preverify_ok,
+                format_fingerprint(&sha256)
+            );
+
+            let accepted = match &check {
+                Check::Any => true,
+                Check::Chain => preverify_ok,
+                Check::Pinned(expected) => expected.contains(&sha256),
+            };
+            if !accepted {
+                log::error!(
+                    "server certificate rejected, sha256={}",
+                    format_fingerprint(&sha256)
+                );
+            }
+            let mut peer = peer.lock().unwrap();
+            peer.sha1 = Some(sha1);
+            peer.sha256 = Some(sha256);
+
+            accepted
+        },
+    );
+
+    Ok((builder.build(), new_known_host))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let hex = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89";
+        let fingerprint = parse_fingerprint(hex).unwrap();
+        assert_eq!(fingerprint.len(), 32);
+        assert_eq!(&fingerprint[..3], &[0xab, 0xcd, 0xef]);
+        assert_eq!(
+            parse_fingerprint(&format_fingerprint(&fingerprint)),
+            Some(fingerprint)
+        );
+
+        assert_eq!(parse_fingerprint("abcd"), None);
+        assert!(TlsPolicy::pinned(&hex.replace("AB", "XY")).is_none());
+    }
+
+    /// Self-signed certificate for `localhost` and `127.0.0.1` with its key.
+    fn self_signed() -> (openssl::pkey::PKey<openssl::pkey::Private>, X509) {
+        use openssl::asn1::Asn1Time;
+        use openssl::pkey::PKey;
+        use openssl::rsa::Rsa;
+        use openssl::x509::extension::SubjectAlternativeName;
+        use openssl::x509::X509NameBuilder;
+
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .ip("127.0.0.1")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        (key, cert.build())
+    }
+
+    /// Accepts one TLS connection, returns what client sent after handshake
+    /// or `None` when handshake failed.
+    fn serve_once() -> (u16, std::thread::JoinHandle<Option<Vec<u8>>>, X509) {
+        let (key, cert) = self_signed();
+        let (port, server) = serve(&key, &cert, 0, false);
+        (port, server, cert)
+    }
+
+    /// Like [`serve_once`], on given port (0 for any), optionally completing
+    /// websocket upgrade.
+    fn serve(
+        key: &openssl::pkey::PKeyRef<openssl::pkey::Private>,
+        cert: &X509,
+        port: u16,
+        upgrade: bool,
+    ) -> (u16, std::thread::JoinHandle<Option<Vec<u8>>>) {
+        use std::io::Read;
+
+        let mut acceptor = ssl::SslAcceptor::mozilla_intermediate(ssl::SslMethod::tls()).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        acceptor.set_certificate(cert).unwrap();
+        let acceptor = acceptor.build();
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = acceptor.accept(stream).ok()?;
+            let mut request = Vec::new();
+            let mut buf = [0u8; 512];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf) {
+                    Ok(n) if n > 0 => request.extend_from_slice(&buf[..n]),
+                    _ => break,
+                }
+            }
+            if upgrade {
+                stream.write_all(&upgrade_response(&request)).unwrap();
+                let _ = stream.read(&mut buf);
+            }
+            Some(request)
+        });
+        (port, server)
+    }
+
+    fn upgrade_response(request: &[u8]) -> Vec<u8> {
+        let request = String::from_utf8_lossy(request);
+        let key = request
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))
+            .map(|(_, key)| key.trim())
+            .unwrap();
+        let accept =
+            openssl::sha::sha1(format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key).as_bytes());
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            openssl::base64::encode_block(&accept)
+        )
+        .into_bytes()
+    }
+
+    fn connect(policy: TlsPolicy, port: u16) -> Result<(), crate::ClientError> {
+        let options = crate::WsOptions::default().with_tls_policy(policy);
+        actix::System::new("test").block_on(async move {
+            crate::wss_with_options("127.0.0.1", port, &options)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    #[test]
+    fn test_rejected_before_upgrade() {
+        let (port, server, _cert) = serve_once();
+        assert!(connect(TlsPolicy::PinnedSha256(vec![0; 32]), port).is_err());
+        assert_eq!(server.join().unwrap(), None);
+
+        let (port, server, _cert) = serve_once();
+        let (_key, other) = self_signed();
+        let other = other.to_pem().unwrap();
+        assert!(connect(TlsPolicy::CaPem(other), port).is_err());
+        assert_eq!(server.join().unwrap(), None);
+    }
+
+    #[test]
+    fn test_pinned_accepted() {
+        let (port, server, cert) = serve_once();
+        let fingerprint = cert.digest(MessageDigest::sha256()).unwrap().to_vec();
+        let policy = TlsPolicy::PinnedSha256Any(vec![vec![0; 32], fingerprint]);
+        // Server does not answer upgrade, but handshake is done and request is sent.
+        let _ = connect(policy, port);
+        let request = server.join().unwrap().unwrap();
+        assert!(request.starts_with(b"GET "));
+    }
+
+    #[test]
+    fn test_ca_accepted() {
+        let (key, cert) = self_signed();
+        let (port, server) = serve(&key, &cert, 0, true);
+        connect(TlsPolicy::CaPem(cert.to_pem().unwrap()), port).unwrap();
+        assert!(server.join().unwrap().is_some());
+
+        let ca_file = std::env::temp_dir().join(format!("actix-wamp-ca-{}.pem", port));
+        fs::write(&ca_file, cert.to_pem().unwrap()).unwrap();
+        let (port, server) = serve(&key, &cert, 0, true);
+        let result = connect(TlsPolicy::CaFile(ca_file.clone()), port);
+        let _ = fs::remove_file(&ca_file);
+        result.unwrap();
+        assert!(server.join().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_trust_on_first_use() {
+        let (key, cert) = self_signed();
+        let (port, server) = serve(&key, &cert, 0, true);
+        let known_hosts = std::env::temp_dir().join(format!("actix-wamp-known-hosts-{}", port));
+        let policy = TlsPolicy::TrustOnFirstUse(known_hosts.clone());
+
+        connect(policy.clone(), port).unwrap();
+        assert!(server.join().unwrap().is_some());
+        let fingerprint = cert.digest(MessageDigest::sha256()).unwrap().to_vec();
+        assert_eq!(
+            load_known_host(&known_hosts, &format!("127.0.0.1:{}", port)).unwrap(),
+            Some(fingerprint)
+        );
+
+        // Same host presenting another certificate is refused.
+        let (key, other) = self_signed();
+        let (_, server) = serve(&key, &other, port, true);
+        let result = connect(policy, port);
+        let _ = fs::remove_file(&known_hosts);
+        assert!(result.is_err());
+        assert_eq!(server.join().unwrap(), None);
+    }
+}
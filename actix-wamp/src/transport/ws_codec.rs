@@ -0,0 +1,418 @@
+//! Websocket codec enforcing message size limit, with optional permessage-deflate
+//! ([RFC 7692](https://tools.ietf.org/html/rfc7692)).
+//!
+//! Wraps actix `ws::Codec`, which parses frames and ignores RSV bits. Fragmented messages
+//! are joined, so session gets each message as single text or binary frame.
+
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use bytes::{Bytes, BytesMut};
+use miniz_oxide::deflate::core::{create_comp_flags_from_zip_params, CompressorOxide};
+use miniz_oxide::inflate::stream::InflateState;
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use std::{cmp, io};
+
+/// Extension offered to server. Compressor is reset after each message, so we never use
+/// context takeover and can tell server so.
+pub(crate) const DEFLATE_OFFER: &str = "permessage-deflate; client_no_context_takeover";
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const OPCODE_TEXT: u8 = 0x01;
+const OPCODE_BINARY: u8 = 0x02;
+
+/// Smaller messages are sent uncompressed.
+const MIN_COMPRESSED_LEN: usize = 256;
+
+/// Trailer removed from compressed message by sender, see RFC 7692 section 7.2.1.
+const SYNC_TRAILER: [u8; 4] = [0, 0, 0xff, 0xff];
+
+fn invalid_data(msg: &'static str) -> ws::ProtocolError {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Checks whether server accepted permessage-deflate in `Sec-WebSocket-Extensions` reply.
+pub(crate) fn deflate_accepted(extensions: &str) -> bool {
+    extensions.split(',').any(|extension| {
+        let mut params = extension.split(';').map(str::trim);
+        params.next() == Some("permessage-deflate")
+            // we did not offer smaller window, so server must not ask for it.
+            && params.all(|param| !param.starts_with("client_max_window_bits"))
+    })
+}
+
+/// Compresses message, in context of earlier ones unless `compressor` was reset.
+fn deflate_message(
+    compressor: &mut CompressorOxide,
+    data: &[u8],
+) -> Result<Vec<u8>, ws::ProtocolError> {
+    let mut out = vec![0; data.len() / 2 + 64];
+    let mut written = 0;
+    let mut input = data;
+    loop {
+        let result = miniz_oxide::deflate::stream::deflate(
+            compressor,
+            input,
+            &mut out[written..],
+            MZFlush::Sync,
+        );
+        result
+            .status
+            .map_err(|_| invalid_data("compression failed"))?;
+        input = &input[result.bytes_consumed..];
+        written += result.bytes_written;
+        if input.is_empty() && written < out.len() {
+            break;
+        }
+        if written == out.len() {
+            out.resize(out.len() * 2, 0);
+        }
+    }
+    out.truncate(written);
+    if out.ends_with(&SYNC_TRAILER) {
+        out.truncate(written - SYNC_TRAILER.len());
+    }
+    Ok(out)
+}
+
+struct Deflate {
+    compressor: Box<CompressorOxide>,
+    inflater: Box<InflateState>,
+}
+
+impl Deflate {
+    fn new() -> Self {
+        Deflate {
+            compressor: Box::new(CompressorOxide::new(create_comp_flags_from_zip_params(
+                6, -15, 0,
+            ))),
+            inflater: InflateState::new_boxed(DataFormat::Raw),
+        }
+    }
+
+    fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, ws::ProtocolError> {
+        self.compressor.reset();
+        deflate_message(&mut self.compressor, data)
+    }
+
+    /// Inflates message, failing when it grows over `limit` bytes.
+    fn decompress(&mut self, data: &[u8], limit: usize) -> Result<Bytes, ws::ProtocolError> {
+        let mut compressed = Vec::with_capacity(data.len() + SYNC_TRAILER.len());
+        compressed.extend_from_slice(data);
+        compressed.extend_from_slice(&SYNC_TRAILER);
+
+        // one byte over limit to tell overflow from exact fit.
+        let capacity = limit.saturating_add(1);
+        let mut out = vec![0; cmp::min(data.len().saturating_mul(4).max(1024), capacity)];
+        let mut written = 0;
+        let mut input = &compressed[..];
+        loop {
+            let result = miniz_oxide::inflate::stream::inflate(
+                &mut self.inflater,
+                input,
+                &mut out[written..],
+                MZFlush::Sync,
+            );
+            input = &input[result.bytes_consumed..];
+            written += result.bytes_written;
+            if written > limit {
+                return Err(ws::ProtocolError::Overflow);
+            }
+            match result.status {
+                // server may end message with final block, next one starts new stream.
+                Ok(MZStatus::StreamEnd) => {
+                    self.inflater.reset(DataFormat::Raw);
+                    break;
+                }
+                Ok(_) | Err(MZError::Buf) => (),
+                Err(_) => return Err(invalid_data("invalid compressed message")),
+            }
+            if input.is_empty() && written < out.len() {
+                break;
+            }
+            if written == out.len() {
+                out.resize(out.len().saturating_mul(2).min(capacity), 0);
+            } else if result.bytes_consumed == 0 && result.bytes_written == 0 {
+                return Err(invalid_data("invalid compressed message"));
+            }
+        }
+        out.truncate(written);
+        Ok(out.into())
+    }
+}
+
+/// Message being received in fragments.
+struct Partial {
+    text: bool,
+    compressed: bool,
+    data: BytesMut,
+}
+
+pub(crate) struct WsCodec {
+    inner: ws::Codec,
+    max_message_size: usize,
+    deflate: Option<Deflate>,
+    partial: Option<Partial>,
+}
+
+impl WsCodec {
+    /// Wraps client codec. Frame size limit is set on `inner`.
+    pub(crate) fn new(inner: ws::Codec, max_message_size: usize, deflate: bool) -> Self {
+        WsCodec {
+            inner,
+            max_message_size,
+            deflate: if deflate { Some(Deflate::new()) } else { None },
+            partial: None,
+        }
+    }
+
+    fn message(
+        &mut self,
+        text: bool,
+        compressed: bool,
+        data: Bytes,
+    ) -> Result<ws::Frame, ws::ProtocolError> {
+        let data = if compressed {
+            match &mut self.deflate {
+                Some(deflate) => deflate.decompress(&data, self.max_message_size)?,
+                None => return Err(invalid_data("compressed frame without permessage-deflate")),
+            }
+        } else if data.len() > self.max_message_size {
+            return Err(ws::ProtocolError::Overflow);
+        } else {
+            data
+        };
+        Ok(if text {
+            ws::Frame::Text(data)
+        } else {
+            ws::Frame::Binary(data)
+        })
+    }
+
+    fn start(
+        &mut self,
+        text: bool,
+        compressed: bool,
+        data: Bytes,
+    ) -> Result<(), ws::ProtocolError> {
+        self.partial = Some(Partial {
+            text,
+            compressed,
+            data: BytesMut::new(),
+        });
+        self.append(data)
+    }
+
+    fn append(&mut self, data: Bytes) -> Result<(), ws::ProtocolError> {
+        let partial = match &mut self.partial {
+            Some(partial) => partial,
+            None => return Err(ws::ProtocolError::ContinuationNotStarted),
+        };
+        if partial.data.len() + data.len() > self.max_message_size {
+            self.partial = None;
+            return Err(ws::ProtocolError::Overflow);
+        }
+        partial.data.extend_from_slice(&data);
+        Ok(())
+    }
+
+    fn encode_compressed(
+        &mut self,
+        opcode: u8,
+        payload: &[u8],
+        dst: &mut BytesMut,
+    ) -> Result<(), ws::ProtocolError> {
+        let compressed = match &mut self.deflate {
+            Some(deflate) => deflate.compress(payload)?,
+            None => return Err(invalid_data("permessage-deflate not negotiated")),
+        };
+        let start = dst.len();
+        self.inner
+            .encode(ws::Message::Binary(compressed.into()), dst)?;
+        // inner codec wrote single final binary frame, mark it compressed and restore opcode.
+        dst[start] = FIN | RSV1 | opcode;
+        Ok(())
+    }
+}
+
+impl Decoder for WsCodec {
+    type Item = ws::Frame;
+    type Error = ws::ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        use ws::{Frame, Item};
+
+        loop {
+            let compressed = src.first().is_some_and(|first| first & RSV1 != 0);
+            let frame = match self.inner.decode(src)? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            match frame {
+                Frame::Text(data) => return self.message(true, compressed, data).map(Some),
+                Frame::Binary(data) => return self.message(false, compressed, data).map(Some),
+                Frame::Continuation(Item::FirstText(data)) => self.start(true, compressed, data)?,
+                Frame::Continuation(Item::FirstBinary(data)) => {
+                    self.start(false, compressed, data)?
+                }
+                Frame::Continuation(Item::Continue(data)) => self.append(data)?,
+                Frame::Continuation(Item::Last(data)) => {
+                    self.append(data)?;
+                    if let Some(Partial {
+                        text,
+                        compressed,
+                        data,
+                    }) = self.partial.take()
+                    {
+                        return self.message(text, compressed, data.freeze()).map(Some);
+                    }
+                }
+                // control frames may come between fragments.
+                frame => return Ok(Some(frame)),
+            }
+        }
+    }
+}
+
+impl Encoder for WsCodec {
+    type Item = ws::Message;
+    type Error = ws::ProtocolError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            ws::Message::Text(text)
+                if self.deflate.is_some() && text.len() >= MIN_COMPRESSED_LEN =>
+            {
+                self.encode_compressed(OPCODE_TEXT, text.as_bytes(), dst)
+            }
+            ws::Message::Binary(data)
+                if self.deflate.is_some() && data.len() >= MIN_COMPRESSED_LEN =>
+            {
+                self.encode_compressed(OPCODE_BINARY, &data, dst)
+            }
+            item => self.inner.encode(item, dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Server side of connection, frames are not masked.
+    fn server_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        assert!(payload.len() < 126);
+        let mut frame = vec![first, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_deflate_roundtrip() {
+        let text = "[48,1,{},\"golem.comp.tasks\"]".repeat(20);
+        let mut client = WsCodec::new(ws::Codec::new().client_mode(), 1024, true);
+        let mut server = ws::Codec::new();
+
+        let mut buf = BytesMut::new();
+        client
+            .encode(ws::Message::Text(text.clone()), &mut buf)
+            .unwrap();
+        assert_eq!(buf[0], FIN | RSV1 | OPCODE_TEXT);
+        let compressed = match server.decode(&mut buf).unwrap() {
+            Some(ws::Frame::Text(data)) => data,
+            frame => panic!("unexpected frame: {:?}", frame),
+        };
+        assert!(compressed.len() < text.len() / 4);
+
+        // two messages in one buffer, each compressed from scratch.
+        let mut peer = Deflate::new();
+        let mut src = BytesMut::new();
+        for _ in 0..2 {
+            let payload = peer.compress(text.as_bytes()).unwrap();
+            assert!(payload.len() < 126);
+            src.extend_from_slice(&server_frame(FIN | RSV1 | OPCODE_TEXT, &payload));
+        }
+        for _ in 0..2 {
+            assert_eq!(
+                client.decode(&mut src).unwrap(),
+                Some(ws::Frame::Text(text.clone().into()))
+            );
+        }
+
+        let mut small = WsCodec::new(ws::Codec::new().client_mode(), 100, true);
+        let payload = peer.compress(text.as_bytes()).unwrap();
+        let mut src = BytesMut::from(&server_frame(FIN | RSV1 | OPCODE_TEXT, &payload)[..]);
+        assert!(matches!(
+            small.decode(&mut src),
+            Err(ws::ProtocolError::Overflow)
+        ));
+    }
+
+    /// Server may use context takeover, so later messages refer to earlier ones.
+    #[test]
+    fn test_server_context_takeover() {
+        let text = "[36,5,1,{},[\"golem.comp.task.status\",\"Started\"]]".repeat(4);
+        let mut client = WsCodec::new(ws::Codec::new().client_mode(), 1024, true);
+        let mut peer = Deflate::new();
+
+        let first = deflate_message(&mut peer.compressor, text.as_bytes()).unwrap();
+        let second = deflate_message(&mut peer.compressor, text.as_bytes()).unwrap();
+        assert!(second.len() < first.len() / 2);
+
+        // second message alone does not inflate to the text.
+        let mut fresh = WsCodec::new(ws::Codec::new().client_mode(), 1024, true);
+        let mut src = BytesMut::from(&server_frame(FIN | RSV1 | OPCODE_TEXT, &second)[..]);
+        assert_ne!(
+            fresh.decode(&mut src).ok(),
+            Some(Some(ws::Frame::Text(text.clone().into())))
+        );
+
+        let mut src = BytesMut::new();
+        for payload in [first, second] {
+            src.extend_from_slice(&server_frame(FIN | RSV1 | OPCODE_TEXT, &payload));
+        }
+        for _ in 0..2 {
+            assert_eq!(
+                client.decode(&mut src).unwrap(),
+                Some(ws::Frame::Text(text.clone().into()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut codec = WsCodec::new(ws::Codec::new().client_mode(), 8, false);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&server_frame(OPCODE_BINARY, b"abc"));
+        src.extend_from_slice(&server_frame(FIN | 0x09, b"hb"));
+        src.extend_from_slice(&server_frame(FIN, b"def"));
+
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ws::Frame::Ping(Bytes::from_static(b"hb")))
+        );
+        assert_eq!(
+            codec.decode(&mut src).unwrap(),
+            Some(ws::Frame::Binary(Bytes::from_static(b"abcdef")))
+        );
+
+        src.extend_from_slice(&server_frame(OPCODE_BINARY, b"abcde"));
+        src.extend_from_slice(&server_frame(FIN, b"fghij"));
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(ws::ProtocolError::Overflow)
+        ));
+    }
+
+    #[test]
+    fn test_deflate_accepted() {
+        assert!(deflate_accepted("permessage-deflate"));
+        assert!(deflate_accepted(
+            "foo, permessage-deflate; server_no_context_takeover"
+        ));
+        assert!(!deflate_accepted(
+            "permessage-deflate; client_max_window_bits=10"
+        ));
+        assert!(!deflate_accepted("x-webkit-deflate-frame"));
+    }
+}
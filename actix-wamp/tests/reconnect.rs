@@ -0,0 +1,258 @@
+use actix::System;
+use actix_wamp::router::{Router, RouterBuilder};
+use actix_wamp::*;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use serde_json::json;
+use std::time::Duration;
+
+const REALM: &str = "realm1";
+
+fn options(call_policy: CallPolicy) -> ReconnectOptions {
+    ReconnectOptions {
+        backoff: Backoff {
+            initial_delay: Duration::from_millis(100),
+            ..Backoff::default()
+        },
+        call_policy,
+    }
+}
+
+async fn session(router: &Router) -> impl RpcEndpoint + PubSubEndpoint + RpcRegistry {
+    SessionBuilder::anonymous(REALM.into())
+        .create(router.connect())
+        .await
+        .unwrap()
+}
+
+fn reconnecting(
+    router: &Router,
+    options: ReconnectOptions,
+) -> ReconnectingSession<
+    impl RpcEndpoint
+        + PubSubEndpoint<Events = impl Stream<Item = Result<WampMessage, Error>> + 'static>
+        + SessionEndpoint
+        + Clone
+        + Send
+        + Unpin,
+> {
+    let router = router.clone();
+    ReconnectingSession::new(
+        move || {
+            SessionBuilder::anonymous(REALM.into())
+                .create(router.connect())
+                .boxed_local()
+        },
+        options,
+    )
+}
+
+async fn wait_for(
+    status: &mut (impl Stream<Item = ConnectionStatus> + Unpin),
+    expected: ConnectionStatus,
+) {
+    while let Some(next) = status.next().await {
+        if next == expected {
+            return;
+        }
+    }
+    panic!("status {:?} not reached", expected);
+}
+
+/// Router handles messages of one session in order, so earlier SUBSCRIBE is done
+/// once this publication is acknowledged.
+async fn sync(session: &impl PubSubEndpoint) {
+    session
+        .publish("evt.sync", vec![], None, PublishOptions::acknowledged())
+        .await
+        .unwrap();
+}
+
+async fn publish(router: &Router, topic: &str, arg: &str) {
+    session(router)
+        .await
+        .publish(
+            topic,
+            vec![json!(arg)],
+            None,
+            PublishOptions::acknowledged(),
+        )
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_resubscribe_after_reconnect() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let session = reconnecting(&router, options(CallPolicy::Fail));
+        let mut status = Box::pin(session.status_changes());
+        wait_for(&mut status, ConnectionStatus::Connected).await;
+
+        let mut events = session.subscribe("evt.a");
+        sync(&session).await;
+        publish(&router, "evt.a", "first").await;
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.args, vec![json!("first")]);
+
+        router.disconnect_all();
+        wait_for(&mut status, ConnectionStatus::Disconnected).await;
+        wait_for(&mut status, ConnectionStatus::Connected).await;
+
+        // Old broker subscription is gone with its session, event comes through new one.
+        sync(&session).await;
+        publish(&router, "evt.a", "second").await;
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.args, vec![json!("second")]);
+    });
+}
+
+/// Registers procedure which never replies, returns stream of its invocations.
+async fn register_hanging(
+    callee: &impl RpcRegistry,
+) -> (Registration, mpsc::UnboundedReceiver<()>) {
+    let (tx, rx) = mpsc::unbounded();
+    let registration = callee
+        .register("test.call", move |_: Invocation| {
+            let _ = tx.unbounded_send(());
+            future::pending::<Result<Payload, WampError>>()
+        })
+        .await
+        .unwrap();
+    (registration, rx)
+}
+
+#[test]
+fn test_retry_call_after_reconnect() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let callee = session(&router).await;
+        let (_registration, mut invocations) = register_hanging(&callee).await;
+
+        let caller = reconnecting(&router, options(CallPolicy::Retry(1)));
+        let result = caller.rpc_call(RpcCallRequest::with_no_args("test.call"));
+        futures::pin_mut!(result);
+        // Call reaches callee before connection is dropped.
+        let invoked = invocations.next();
+        let result = match future::select(result, invoked).await {
+            future::Either::Right((_, result)) => result,
+            future::Either::Left((result, _)) => panic!("unexpected result: {:?}", result.err()),
+        };
+
+        router.disconnect_all();
+        // Registered before caller reconnects after backoff delay.
+        let callee = session(&router).await;
+        let _registration = callee
+            .register("test.call", |_: Invocation| {
+                future::ok(RpcCallResponse {
+                    args: vec![json!("done")],
+                    kw_args: None,
+                })
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(result.await.unwrap().args, vec![json!("done")]);
+    });
+}
+
+#[test]
+fn test_fail_call_on_connection_loss() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let callee = session(&router).await;
+        let (_registration, mut invocations) = register_hanging(&callee).await;
+
+        let caller = reconnecting(&router, options(CallPolicy::Fail));
+        let result = caller.rpc_call(RpcCallRequest::with_no_args("test.call"));
+        futures::pin_mut!(result);
+        let result = match future::select(result, invocations.next()).await {
+            future::Either::Right((_, result)) => result,
+            future::Either::Left((result, _)) => panic!("unexpected result: {:?}", result.err()),
+        };
+
+        router.disconnect_all();
+        assert!(matches!(result.await, Err(Error::ConnectionClosed)));
+    });
+}
+
+#[test]
+fn test_status_changes() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let session = reconnecting(&router, options(CallPolicy::Fail));
+        let status = session.status_changes();
+        futures::pin_mut!(status);
+        assert_eq!(
+            status.next().await,
+            Some(ConnectionStatus::Connecting { attempt: 1 })
+        );
+        assert_eq!(status.next().await, Some(ConnectionStatus::Connected));
+
+        router.disconnect_all();
+        assert_eq!(status.next().await, Some(ConnectionStatus::Disconnected));
+        assert_eq!(
+            status.next().await,
+            Some(ConnectionStatus::Connecting { attempt: 1 })
+        );
+        assert_eq!(status.next().await, Some(ConnectionStatus::Connected));
+    });
+}
+
+#[test]
+fn test_give_up_reconnecting() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).deny_anonymous().start();
+        let session = reconnecting(
+            &router,
+            ReconnectOptions {
+                backoff: Backoff {
+                    initial_delay: Duration::from_millis(10),
+                    max_attempts: Some(2),
+                    ..Backoff::default()
+                },
+                call_policy: CallPolicy::Fail,
+            },
+        );
+        let status: Vec<_> = session.status_changes().collect().await;
+        assert_eq!(
+            status,
+            vec![
+                ConnectionStatus::Connecting { attempt: 1 },
+                ConnectionStatus::Connecting { attempt: 2 },
+                ConnectionStatus::Failed,
+            ]
+        );
+    });
+}
+
+#[test]
+fn test_denied_subscription() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM)
+            .deny_subscribe("evt.secret")
+            .start();
+        let session = reconnecting(&router, options(CallPolicy::Fail));
+        let mut status = Box::pin(session.status_changes());
+
+        let mut events = session.subscribe("evt.secret");
+        match events.next().await {
+            Some(Err(Error::WampError(e))) => assert_eq!(e.code, ErrorKind::NotAuthorized),
+            Some(Err(e)) => panic!("unexpected error: {}", e),
+            Some(Ok(_)) => panic!("unexpected event"),
+            None => panic!("subscription ended without error"),
+        }
+        assert!(events.next().await.is_none());
+
+        // Error of session closed by router is not passed on, topic is subscribed again.
+        let mut events = session.subscribe("evt.a");
+        sync(&session).await;
+        router.shutdown("wamp.close.system_shutdown");
+        wait_for(&mut status, ConnectionStatus::Disconnected).await;
+        wait_for(&mut status, ConnectionStatus::Connected).await;
+        sync(&session).await;
+        publish(&router, "evt.a", "after").await;
+        let event = events.try_next().await.unwrap().unwrap();
+        assert_eq!(event.args, vec![json!("after")]);
+    });
+}
@@ -0,0 +1,459 @@
+use actix::System;
+use actix_http::ws;
+use actix_wamp::router::{MemoryTransport, Router, RouterBuilder};
+use actix_wamp::*;
+use futures::prelude::*;
+use serde_json::json;
+use std::convert::Infallible;
+
+const REALM: &str = "realm1";
+
+fn secret(_auth_id: &str) -> Result<Vec<u8>, Infallible> {
+    Ok(b"s3cr3t".to_vec())
+}
+
+#[test]
+fn test_call_registered_procedure() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let callee = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let caller = SessionBuilder::anonymous(REALM.into())
+            .with_serializers(&[Serializer::Json])
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let _registration = callee
+            .register("test.add", |invocation: Invocation| {
+                let sum: i64 = invocation.args.iter().filter_map(|v| v.as_i64()).sum();
+                future::ok(RpcCallResponse {
+                    args: vec![json!(sum)],
+                    kw_args: None,
+                })
+            })
+            .await
+            .unwrap();
+
+        let response = caller
+            .rpc_call(RpcCallRequest::with_args("test.add", &(2, 3)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.args, vec![json!(5)]);
+    });
+}
+
+#[test]
+fn test_binary_payload_roundtrip() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let callee = SessionBuilder::anonymous(REALM.into())
+            .with_serializers(&[Serializer::Json])
+            .create(router.connect())
+            .await
+            .unwrap();
+        let caller = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let data = rmpv::Value::Binary(vec![0, 1, 255]);
+
+        let expected = data.clone();
+        let _registration = callee
+            .register("test.echo", move |invocation: Invocation| {
+                assert_eq!(invocation.payload.args[0], expected);
+                assert_eq!(invocation.payload.parse_arg::<u64>(1).unwrap(), u64::MAX);
+                let mut reply = invocation.payload;
+                reply.kw_args.insert("data".into(), expected.clone());
+                future::ok::<_, WampError>(reply)
+            })
+            .await
+            .unwrap();
+
+        let mut payload = Payload::with_args(&((), u64::MAX)).unwrap();
+        payload.args[0] = data.clone();
+        let response = caller
+            .rpc_call_payload(RpcCallRequest::with_payload("test.echo", payload))
+            .await
+            .unwrap();
+        assert_eq!(response.args[0], data);
+        assert_eq!(response.parse_arg::<u64>(1).unwrap(), u64::MAX);
+        assert_eq!(response.kw_args["data"], data);
+    });
+}
+
+#[test]
+fn test_call_errors() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let session = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let _registration = session
+            .register("test.fail", |_invocation: Invocation| {
+                future::err::<Payload, _>(WampError::with_message(
+                    ErrorKind::InvalidArgument,
+                    "bad",
+                ))
+            })
+            .await
+            .unwrap();
+
+        match session
+            .rpc_call(RpcCallRequest::with_no_args("test.fail"))
+            .await
+        {
+            Err(Error::WampError(e)) => assert_eq!(e.code, ErrorKind::InvalidArgument),
+            other => panic!("unexpected result: {:?}", other.map(|r| r.args)),
+        }
+        match session
+            .rpc_call(RpcCallRequest::with_no_args("test.missing"))
+            .await
+        {
+            Err(Error::WampError(e)) => assert_eq!(e.code, ErrorKind::NoSuchProcedure),
+            other => panic!("unexpected result: {:?}", other.map(|r| r.args)),
+        }
+    });
+}
+
+#[test]
+fn test_structured_call_error() {
+    #[derive(serde_derive::Deserialize)]
+    struct Details {
+        task_id: String,
+        retry: bool,
+    }
+
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let session = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let _registration = session
+            .register("test.fail", |_invocation: Invocation| {
+                let payload = Payload::with_args(&("task failed", 7))
+                    .and_then(|payload| {
+                        payload.with_kw_args(&json!({"task_id": "t1", "retry": true}))
+                    })
+                    .unwrap();
+                future::err::<Payload, _>(
+                    WampError::with_message(ErrorKind::from_uri("golem.error.task"), "task failed")
+                        .with_payload(payload),
+                )
+            })
+            .await
+            .unwrap();
+
+        let e = match session
+            .rpc_call(RpcCallRequest::with_no_args("test.fail"))
+            .await
+        {
+            Err(Error::WampError(e)) => e,
+            other => panic!("unexpected result: {:?}", other.map(|r| r.args)),
+        };
+        assert_eq!(e.uri(), "golem.error.task");
+        assert_eq!(e.message, "task failed");
+        assert_eq!(e.parse_arg::<u32>(1).unwrap(), 7);
+        let details: Details = e.parse_kw_args().unwrap();
+        assert_eq!(details.task_id, "t1");
+        assert!(details.retry);
+    });
+}
+
+/// Peer speaking raw WAMP over json, for features session does not offer as callee,
+/// e.g. progressive YIELDs.
+struct RawPeer(MemoryTransport);
+
+impl RawPeer {
+    const HELLO: u8 = 1;
+    const REGISTER: u8 = 64;
+    const INVOCATION: u8 = 68;
+    const INTERRUPT: u8 = 69;
+    const YIELD: u8 = 70;
+
+    async fn join(router: &Router) -> Self {
+        let mut peer = RawPeer(router.connect());
+        peer.send(json!([Self::HELLO, REALM, {"roles": {"callee": {}}}]))
+            .await;
+        peer.recv().await;
+        peer
+    }
+
+    async fn send(&mut self, msg: serde_json::Value) {
+        self.0
+            .send(ws::Message::Text(msg.to_string()))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(&mut self) -> serde_json::Value {
+        loop {
+            if let ws::Frame::Text(text) = self.0.next().await.unwrap().unwrap() {
+                return serde_json::from_slice(&text).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_progressive_call() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let mut callee = RawPeer::join(&router).await;
+        callee
+            .send(json!([RawPeer::REGISTER, 1, {}, "test.progress"]))
+            .await;
+        callee.recv().await;
+        let caller = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let mut results =
+            caller.rpc_call_progressive(RpcCallRequest::with_no_args("test.progress"));
+        let invocation = callee.recv().await;
+        assert_eq!(invocation[0], json!(RawPeer::INVOCATION));
+        assert_eq!(invocation[3]["receive_progress"], json!(true));
+        for (n, details) in [
+            (1, json!({"progress": true})),
+            (2, json!({"progress": true})),
+            (3, json!({})),
+        ] {
+            callee
+                .send(json!([RawPeer::YIELD, invocation[1], details, [n]]))
+                .await;
+        }
+        for n in 1..=3 {
+            let result = results.next().await.unwrap().unwrap();
+            assert_eq!(result.args, vec![json!(n)]);
+        }
+        assert!(results.next().await.is_none());
+
+        // Dropping results before final one cancels call.
+        let mut results = caller.rpc_call_progressive(
+            RpcCallRequest::with_no_args("test.progress").with_cancel_mode(CancelMode::Skip),
+        );
+        let invocation = callee.recv().await;
+        callee
+            .send(json!([RawPeer::YIELD, invocation[1], {"progress": true}, [1]]))
+            .await;
+        let result = results.next().await.unwrap().unwrap();
+        assert_eq!(result.args, vec![json!(1)]);
+        drop(results);
+        assert_eq!(
+            callee.recv().await,
+            json!([RawPeer::INTERRUPT, invocation[1], {"mode": "skip"}])
+        );
+    });
+}
+
+#[test]
+fn test_prefix_subscription() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let subscriber = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let publisher = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let mut events =
+            Box::pin(subscriber.subscribe_with("evt.comp.", SubscribeOptions::prefix()));
+        // Router handles messages of one session in order, so SUBSCRIBE is done once
+        // this publication is acknowledged.
+        subscriber
+            .publish("evt.sync", vec![], None, PublishOptions::acknowledged())
+            .await
+            .unwrap();
+
+        publisher
+            .publish(
+                "evt.comp.started",
+                vec![json!("task1")],
+                None,
+                PublishOptions::acknowledged(),
+            )
+            .await
+            .unwrap();
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.details.topic, "evt.comp.started");
+        assert_eq!(event.args, vec![json!("task1")]);
+    });
+}
+
+#[test]
+fn test_cra_auth() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM)
+            .with_cra_user("alice", "user", b"s3cr3t")
+            .deny_anonymous()
+            .start();
+
+        let session = SessionBuilder::with_auth(REALM, "alice", challenge_response_auth(secret))
+            .create(router.connect())
+            .await
+            .unwrap();
+        let info = session.session_info().await.unwrap();
+        assert_eq!(info.auth_id.as_deref(), Some("alice"));
+        assert_eq!(info.auth_role.as_deref(), Some("user"));
+        assert_eq!(info.auth_method.as_deref(), Some("wampcra"));
+        assert!(info.has_feature(Role::Dealer, "call_canceling"));
+        assert!(info.has_feature(Role::Broker, "pattern_based_subscription"));
+        assert!(!info.has_feature(Role::Broker, "event_history"));
+
+        let wrong_secret = |_: &str| -> Result<Vec<u8>, Infallible> { Ok(b"wrong".to_vec()) };
+        match SessionBuilder::with_auth(REALM, "alice", challenge_response_auth(wrong_secret))
+            .create(router.connect())
+            .await
+        {
+            Err(Error::WampError(e)) => assert_eq!(e.code, ErrorKind::NotAuthorized),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("authenticated with wrong secret"),
+        }
+
+        match SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+        {
+            Err(Error::WampError(e)) => assert_eq!(e.code, ErrorKind::NotAuthorized),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("anonymous session accepted"),
+        }
+    });
+}
+
+#[test]
+fn test_salted_cra_auth() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM)
+            .with_salted_cra_user("alice", "user", b"s3cr3t", "salt123")
+            .deny_anonymous()
+            .start();
+
+        // Signed with key derived from secret, not with secret itself.
+        let session = SessionBuilder::with_auth(REALM, "alice", challenge_response_auth(secret))
+            .create(router.connect())
+            .await
+            .unwrap();
+        let info = session.session_info().await.unwrap();
+        assert_eq!(info.auth_id.as_deref(), Some("alice"));
+        assert_eq!(info.auth_method.as_deref(), Some("wampcra"));
+    });
+}
+
+#[test]
+fn test_ticket_auth() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM)
+            .with_ticket_user("bob", "user", "t1cket")
+            .deny_anonymous()
+            .start();
+
+        let ticket = |_: &str| -> Result<String, Infallible> { Ok("t1cket".into()) };
+        let session = SessionBuilder::with_auth(REALM, "bob", ticket_auth(ticket))
+            .create(router.connect())
+            .await
+            .unwrap();
+        let info = session.session_info().await.unwrap();
+        assert_eq!(info.auth_id.as_deref(), Some("bob"));
+        assert_eq!(info.auth_method.as_deref(), Some("ticket"));
+
+        let wrong_ticket = |_: &str| -> Result<String, Infallible> { Ok("wrong".into()) };
+        match SessionBuilder::with_auth(REALM, "bob", ticket_auth(wrong_ticket))
+            .create(router.connect())
+            .await
+        {
+            Err(Error::WampError(e)) => assert_eq!(e.code, ErrorKind::NotAuthorized),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("authenticated with wrong ticket"),
+        }
+    });
+}
+
+#[test]
+fn test_router_shutdown() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new(REALM).start();
+        let session = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let events = session.session_events();
+
+        router.shutdown("wamp.close.system_shutdown");
+
+        let events: Vec<SessionEvent> = events.collect().await;
+        assert_eq!(
+            events.last(),
+            Some(&SessionEvent::Closed {
+                reason: CloseReason::Goodbye {
+                    uri: "wamp.close.system_shutdown".into(),
+                    message: None,
+                    by_router: true,
+                }
+            })
+        );
+    });
+}
+
+#[test]
+fn test_record_and_replay() {
+    let path = std::env::temp_dir().join(format!("actix-wamp-replay-{}.jsonl", std::process::id()));
+
+    let recording = path.clone();
+    System::new("test").block_on(async move {
+        let router = RouterBuilder::new(REALM).start();
+        let callee = SessionBuilder::anonymous(REALM.into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let _registration = callee
+            .register("test.add", |invocation: Invocation| {
+                let sum: i64 = invocation.args.iter().filter_map(|v| v.as_i64()).sum();
+                future::ok(RpcCallResponse {
+                    args: vec![json!(sum)],
+                    kw_args: None,
+                })
+            })
+            .await
+            .unwrap();
+
+        let transport = record::Recorder::create(router.connect(), recording).unwrap();
+        let caller = SessionBuilder::anonymous(REALM.into())
+            .create(transport)
+            .await
+            .unwrap();
+        let response = caller
+            .rpc_call(RpcCallRequest::with_args("test.add", &(2, 3)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.args, vec![json!(5)]);
+    });
+
+    // No router this time, request ids of the new session differ from recorded ones.
+    let recording = path.clone();
+    System::new("test").block_on(async move {
+        let replay = record::Replay::open(recording).unwrap();
+        let caller = SessionBuilder::anonymous(REALM.into())
+            .create(replay)
+            .await
+            .unwrap();
+        let response = caller
+            .rpc_call(RpcCallRequest::with_args("test.add", &(2, 3)).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.args, vec![json!(5)]);
+    });
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -183,6 +183,20 @@ impl<'a, Inner: crate::rpc::wamp::RpcEndpoint + ?Sized + 'static> GolemComp<'a,
             })
     }
 
+    /// Calls `comp.task.subtasks` for every task, see [`crate::rpc::wamp::RpcEndpoint::rpc_call_batch`].
+    pub fn get_subtasks_batch(
+        &self,
+        task_ids: impl IntoIterator<Item = String>,
+        options: crate::rpc::wamp::BatchOptions,
+    ) -> impl Future<Output = Vec<Result<Option<Vec<SubtaskInfo>>, crate::rpc::wamp::Error>>> + 'a
+    {
+        self.0.rpc_call_batch(
+            "comp.task.subtasks",
+            task_ids.into_iter().map(|task_id| (task_id,)),
+            options,
+        )
+    }
+
     pub fn create_dry_run(
         &self,
         task_spec: serde_json::Value,
@@ -0,0 +1,95 @@
+//! Typed streams of events published by Golem node.
+//!
+//! ```no_run
+//! # use futures::prelude::*;
+//! # use golem_rpc_api::events::AsGolemEvents;
+//! # async fn watch(endpoint: impl actix_wamp::PubSubEndpoint) {
+//! let tasks = endpoint.as_golem_events().task_status();
+//! futures::pin_mut!(tasks);
+//! while let Some(Ok(event)) = tasks.next().await {
+//!     println!("task {} changed", event.task_id);
+//! }
+//! # }
+//! ```
+use crate::core::ServerStatus;
+use crate::net::PeerInfo;
+use crate::pay::Balance;
+use serde::*;
+use serde_json::Value;
+
+pubsub_interface! {
+    trait GolemEvents {
+        /// Task was created, changed status or made progress.
+        #[topic = "evt.comp.task.status"]
+        fn task_status(&self) -> Stream<TaskStatusEvent>;
+
+        #[topic = "evt.comp.subtask.status"]
+        fn subtask_status(&self) -> Stream<SubtaskStatusEvent>;
+
+        /// Stage of node components, same as returned by `golem.status`.
+        #[topic = "evt.golem.status"]
+        fn component_status(&self) -> Stream<ComponentStatusEvent>;
+
+        #[topic = "evt.pay.balance"]
+        fn balance(&self) -> Stream<BalanceEvent>;
+
+        #[topic = "evt.net.peer.connected"]
+        fn peer_connected(&self) -> Stream<PeerEvent>;
+
+        #[topic = "evt.net.peer.disconnected"]
+        fn peer_disconnected(&self) -> Stream<PeerEvent>;
+
+        /// Node finished startup and serves RPC.
+        #[topic = "golem.rpc_ready"]
+        fn rpc_ready(&self) -> Stream<RpcReady>;
+    }
+
+    converter AsGolemEvents as_golem_events;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskStatusEvent {
+    pub task_id: String,
+    /// Kind of operation, e.g. `TaskOp`.
+    #[serde(default)]
+    pub op_class: Option<String>,
+    #[serde(default)]
+    pub op_value: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubtaskStatusEvent {
+    pub task_id: String,
+    #[serde(default)]
+    pub subtask_id: Option<String>,
+    #[serde(default)]
+    pub op_class: Option<String>,
+    #[serde(default)]
+    pub op_value: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ComponentStatusEvent {
+    pub status: ServerStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceEvent {
+    pub balance: Balance,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerEvent {
+    pub peer: PeerInfo,
+}
+
+/// Carries no data, any arguments are ignored.
+#[derive(Serialize, Debug, Clone)]
+pub struct RpcReady {}
+
+impl<'de> Deserialize<'de> for RpcReady {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        de::IgnoredAny::deserialize(deserializer)?;
+        Ok(RpcReady {})
+    }
+}
@@ -7,6 +7,7 @@ pub mod apps;
 pub mod comp;
 pub mod concent;
 pub mod core;
+pub mod events;
 pub mod net;
 pub mod pay;
 pub mod res;
@@ -49,6 +50,17 @@ pub enum Error {
     Ssl(openssl::error::ErrorStack),
 }
 
+impl Error {
+    /// Error returned by peer, with uri and arguments, e.g. to decode details of
+    /// `golem.error.*` with [`actix_wamp::WampError::parse_kw_args`].
+    pub fn wamp_error(&self) -> Option<&actix_wamp::WampError> {
+        match self {
+            Error::WampError(actix_wamp::Error::WampError(e)) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 impl From<serde_json::error::Error> for Error {
     fn from(json_err: serde_json::Error) -> Self {
         Error::ParseError(json_err)
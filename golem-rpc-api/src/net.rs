@@ -72,6 +72,36 @@ rpc_interface! {
     }
 }
 
+impl<'a, Inner: wamp::RpcEndpoint + ?Sized + 'static> GolemNet<'a, Inner> {
+    /// Calls `net.peer.block_ip` for every address, see [`wamp::RpcEndpoint::rpc_call_batch`].
+    pub fn block_ips(
+        &self,
+        ips: impl IntoIterator<Item = IpAddr>,
+        timeout_seconds: i32,
+        options: wamp::BatchOptions,
+    ) -> impl Future<Output = Vec<Result<(), wamp::Error>>> + 'a {
+        self.0.rpc_call_batch(
+            "net.peer.block_ip",
+            ips.into_iter().map(move |ip| (ip, timeout_seconds)),
+            options,
+        )
+    }
+
+    /// Calls `net.peer.allow_ip` for every address, see [`wamp::RpcEndpoint::rpc_call_batch`].
+    pub fn allow_ips(
+        &self,
+        ips: impl IntoIterator<Item = IpAddr>,
+        timeout_seconds: i32,
+        options: wamp::BatchOptions,
+    ) -> impl Future<Output = Vec<Result<(), wamp::Error>>> + 'a {
+        self.0.rpc_call_batch(
+            "net.peer.allow_ip",
+            ips.into_iter().map(move |ip| (ip, timeout_seconds)),
+            options,
+        )
+    }
+}
+
 pub trait AsGolemNet: wamp::RpcEndpoint {
     fn as_golem_net<'a>(&'a self) -> GolemNet<'a, Self>;
 }
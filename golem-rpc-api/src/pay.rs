@@ -106,7 +106,7 @@ pub struct DepositBalance {
     pub balance: BigDecimal,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Balance {
     #[serde(default)]
     pub eth: BigDecimal,
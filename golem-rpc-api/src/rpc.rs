@@ -1,12 +1,18 @@
-use actix_wamp::{Error, RpcCallRequest, RpcCallResponse, RpcEndpoint, ToArgs};
+use actix_wamp::{
+    BatchOptions, Error, Payload, PubSubEndpoint, RpcCallRequest, RpcEndpoint, SubscribeOptions,
+    ToArgs,
+};
 pub use futures::prelude::*;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::borrow::Cow;
 
 pub mod wamp {
-    pub use actix_wamp::{Error, RpcCallRequest, RpcCallResponse, RpcEndpoint, ToArgs};
-    pub use futures::Future;
+    pub use actix_wamp::{
+        BatchOptions, Error, PubSubEndpoint, RpcCallRequest, RpcCallResponse, RpcEndpoint,
+        SubscribeOptions, ToArgs,
+    };
+    pub use futures::{Future, Stream};
 }
 
 pub struct Invoker<'a, Inner: RpcEndpoint + ?Sized>(&'a Inner);
@@ -31,20 +37,11 @@ impl<'a, Inner: RpcEndpoint + ?Sized> Invoker<'a, Inner> {
             Ok(resuest) => resuest,
             Err(e) => return future::Either::Right(future::err(e)),
         };
-        future::Either::Left(self.0.rpc_call(request).and_then(
-            move |RpcCallResponse { args, .. }| async move {
-                if args.len() != 1 {
-                    Err(Error::protocol_err(
-                        "invalid rpc response, exactly 1 argument expected",
-                    ))
-                } else {
-                    Ok(serde_json::from_value(args[0].clone()).map_err(move |e| {
-                        log::error!("on {} unable to parse: {:?}: {}", uri, args, e);
-                        e
-                    })?)
-                }
-            },
-        ))
+        future::Either::Left(
+            self.0
+                .rpc_call_payload(request)
+                .and_then(move |payload| future::ready(parse_result(uri, payload))),
+        )
     }
 
     pub fn rpc_va_call<T: Serialize, Ret: DeserializeOwned + 'static>(
@@ -57,21 +54,96 @@ impl<'a, Inner: RpcEndpoint + ?Sized> Invoker<'a, Inner> {
             Ok(request) => request,
             Err(e) => return future::Either::Right(future::err(e)),
         };
-        future::Either::Left(self.0.rpc_call(request).and_then(
-            move |RpcCallResponse { args, .. }| async move {
-                if args.len() != 1 {
-                    Err(Error::protocol_err(
-                        "invalid rpc response, exactly 1 argument expected",
-                    ))
-                } else {
-                    Ok(serde_json::from_value(args[0].clone()).map_err(move |e| {
-                        log::error!("on {} unable to parse: {:?}: {}", uri, args, e);
-                        e
-                    })?)
+        future::Either::Left(
+            self.0
+                .rpc_call_payload(request)
+                .and_then(move |payload| future::ready(parse_result(&uri, payload))),
+        )
+    }
+
+    /// Calls `uri` once for every item of `args` with bounded concurrency,
+    /// see [`RpcEndpoint::rpc_call_batch`]. Results are in order of `args`.
+    ///
+    /// Items which fail to serialize get their own error and are not sent. With
+    /// [`BatchOptions::stop_on_error`] items after such one fail with [`Error::BatchStopped`].
+    pub fn rpc_call_batch<Args: ToArgs, Ret: DeserializeOwned + 'static>(
+        &self,
+        uri: &'static str,
+        args: impl IntoIterator<Item = Args>,
+        options: BatchOptions,
+    ) -> impl Future<Output = Vec<Result<Ret, Error>>> + 'a {
+        let mut results = Vec::new();
+        let mut requests = Vec::new();
+        let mut stopped = false;
+        for args in args {
+            match RpcCallRequest::with_args(uri, &args) {
+                Ok(_) if stopped => results.push(Some(Err(Error::BatchStopped))),
+                Ok(request) => {
+                    requests.push(request);
+                    results.push(None);
+                }
+                Err(e) => {
+                    stopped = options.stops_on_error();
+                    results.push(Some(Err(e)));
                 }
-            },
-        ))
+            }
+        }
+        let endpoint = self.0;
+        async move {
+            let mut replies = endpoint.rpc_call_batch(requests, options).await.into_iter();
+            results
+                .into_iter()
+                .map(|result| match result {
+                    Some(result) => result,
+                    None => replies
+                        .next()
+                        .unwrap_or(Err(Error::BatchStopped))
+                        .and_then(|payload| parse_result(uri, payload)),
+                })
+                .collect()
+        }
+    }
+}
+
+/// Decodes single result argument straight from msgpack, so binary data and
+/// large integers are not mangled.
+fn parse_result<Ret: DeserializeOwned>(uri: &str, payload: Payload) -> Result<Ret, Error> {
+    if payload.args.len() != 1 {
+        return Err(Error::protocol_err(
+            "invalid rpc response, exactly 1 argument expected",
+        ));
     }
+    payload.parse_arg(0).map_err(|e| {
+        log::error!("on {} unable to parse: {:?}: {}", uri, payload.args, e);
+        e
+    })
+}
+
+/// Subscribes to `topic` and decodes every event as `T`, see [`parse_event`].
+#[doc(hidden)]
+pub fn subscribe_typed<Inner: PubSubEndpoint + ?Sized, T: DeserializeOwned>(
+    endpoint: &Inner,
+    topic: &'static str,
+    options: SubscribeOptions,
+) -> impl Stream<Item = Result<T, Error>> {
+    endpoint
+        .subscribe_with(topic, options)
+        .map(move |message| message.and_then(|message| parse_event(topic, &message.payload)))
+}
+
+/// Decodes event from named arguments when present, otherwise from positional ones.
+/// Structs with named fields deserialize from both. Positional arguments beyond
+/// fields of `T` are ignored.
+fn parse_event<T: DeserializeOwned>(topic: &str, payload: &Payload) -> Result<T, Error> {
+    let event = if payload.kw_args.is_empty() {
+        payload.parse_leading_args()
+    } else {
+        payload.parse_kw_args()
+    };
+    event.map_err(|e| {
+        log::error!("on {} unable to parse: {:?}: {}", topic, payload, e);
+        e
+    })
 }
 
 #[macro_export]
@@ -125,6 +197,61 @@ macro_rules! rpc_interface {
      };
 }
 
+/// Typed subscriptions, counterpart of `rpc_interface!` for pub/sub topics.
+#[macro_export]
+macro_rules! pubsub_interface {
+    {
+        trait $interface_name:ident {
+            $(
+                $(#[doc = $doc:expr])*
+                #[topic = $topic:expr]
+                fn $name:ident(&self) -> Stream<$event:ty>;
+            )*
+        }
+
+        $(
+            converter $converter_name:ident $converter_method:ident;
+        )?
+    }
+
+     => {
+        pub struct $interface_name<'a, Inner: $crate::rpc::wamp::PubSubEndpoint + ?Sized>(
+            &'a Inner,
+            $crate::rpc::wamp::SubscribeOptions,
+        );
+
+        impl<'a, Inner: $crate::rpc::wamp::PubSubEndpoint + ?Sized> $interface_name<'a, Inner> {
+            /// Options used for subscriptions, e.g. to bound event buffers.
+            pub fn with_options(mut self, options: $crate::rpc::wamp::SubscribeOptions) -> Self {
+                self.1 = options;
+                self
+            }
+
+            $(
+                $(#[doc = $doc])*
+                #[doc = "Subscribes to `"]
+                #[doc = $topic]
+                #[doc = "` topic."]
+                pub fn $name(&self) -> impl $crate::rpc::wamp::Stream<Item = Result<$event, $crate::rpc::wamp::Error>> {
+                    $crate::rpc::subscribe_typed(self.0, $topic, self.1.clone())
+                }
+            )*
+        }
+
+        $(
+            pub trait $converter_name : $crate::rpc::wamp::PubSubEndpoint {
+                fn $converter_method<'a>(&'a self) -> $interface_name<'a, Self>;
+            }
+
+            impl<Endpoint: $crate::rpc::wamp::PubSubEndpoint> $converter_name for Endpoint {
+                fn $converter_method<'a>(&'a self) -> $interface_name<'a, Endpoint> {
+                    $interface_name(self, Default::default())
+                }
+            }
+        )?
+     };
+}
+
 #[macro_export]
 #[doc(hidden)]
 macro_rules! impl_async_rpc_item {
@@ -178,6 +305,7 @@ macro_rules! impl_async_rpc_item {
 #[allow(dead_code)]
 mod test {
     use super::*;
+    use actix_wamp::RpcCallResponse;
 
     rpc_interface! {
         trait Test {
@@ -195,6 +323,19 @@ mod test {
 
     struct RpcMock;
 
+    impl Test<'_, RpcMock> {
+        fn test_batch(
+            &self,
+            args: Vec<u8>,
+        ) -> impl Future<Output = Vec<Result<Vec<String>, Error>>> + '_ {
+            self.0.rpc_call_batch(
+                "test.batch",
+                args.into_iter().map(|a| (a,)),
+                Default::default(),
+            )
+        }
+    }
+
     impl RpcEndpoint for RpcMock {
         type Response = future::Ready<Result<RpcCallResponse, Error>>;
 
@@ -206,4 +347,51 @@ mod test {
             })
         }
     }
+
+    #[test]
+    fn test_batch() {
+        let results = futures::executor::block_on(RpcMock.as_test().test_batch(vec![1, 2]));
+        let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            results,
+            vec![vec!["foo".to_string()], vec!["foo".to_string()]]
+        );
+    }
+
+    /// Argument which fails to serialize when `None`.
+    struct Arg(Option<u8>);
+
+    impl Serialize for Arg {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match self.0 {
+                Some(v) => serializer.serialize_u8(v),
+                None => Err(serde::ser::Error::custom("invalid arg")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch_serialize_error() {
+        let args = || vec![(Arg(Some(1)),), (Arg(None),), (Arg(Some(3)),)];
+        let invoker = RpcMock.as_invoker();
+
+        let results: Vec<Result<Vec<String>, Error>> = futures::executor::block_on(
+            invoker.rpc_call_batch("test.batch", args(), Default::default()),
+        );
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+
+        let results: Vec<Result<Vec<String>, Error>> =
+            futures::executor::block_on(invoker.rpc_call_batch(
+                "test.batch",
+                args(),
+                BatchOptions::default().stop_on_error(),
+            ));
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(matches!(results[2], Err(Error::BatchStopped)));
+    }
 }
@@ -1,4 +1,7 @@
+use actix_wamp::TlsPolicy;
 use futures::{future, prelude::*};
+use openssl::hash::MessageDigest;
+use openssl::x509::X509;
 use std::fmt;
 use std::path::Path;
 use std::str::FromStr;
@@ -36,30 +39,57 @@ impl fmt::Display for Net {
     }
 }
 
-fn cert_hash(data_dir: impl AsRef<Path>, net: &Net) -> Result<Vec<u8>, super::Error> {
-    let main_net_cert_path = data_dir
+fn load_cert(data_dir: impl AsRef<Path>, net: &Net) -> Result<X509, super::Error> {
+    let cert_path = data_dir
         .as_ref()
         .join(net.data_dir())
         .join("crossbar")
         .join("rpc_cert.pem");
 
-    let cert = openssl::x509::X509::from_pem(std::fs::read(main_net_cert_path)?.as_ref())?;
-    let bytes = cert.digest(openssl::hash::MessageDigest::sha1())?.to_vec();
+    Ok(X509::from_pem(std::fs::read(cert_path)?.as_ref())?)
+}
 
-    Ok(bytes)
+/// Rpc certificates of given net, or of every net found in `data_dir` when autodetecting.
+fn rpc_certs(data_dir: &Path, net: Option<Net>) -> Result<Vec<(Net, X509)>, super::Error> {
+    let nets = match net {
+        Some(net) => vec![net],
+        None => vec![Net::MainNet, Net::TestNet],
+    };
+    let mut certs = Vec::new();
+    for net in &nets {
+        match load_cert(data_dir, net) {
+            Ok(cert) => certs.push((net.clone(), cert)),
+            Err(e) if nets.len() == 1 => return Err(e),
+            Err(e) => log::debug!("unable to load cert from: {}, reason: {}", net, e),
+        }
+    }
+    if certs.is_empty() {
+        return Err(super::Error::Other(format!(
+            "no rpc cert found in {}",
+            data_dir.display()
+        )));
+    }
+    Ok(certs)
 }
 
-fn hash_to_net(data_dir: &Path, hash: Vec<u8>) -> Option<Net> {
-    [Net::MainNet, Net::TestNet]
+/// Pins all given certificates, server has to present one of them during handshake.
+fn tls_policy(certs: &[(Net, X509)]) -> Result<TlsPolicy, super::Error> {
+    let fingerprints = certs
         .iter()
-        .cloned()
-        .find(|net| match cert_hash(data_dir, net) {
-            Ok(v) => v == hash,
-            Err(e) => {
-                log::warn!("unable to load cert from: {}, reason: {}", net, e);
-                false
-            }
-        })
+        .map(|(_, cert)| Ok(cert.digest(MessageDigest::sha256())?.to_vec()))
+        .collect::<Result<_, super::Error>>()?;
+    Ok(TlsPolicy::PinnedSha256Any(fingerprints))
+}
+
+/// Net of pinned certificate accepted during handshake.
+fn accepted_net(certs: &[(Net, X509)], hash: Option<Vec<u8>>) -> Net {
+    let digest = |cert: &X509| cert.digest(MessageDigest::sha1()).ok().map(|d| d.to_vec());
+    certs
+        .iter()
+        .find(|(_, cert)| hash.is_some() && digest(cert) == hash)
+        .unwrap_or(&certs[0])
+        .0
+        .clone()
 }
 
 ///
@@ -71,7 +101,8 @@ fn hash_to_net(data_dir: &Path, hash: Vec<u8>) -> Option<Net> {
 /// * `net` - configuration type (mainnet/testnet) None for autodetect
 /// * `rpc_addr` - force other than default rpc_address
 ///
-
+/// Server certificate must match `crossbar/rpc_cert.pem` of the net, it is checked
+/// during TLS handshake.
 pub fn connect_to_app(
     data_dir: &Path,
     net: impl Into<Option<Net>>,
@@ -83,17 +114,18 @@ pub fn connect_to_app(
     >,
 > {
     let (address, port) = rpc_addr.unwrap_or_else(|| ("127.0.0.1", 61000));
+    let (certs, tls_policy) = match rpc_certs(data_dir, net.into())
+        .and_then(|certs| tls_policy(&certs).map(|tls_policy| (certs, tls_policy)))
+    {
+        Ok(v) => v,
+        Err(e) => return future::err(e).left_future(),
+    };
     let data_dir = data_dir.to_owned();
-    actix_wamp::wss(address, port)
+    let options = actix_wamp::WsOptions::default().with_tls_policy(tls_policy);
+    actix_wamp::wss_with_options(address, port, &options)
         .map_err(|e| super::Error::Other(format!("{}", e)))
-        .and_then(move |(transport, hash)| {
-            let net = match net.into().or_else(|| hash_to_net(&data_dir, hash?)) {
-                Some(net) => net,
-                None => {
-                    return future::err(super::Error::Other("invalid rpc cert".into()))
-                        .right_future()
-                }
-            };
+        .and_then(move |(transport, _serializer, hash)| {
+            let net = accepted_net(&certs, hash);
             let net_data_dir = data_dir.join(net.data_dir());
             let auth_method =
                 actix_wamp::challenge_response_auth(move |auth_id| -> Result<_, std::io::Error> {
@@ -106,6 +138,49 @@ pub fn connect_to_app(
             actix_wamp::SessionBuilder::with_auth("golem", "golemcli", auth_method)
                 .create(transport)
                 .map_err(From::from)
-                .left_future()
         })
+        .right_future()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    fn write_cert(data_dir: &Path, net: &Net) -> Vec<u8> {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut cert = X509::builder().unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let dir = data_dir.join(net.data_dir()).join("crossbar");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("rpc_cert.pem"), cert.to_pem().unwrap()).unwrap();
+        cert.digest(MessageDigest::sha256()).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_tls_policy() {
+        let data_dir =
+            std::env::temp_dir().join(format!("golem-rpc-api-{}", rand::random::<u64>()));
+        assert!(rpc_certs(&data_dir, None).is_err());
+
+        let fingerprint = write_cert(&data_dir, &Net::TestNet);
+        let certs = rpc_certs(&data_dir, None).unwrap();
+        match tls_policy(&certs).unwrap() {
+            TlsPolicy::PinnedSha256Any(pins) => assert_eq!(pins, vec![fingerprint]),
+            policy => panic!("unexpected policy: {:?}", policy),
+        }
+        assert!(matches!(accepted_net(&certs, None), Net::TestNet));
+        assert!(rpc_certs(&data_dir, Some(Net::MainNet)).is_err());
+
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
 }
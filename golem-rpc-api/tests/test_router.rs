@@ -0,0 +1,182 @@
+use actix::System;
+use actix_wamp::router::RouterBuilder;
+use actix_wamp::{
+    Invocation, PubSubEndpoint, PublishOptions, RpcCallResponse, RpcRegistry, SessionBuilder,
+};
+use futures::prelude::*;
+use golem_rpc_api::core::{ComponentReport, Stage};
+use golem_rpc_api::events::AsGolemEvents;
+use golem_rpc_api::net::AsGolemNet;
+use serde_json::json;
+
+#[test]
+fn test_typed_call() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new("golem").start();
+        let node = SessionBuilder::anonymous("golem".into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let client = SessionBuilder::anonymous("golem".into())
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let _registration = node
+            .register("net.ident.name", |_: Invocation| {
+                future::ok(RpcCallResponse {
+                    args: vec![json!("node-1")],
+                    kw_args: None,
+                })
+            })
+            .await
+            .unwrap();
+
+        let name = client.as_golem_net().get_node_name().await.unwrap();
+        assert_eq!(name, "node-1");
+    });
+}
+
+#[test]
+fn test_typed_events() {
+    System::new("test").block_on(async {
+        let router = RouterBuilder::new("golem").start();
+        let node = SessionBuilder::anonymous("golem".into())
+            .create(router.connect())
+            .await
+            .unwrap();
+        let client = SessionBuilder::anonymous("golem".into())
+            .create(router.connect())
+            .await
+            .unwrap();
+
+        let events = client.as_golem_events();
+        let mut tasks = Box::pin(events.task_status());
+        let mut subtasks = Box::pin(events.subtask_status());
+        let mut rpc_ready = Box::pin(events.rpc_ready());
+        let mut component_status = Box::pin(events.component_status());
+        let mut balance = Box::pin(events.balance());
+        let mut peer_connected = Box::pin(events.peer_connected());
+        let mut peer_disconnected = Box::pin(events.peer_disconnected());
+        // Router handles messages of one session in order, so SUBSCRIBE is done once
+        // this publication is acknowledged.
+        client
+            .publish("evt.sync", vec![], None, PublishOptions::acknowledged())
+            .await
+            .unwrap();
+
+        let publish = |topic, args, kw_args| {
+            node.publish(topic, args, kw_args, PublishOptions::acknowledged())
+        };
+        publish(
+            "evt.comp.task.status",
+            // Trailing arguments unknown to event type are ignored.
+            vec![json!("t1"), json!("TaskOp"), json!(2), json!("extra")],
+            None,
+        )
+        .await
+        .unwrap();
+        let kw_args = json!({"task_id": "t1", "subtask_id": "s1"});
+        publish(
+            "evt.comp.subtask.status",
+            vec![],
+            kw_args.as_object().cloned(),
+        )
+        .await
+        .unwrap();
+
+        let event = tasks.next().await.unwrap().unwrap();
+        assert_eq!(event.task_id, "t1");
+        assert_eq!(event.op_class.as_deref(), Some("TaskOp"));
+        assert_eq!(event.op_value, Some(json!(2)));
+        let event = subtasks.next().await.unwrap().unwrap();
+        assert_eq!(event.subtask_id.as_deref(), Some("s1"));
+        assert_eq!(event.op_class, None);
+
+        publish("golem.rpc_ready", vec![json!(1), json!("ready")], None)
+            .await
+            .unwrap();
+        rpc_ready.next().await.unwrap().unwrap();
+
+        // Status publisher sends `{component: (method, stage, data)}`.
+        publish(
+            "evt.golem.status",
+            vec![json!({
+                "client": ["start", "post", null],
+                "docker": ["instance.check", "warning", {"reason": "no vm"}],
+            })],
+            None,
+        )
+        .await
+        .unwrap();
+        let event = component_status.next().await.unwrap().unwrap();
+        let ComponentReport(method, stage, data) = event.status.client.unwrap();
+        assert_eq!(method, "start");
+        assert!(matches!(stage, Stage::Post));
+        assert_eq!(data, json!(null));
+        let ComponentReport(method, stage, data) = event.status.docker.unwrap();
+        assert_eq!(method, "instance.check");
+        assert!(matches!(stage, Stage::Warning));
+        assert_eq!(data, json!({"reason": "no vm"}));
+        assert!(event.status.ethereum.is_none());
+
+        // Amounts are sent in wei as strings, beyond range of u64.
+        publish(
+            "evt.pay.balance",
+            vec![json!({
+                "eth": "1000000000000000000",
+                "eth_lock": "0",
+                "av_gnt": "25000000000000000000000",
+                "gnt_lock": "1500000000000000000",
+                "gnt_nonconverted": "0",
+                "block_number": "5631912",
+            })],
+            None,
+        )
+        .await
+        .unwrap();
+        let event = balance.next().await.unwrap().unwrap();
+        assert_eq!(event.balance.eth.to_string(), "1000000000000000000");
+        assert_eq!(event.balance.av_gnt.to_string(), "25000000000000000000000");
+        assert_eq!(event.balance.gnt_lock.to_string(), "1500000000000000000");
+
+        let peer = json!({
+            "address": "5.226.70.4",
+            "port": 40102,
+            "verified": true,
+            "degree": 4,
+            "key_id": "b7da70a8bbb439e8f1cdf7494ce163294a884ccaf9f117023e3cad5e5d4cb606",
+            "node_name": "R03 Laughing Octopus",
+            "node_info": {
+                "node_name": "R03 Laughing Octopus",
+                "key": "b7da70a8bbb439e8f1cdf7494ce163294a884ccaf9f117023e3cad5e5d4cb606",
+                "prv_port": 40201,
+                "pub_port": 40201,
+                "p2p_prv_port": 40200,
+                "p2p_pub_port": 40200,
+                "prv_addr": "10.30.8.60",
+                "pub_addr": "5.226.70.4",
+                "prv_addresses": ["10.30.8.60", "172.17.0.1"],
+                "hyperdrive_prv_port": 3282,
+                "hyperdrive_pub_port": 3282,
+                "port_statuses": {"40200": "open"},
+                "nat_type": [],
+            },
+            "listen_port": 40200,
+            "conn_id": null,
+        });
+        publish("evt.net.peer.connected", vec![peer.clone()], None)
+            .await
+            .unwrap();
+        publish("evt.net.peer.disconnected", vec![peer], None)
+            .await
+            .unwrap();
+        let event = peer_connected.next().await.unwrap().unwrap();
+        assert_eq!(event.peer.node_name, "R03 Laughing Octopus");
+        assert_eq!(event.peer.port, 40102);
+        assert_eq!(event.peer.node_info.p2p_pub_port, Some(40200));
+        assert_eq!(event.peer.conn_id, None);
+        let event = peer_disconnected.next().await.unwrap().unwrap();
+        assert_eq!(event.peer.address, "5.226.70.4");
+    });
+}
@@ -1,5 +1,6 @@
 use crate::context::*;
 use crate::utils::GolemIdPattern;
+use actix_wamp::BatchOptions;
 use failure::Fallible;
 use futures::{future, prelude::*};
 use golem_rpc_api::core::AsGolemCore;
@@ -174,18 +175,15 @@ impl Section {
         nodes: &Vec<GolemIdPattern>,
         timeout: i32,
     ) -> Fallible<CommandResponse> {
-        let block_ips = future::try_join_all(
-            ips.into_iter()
-                .cloned()
-                .map(|ip| {
-                    let endpoint = endpoint.clone();
-                    async move {
-                        endpoint.as_golem_net().block_ip(ip, timeout).await?;
-                        Ok(())
-                    }
-                })
-                .collect::<Vec<_>>(),
-        );
+        let block_ips = async {
+            endpoint
+                .as_golem_net()
+                .block_ips(ips.into_iter().cloned(), timeout, batch_options())
+                .await
+                .into_iter()
+                .collect::<Result<Vec<()>, _>>()
+                .map_err(failure::Error::from)
+        };
 
         let list_ep = endpoint.clone();
         let nodes = nodes.clone();
@@ -245,19 +243,15 @@ impl Section {
     ) -> Fallible<CommandResponse> {
         let list_ep = endpoint.clone();
 
-        let allow_ips = future::try_join_all(
-            ips.into_iter()
-                .cloned()
-                .map(|ip| {
-                    let endpoint = endpoint.clone();
-                    async move {
-                        endpoint.as_golem_net().allow_ip(ip, -1).await?;
-
-                        Ok(())
-                    }
-                })
-                .collect::<Vec<_>>(),
-        );
+        let allow_ips = async {
+            endpoint
+                .as_golem_net()
+                .allow_ips(ips.into_iter().cloned(), -1, batch_options())
+                .await
+                .into_iter()
+                .collect::<Result<Vec<()>, _>>()
+                .map_err(failure::Error::from)
+        };
 
         let nodes = nodes.clone();
         let allow_nodes = async {
@@ -309,6 +303,11 @@ impl Section {
     }
 }
 
+/// Keeps bulk ip changes from flooding the node, stops on first rejected address.
+fn batch_options() -> BatchOptions {
+    BatchOptions::default().stop_on_error()
+}
+
 struct AclListOutput {
     full: bool,
     ips: Option<Vec<AclRuleItem<IpAddr>>>,
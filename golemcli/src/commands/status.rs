@@ -450,7 +450,6 @@ impl Section {
         task_status_in_fly.extend(vec![TaskStatus::NotStarted]);
 
         let tasks: Vec<TaskInfo> = endpoint.as_golem_comp().get_tasks().await?;
-        let mut subtasks = Vec::new();
 
         let tasks_in_fly: Vec<TaskInfo> = tasks
             .into_iter()
@@ -461,15 +460,15 @@ impl Section {
         let total_subtasks = tasks_in_fly.iter().fold(0, |total_subtasks, task| {
             total_subtasks + task.subtasks_count.unwrap()
         });
-        subtasks.reserve(tasks_in_fly.len() * 2);
-        for task_in_fly in &tasks_in_fly {
-            let subtask = endpoint
-                .as_golem_comp()
-                .get_subtasks(task_in_fly.id.clone());
-            subtasks.push(subtask);
-        }
-
-        let all_subtasks = future::try_join_all(subtasks).await?;
+        let all_subtasks = endpoint
+            .as_golem_comp()
+            .get_subtasks_batch(
+                tasks_in_fly.iter().map(|task| task.id.clone()),
+                Default::default(),
+            )
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
         let finished_subtasks = all_subtasks
             .into_iter()
             .fold(0, |finished_subtasks, subtasks| {
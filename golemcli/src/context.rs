@@ -112,10 +112,18 @@ impl TryFrom<&CliArgs> for CliCtx {
 async fn wait_for_server(
     endpoint: impl actix_wamp::PubSubEndpoint + Clone + 'static,
 ) -> Result<bool, actix_wamp::Error> {
-    use futures::stream::Stream;
+    use actix_wamp::{BufferOptions, OverflowPolicy, SubscribeOptions};
+    use golem_rpc_api::events::AsGolemEvents;
 
     eprintln!("Waiting for server start");
-    let subscribe = endpoint.subscribe("golem.rpc_ready");
+    // Only first event matters, so nothing more is buffered.
+    let subscribe = endpoint
+        .as_golem_events()
+        .with_options(
+            SubscribeOptions::default()
+                .with_buffer(BufferOptions::new(1, OverflowPolicy::DropNewest)),
+        )
+        .rpc_ready();
     futures::pin_mut!(subscribe);
     let _ = subscribe.try_next().await?;
     Ok(true)
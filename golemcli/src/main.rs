@@ -135,8 +135,7 @@ impl CliArgs {
     }
 }
 
-#[actix_rt::main]
-async fn main() -> Fallible<()> {
+async fn run() -> Fallible<()> {
     let args = CliArgs::from_args();
 
     if args.version {
@@ -150,3 +149,12 @@ async fn main() -> Fallible<()> {
 
     args.run_command().await
 }
+
+fn main() {
+    // Exit only after runtime, session and output are dropped.
+    let result = actix_rt::System::new("golemcli").block_on(run());
+    if let Err(e) = result {
+        eprintln!("Error: {}", utils::format_error(&e));
+        std::process::exit(1);
+    }
+}
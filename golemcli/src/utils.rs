@@ -163,10 +163,53 @@ pub async fn resolve_from_known_hosts(
     }
 }
 
+/// Formats error for user, WAMP errors are shown with uri and all details sent by node.
+pub fn format_error(err: &failure::Error) -> String {
+    let wamp_error =
+        err.iter_chain()
+            .find_map(|cause| match cause.downcast_ref::<actix_wamp::Error>() {
+                Some(actix_wamp::Error::WampError(e)) => Some(e.as_ref()),
+                _ => cause
+                    .downcast_ref::<golem_rpc_api::Error>()
+                    .and_then(golem_rpc_api::Error::wamp_error),
+            });
+    let e = match wamp_error {
+        Some(e) => e,
+        None => return err.to_string(),
+    };
+
+    let mut out = e.to_string();
+    let mut args: Vec<serde_json::Value> = e.parse_args().unwrap_or_default();
+    if args.first().and_then(|arg| arg.as_str()) == Some(e.message.as_str()) {
+        args.remove(0);
+    }
+    if !args.is_empty() {
+        out.push_str(&format!("\n  args: {}", serde_json::Value::from(args)));
+    }
+    for (key, value) in e.extra.iter().filter(|(key, _)| *key != "message") {
+        out.push_str(&format!("\n  {}: {}", key, value));
+    }
+    out
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_format_error() {
+        let args = rmpv::Value::Array(vec!["task failed".into(), 7.into()]);
+        let kwargs = rmpv::Value::Map(vec![("task_id".into(), "t1".into())]);
+        let err = actix_wamp::Error::from_wamp_error_message("golem.error.task", &args, &kwargs);
+        let err: failure::Error = golem_rpc_api::Error::from(err).into();
+
+        assert_eq!(
+            format_error(&err),
+            "golem.error.task: task failed\n  args: [7]\n  task_id: \"t1\""
+        );
+        assert_eq!(format_error(&failure::err_msg("other")), "other");
+    }
+
     #[test]
     fn test_pattern() {
         let key = "8c6c3bcb42a1a7a555118a14c737e06aac16e17045523cef7c61facd17a8eeff971f377518773848312f312aaf1a2d8b3709376106cb3f6e4acbcf47a0cb9361";